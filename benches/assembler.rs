@@ -0,0 +1,67 @@
+// Source lines per second through the lexer, the parser and the whole
+// assembler, on a large generated program.
+mod harness;
+
+use std::hint::black_box;
+
+use rpd::assembler::lexer::Lexer;
+use rpd::assembler::parser::Parser;
+use rpd::assembler::Assembler;
+
+// Enough to fill most of the 64 KiB that jumps can address.
+const LINES: usize = 15_000;
+
+// A mix of every operand kind, in blocks that each jump back to their own label.
+fn generated_source() -> String {
+    let mut source = String::from(".data\ngreeting: .asciiz \"hello\"\n.code\n");
+    let body = [
+        "load $1 #500",
+        "add $3 $1 $2",
+        "sub $4 $3 $1",
+        "mul $5 $4 $4",
+        "div $6 $5 $1",
+        "eq $6 $1",
+        "jeq @block{}",
+        "inc $7",
+        "dec $8",
+        "neq $7 $8",
+        "jneq @block{}",
+        "alloc $1",
+    ];
+    let mut lines = 3;
+    let mut block = 0;
+    while lines < LINES {
+        source.push_str(&format!("block{}:\n", block));
+        for line in body {
+            source.push_str(&line.replace("{}", &block.to_string()));
+            source.push('\n');
+        }
+        lines += body.len() + 1;
+        block += 1;
+    }
+    source.push_str("hlt\n");
+    source
+}
+
+fn main() {
+    let source = generated_source();
+    let lines = source.lines().count() as u64;
+
+    harness::bench("assembler/lex", "lines", || {
+        let mut lexer = Lexer::new(source.clone());
+        black_box(lexer.tokenize().unwrap());
+        lines
+    });
+    harness::bench("assembler/lex+parse", "lines", || {
+        let mut lexer = Lexer::new(source.clone());
+        let tokens = lexer.tokenize().unwrap();
+        let mut parser = Parser::new_with_spans(tokens, lexer.spans().clone());
+        black_box(parser.parse().unwrap());
+        lines
+    });
+    harness::bench("assembler/assemble", "lines", || {
+        let mut assembler = Assembler::new(source.clone());
+        black_box(assembler.assemble().unwrap());
+        lines
+    });
+}
@@ -0,0 +1,55 @@
+// A small timing harness shared by the benches, so they run with nothing but
+// std. Every bench gets a warm up call and is then repeated for at least
+// `MIN_TIME`. Pass a name fragment to `cargo bench` to run only matching
+// benches, e.g. `cargo bench -- assembler`.
+use std::time::{Duration, Instant};
+
+const MIN_TIME: Duration = Duration::from_secs(1);
+const MIN_ITERATIONS: u32 = 3;
+
+pub struct Measurement {
+    pub elapsed: Duration,
+    pub units: u64,
+}
+
+impl Measurement {
+    pub fn per_second(&self) -> f64 {
+        self.units as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+fn selected(name: &str) -> bool {
+    let filters: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .collect();
+    filters.is_empty() || filters.iter().any(|filter| name.contains(filter.as_str()))
+}
+
+// Times `routine`, which returns how many `unit`s of work one call did, and
+// prints the throughput.
+pub fn bench(name: &str, unit: &str, mut routine: impl FnMut() -> u64) -> Option<Measurement> {
+    if !selected(name) {
+        return None;
+    }
+    routine();
+    let start = Instant::now();
+    let mut units = 0;
+    let mut iterations = 0;
+    while iterations < MIN_ITERATIONS || start.elapsed() < MIN_TIME {
+        units += routine();
+        iterations += 1;
+    }
+    let measurement = Measurement {
+        elapsed: start.elapsed(),
+        units,
+    };
+    println!(
+        "{:<36} {:>10.2} M {}/s  ({} iterations)",
+        name,
+        measurement.per_second() / 1e6,
+        unit,
+        iterations
+    );
+    Some(measurement)
+}
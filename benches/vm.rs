@@ -0,0 +1,122 @@
+// Instructions per second of `VM::run`, through the pre-decoded dispatch loop
+// and through the byte-level interpreter it falls back to.
+//
+// Recursive Fibonacci is still missing, it needs call instructions the VM
+// doesn't have yet.
+mod harness;
+
+use std::hint::black_box;
+
+use rpd::assembler::Assembler;
+use rpd::profile::Profile;
+use rpd::vm::{RunStatus, VM};
+
+// Assembled bytecode with the read-only data it refers to.
+struct Program {
+    bytecode: Vec<u8>,
+    read_only: Vec<u8>,
+}
+
+impl Program {
+    fn vm(&self) -> VM {
+        let mut vm = VM::new_with_program(self.bytecode.clone());
+        vm.read_only = self.read_only.clone();
+        vm
+    }
+}
+
+fn assemble(source: &str) -> Program {
+    let mut assembler = Assembler::new(source.to_string());
+    let bytecode = assembler.assemble().unwrap();
+    Program {
+        bytecode,
+        read_only: assembler.read_only_secion,
+    }
+}
+
+// Counts down from 65535, adding to an accumulator on every iteration.
+fn count_loop() -> Program {
+    assemble(
+        "load $0 #65535
+        load $2 #3
+        loop:
+        dec $0
+        add $1 $1 $2
+        neq $0 $3
+        jeq @loop
+        hlt",
+    )
+}
+
+// The first 40 Fibonacci numbers, iteratively, a thousand times over.
+fn fibonacci() -> Program {
+    assemble(
+        "load $6 #1000
+        outer:
+        load $0 #40
+        load $1 #0
+        load $2 #1
+        inner:
+        add $3 $1 $2
+        add $1 $2 $5
+        add $2 $3 $5
+        dec $0
+        neq $0 $5
+        jeq @inner
+        dec $6
+        neq $6 $5
+        jeq @outer
+        hlt",
+    )
+}
+
+// Copies a 64 byte string into a new heap string twice over and grows a heap
+// block from 64 bytes to 4 KiB, freeing both, ten thousand times.
+fn heap_copy() -> Program {
+    assemble(&format!(
+        ".data
+        text: .asciiz \"{}\"
+        .code
+        lda $0 @text
+        strload $2 $0
+        load $8 #10000
+        load $10 #4096
+        loop:
+        strcat $4 $2 $2
+        free $4
+        load $6 #64
+        alloc $6
+        realloc $6 $10
+        free $6
+        dec $8
+        neq $8 $9
+        jeq @loop
+        hlt",
+        "0123456789abcdef".repeat(4)
+    ))
+}
+
+fn instruction_count(program: &Program) -> u64 {
+    let mut vm = program.vm();
+    vm.profile = Some(Profile::new());
+    vm.run();
+    vm.profile.unwrap().instructions
+}
+
+fn bench_program(name: &str, program: Program) {
+    let instructions = instruction_count(&program);
+    for (mode, predecode) in [("pre-decoded", true), ("byte-level", false)] {
+        harness::bench(&format!("vm/{}/{}", name, mode), "instructions", || {
+            let mut vm = program.vm();
+            vm.predecode = predecode;
+            assert_eq!(black_box(vm.run()), RunStatus::Halted(0));
+            instructions
+        });
+    }
+}
+
+fn main() {
+    bench_program("count_loop", count_loop());
+    bench_program("fibonacci", fibonacci());
+    bench_program("heap_copy", heap_copy());
+}
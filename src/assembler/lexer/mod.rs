@@ -1,8 +1,8 @@
 use std::fmt::{Display, Write};
 
-use token::{DirectiveType, Token};
+use token::{DirectiveType, Span, Token};
 
-use crate::instruction::Opcode;
+use crate::instruction::{Opcode, REGISTER_COUNT};
 
 pub mod token;
 
@@ -15,6 +15,7 @@ pub struct Lexer {
     current_line: usize,
     current_column: usize,
     errors: Vec<LexerError>,
+    spans: Vec<Span>,
 }
 
 #[derive(Debug)]
@@ -40,17 +41,29 @@ impl Display for LexerError {
 
 impl LexerError {
     pub fn new(message: &str, line: usize, column: usize, context: Option<String>) -> LexerError {
-        return LexerError {
+        LexerError {
             message: message.to_string(),
             line,
             column,
             context,
-        };
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn column(&self) -> usize {
+        self.column
     }
 }
 
 impl Lexer {
-    pub fn new(input: &String) -> Lexer {
+    pub fn new(input: String) -> Lexer {
         let mut lexer = Lexer {
             source: input.chars().collect(),
             current_char: '0',
@@ -59,22 +72,33 @@ impl Lexer {
             current_line: 1,
             current_column: 0,
             errors: Vec::new(),
+            spans: Vec::new(),
         };
         lexer.read_char();
-        return lexer;
+        lexer
     }
 
     pub fn tokenize(&mut self) -> Result<Vec<Token>, &Vec<LexerError>> {
         let mut tokens: Vec<Token> = vec![];
         while self.read_position < self.source.len() {
+            self.skip_whitespace();
+            let span = Span {
+                line: self.current_line,
+                column: self.current_column,
+            };
             if let Some(token) = self.next_token() {
                 tokens.push(token);
+                self.spans.push(span);
             }
         }
         if !self.errors.is_empty() {
             return Err(&self.errors);
         }
-        return Ok(tokens);
+        Ok(tokens)
+    }
+
+    pub fn spans(&self) -> &Vec<Span> {
+        &self.spans
     }
 
     pub fn next_token(&mut self) -> Option<Token> {
@@ -102,7 +126,6 @@ impl Lexer {
                         return Some(Token::LabelUsage { value: word });
                     }
                 }
-                println!("word {}", word);
                 match word.as_str() {
                     ".asciiz" => Some(Token::Directive {
                         directive_type: DirectiveType::Asciiz,
@@ -116,48 +139,57 @@ impl Lexer {
                         directive_type: DirectiveType::Data,
                         literal: ".data".to_string(),
                     }),
-                    "load" => Some(Token::Op { code: Opcode::LOAD }),
-                    "add" => Some(Token::Op { code: Opcode::ADD }),
-                    "sub" => Some(Token::Op { code: Opcode::SUB }),
-                    "mul" => Some(Token::Op { code: Opcode::MUL }),
-                    "div" => Some(Token::Op { code: Opcode::DIV }),
-                    "eq" => Some(Token::Op { code: Opcode::EQ }),
-                    "jmp" => Some(Token::Op { code: Opcode::JMP }),
-                    "jeq" => Some(Token::Op { code: Opcode::JEQ }),
-                    "jneq" => Some(Token::Op { code: Opcode::JNEQ }),
-                    "alloc" => Some(Token::Op {
-                        code: Opcode::ALLOC,
-                    }),
-                    "inc" => Some(Token::Op { code: Opcode::INC }),
-                    "dec" => Some(Token::Op { code: Opcode::DEC }),
-                    _ => {
-                        self.record_error(&format!("Unexpected word: {:?}", word.into_bytes()));
-                        None
-                    }
+                    word => match Opcode::from_mnemonic(word) {
+                        Some(code) => Some(Token::Op { code }),
+                        None => {
+                            self.record_error(&format!("Unexpected word: {:?}", word.as_bytes()));
+                            None
+                        }
+                    },
                 }
             }
 
-            '\0' => Some(Token::Op { code: Opcode::ZERO }),
+            // Only whitespace was left before the end of the input.
+            '\0' => None,
             '$' => {
+                let (line, column) = (self.current_line, self.current_column);
                 self.read_char();
                 if self.current_char.is_numeric() {
-                    let register = self.read_number() as u8;
-                    Some(Token::Register {
-                        reg_number: register,
-                    })
+                    // Checked before narrowing, so $256 doesn't turn into $0.
+                    let register = self.read_number();
+                    match u8::try_from(register) {
+                        Ok(reg_number) => Some(Token::Register { reg_number }),
+                        Err(_) => {
+                            self.record_error_at(
+                                &format!(
+                                    "Register ${} is out of range (0-{})",
+                                    register,
+                                    REGISTER_COUNT - 1
+                                ),
+                                line,
+                                column,
+                            );
+                            None
+                        }
+                    }
                 } else {
-                    self.record_error(&format!("Expected number after '$' symbol"));
+                    self.record_error("Expected number after '$' symbol");
                     self.read_char();
                     None
                 }
             }
             '#' => {
                 self.read_char();
+                let negative = self.current_char == '-';
+                if negative {
+                    self.read_char();
+                }
                 if self.current_char.is_numeric() {
                     let number = self.read_number();
-                    Some(Token::IntegerOp { value: number })
+                    let value = if negative { -number } else { number };
+                    Some(Token::IntegerOp { value })
                 } else {
-                    self.record_error(&format!("Expected number after '#' symbol"));
+                    self.record_error("Expected number after '#' symbol");
                     None
                 }
             }
@@ -173,11 +205,15 @@ impl Lexer {
         self.read_char();
         let mut result = String::new();
         while self.current_char != '"' {
+            if self.read_position > self.source.len() {
+                self.record_error("Unterminated string literal");
+                return result;
+            }
             result.push(self.current_char);
             self.read_char();
         }
         self.read_char();
-        return result;
+        result
     }
 
     pub fn read_char(&mut self) {
@@ -212,10 +248,9 @@ impl Lexer {
             || self.current_char == '_'
             || self.current_char == '-'
             || self.current_char == '@'
-            || self.current_char == '@'
             || self.current_char == '.'
         {
-            if let Err(_) = write!(&mut result, "{}", self.current_char) {
+            if write!(&mut result, "{}", self.current_char).is_err() {
                 panic!(
                     "Could not add char to the string buffer {}",
                     self.current_char
@@ -223,13 +258,13 @@ impl Lexer {
             }
             self.read_char();
         }
-        return result;
+        result
     }
 
-    pub fn read_number(&mut self) -> i32 {
+    pub fn read_number(&mut self) -> i64 {
         let mut result = String::new();
         while self.current_char.is_numeric() {
-            if let Err(_) = write!(&mut result, "{}", self.current_char) {
+            if write!(&mut result, "{}", self.current_char).is_err() {
                 panic!(
                     "Coud not add char to the string buffer {}",
                     self.current_char
@@ -237,12 +272,22 @@ impl Lexer {
             }
             self.read_char();
         }
-        return result.parse::<i32>().expect("Failed to parse number");
+        match result.parse::<i64>() {
+            Ok(number) => number,
+            Err(_) => {
+                self.record_error(&format!("Number {} is too large", result));
+                0
+            }
+        }
     }
 
     fn record_error(&mut self, message: &str) {
+        self.record_error_at(message, self.current_line, self.current_column);
+    }
+
+    fn record_error_at(&mut self, message: &str, line: usize, column: usize) {
         let context = self.get_context();
-        let err = LexerError::new(message, self.current_line, self.current_column, context);
+        let err = LexerError::new(message, line, column, context);
         self.errors.push(err);
     }
 
@@ -267,7 +312,7 @@ mod tests {
     fn tokenize_and_expect_error(input: &str) {
         let mut lexer = Lexer::new(input.to_string());
         let result_tokenization = lexer.tokenize();
-        assert!(!result_tokenization.is_ok());
+        assert!(result_tokenization.is_err());
         if let Err(errors) = result_tokenization {
             assert_eq!(errors.len(), 1);
         }
@@ -367,6 +412,19 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_tokenize_neq() {
+        tokenize_and_check(
+            "neq $1 $2",
+            &[
+                Token::Op { code: Opcode::NEQ },
+                Token::Register { reg_number: 1 },
+                Token::Register { reg_number: 2 },
+            ],
+            3,
+        );
+    }
+
     #[test]
     fn test_tokenize_jeq() {
         tokenize_and_check(
@@ -417,6 +475,19 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_tokenize_hlt_and_exit() {
+        tokenize_and_check(
+            "hlt exit #3",
+            &[
+                Token::Op { code: Opcode::HLT },
+                Token::Op { code: Opcode::EXIT },
+                Token::IntegerOp { value: 3 },
+            ],
+            3,
+        );
+    }
+
     #[test]
     fn test_tokenize_label_decl() {
         tokenize_and_check(
@@ -436,9 +507,8 @@ mod tests {
                 Token::LabelUsage {
                     value: "test_label".to_string(),
                 },
-                Token::Op { code: Opcode::ZERO },
             ],
-            7,
+            6,
         );
     }
 
@@ -464,9 +534,8 @@ mod tests {
                 Token::StringLiteral {
                     value: "hello world!".to_string(),
                 },
-                Token::Op { code: Opcode::ZERO },
             ],
-            5,
+            4,
         )
     }
 
@@ -474,4 +543,40 @@ mod tests {
     fn test_errors() {
         tokenize_and_expect_error("load $1 gibrish");
     }
+
+    #[test]
+    fn test_unterminated_string() {
+        tokenize_and_expect_error(".asciiz \"never closed");
+    }
+
+    #[test]
+    fn test_register_out_of_range() {
+        let mut lexer = Lexer::new("inc $0\ninc $256".to_string());
+        let errors = lexer.tokenize().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message(), "Register $256 is out of range (0-31)");
+        assert_eq!((errors[0].line(), errors[0].column()), (2, 5));
+    }
+
+    #[test]
+    fn test_number_too_large() {
+        tokenize_and_expect_error("load $1 #99999999999999999999");
+    }
+
+    #[test]
+    fn test_tokenize_negative_and_wide_numbers() {
+        tokenize_and_check(
+            "load $1 #-42\nload $2 #99999999999",
+            &[
+                Token::Op { code: Opcode::LOAD },
+                Token::Register { reg_number: 1 },
+                Token::IntegerOp { value: -42 },
+                Token::Op { code: Opcode::LOAD },
+                Token::Register { reg_number: 2 },
+                Token::IntegerOp { value: 99999999999 },
+            ],
+            6,
+        );
+        tokenize_and_expect_error("load $1 #-");
+    }
 }
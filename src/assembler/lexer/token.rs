@@ -16,7 +16,7 @@ pub enum Token {
         reg_number: u8,
     },
     IntegerOp {
-        value: i32,
+        value: i64,
     },
     Directive {
         directive_type: DirectiveType,
@@ -33,3 +33,9 @@ pub enum DirectiveType {
     Data,
     Asciiz,
 }
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
@@ -2,23 +2,103 @@ pub mod lexer;
 pub mod parser;
 pub mod symbol;
 
-use core::panicking::panic;
+use std::fmt::Display;
 
-use lexer::{token::Token, Lexer};
+use lexer::{
+    token::{DirectiveType, Span, Token},
+    Lexer,
+};
 use parser::{AssemblyInstruction, Parser};
 use symbol::{
     symbol::{Symbol, SymbolType},
     symbol_table::SymbolTable,
 };
 
-use crate::vm::VM;
+use crate::instruction::{Encoding, Width};
 
+#[derive(Debug, Clone)]
 pub enum AssemblerSection {
     Data { starting_offset: Option<u32> },
     Code { starting_offset: Option<u32> },
     Unkown,
 }
 
+#[derive(Debug, PartialEq, Clone)]
+pub struct AssemblerError {
+    message: String,
+    span: Option<Span>,
+}
+
+impl AssemblerError {
+    pub fn new(message: &str, span: Option<Span>) -> AssemblerError {
+        AssemblerError {
+            message: message.to_string(),
+            span,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+}
+
+impl Display for AssemblerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.span {
+            Some(span) => write!(
+                f,
+                "ERROR: {} on line {} column {}",
+                self.message, span.line, span.column
+            ),
+            None => write!(f, "ERROR: {}", self.message),
+        }
+    }
+}
+
+// Where every emitted instruction came from and which code labels were
+// declared where, so tools looking at a running program (like the profiler)
+// can talk about source lines instead of raw addresses.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Listing {
+    pub instructions: Vec<(usize, Span)>,
+    pub labels: Vec<(String, usize)>,
+}
+
+impl Listing {
+    pub fn span_at(&self, offset: usize) -> Option<Span> {
+        self.instructions
+            .binary_search_by_key(&offset, |(start, _)| *start)
+            .ok()
+            .map(|index| self.instructions[index].1)
+    }
+
+    // The closest label at or before `offset` and how far past it `offset` is.
+    pub fn label_at(&self, offset: usize) -> Option<(&str, usize)> {
+        self.labels
+            .iter()
+            .filter(|(_, start)| *start <= offset)
+            .max_by_key(|(_, start)| *start)
+            .map(|(name, start)| (name.as_str(), offset - start))
+    }
+}
+
+struct Checkpoint {
+    symbol_table: SymbolTable,
+    read_only_len: usize,
+    bytecode_len: usize,
+    read_only_offset: u32,
+    sections_len: usize,
+    current_section: Option<AssemblerSection>,
+    current_offset: u32,
+    listing_len: usize,
+    listing_labels_len: usize,
+}
+
+#[derive(Clone)]
 pub struct Assembler {
     source: String,
 
@@ -33,8 +113,20 @@ pub struct Assembler {
 
     current_section: Option<AssemblerSection>,
 
-    current_inst: u32,
-    // errros: Vec<String>,
+    // Where the next instruction goes, in bytes.
+    current_offset: u32,
+
+    // The register width the code is assembled for, literals must fit in it.
+    pub width: Width,
+    // How the instructions are laid out in the bytecode.
+    pub encoding: Encoding,
+
+    listing: Listing,
+    // Lines assembled by earlier `assemble_more` calls, so the listing numbers
+    // lines as if all the pieces were one source.
+    line_offset: usize,
+
+    errors: Vec<AssemblerError>,
 }
 
 impl Assembler {
@@ -47,33 +139,451 @@ impl Assembler {
             read_only_offset: 0,
             sections: vec![],
             current_section: None,
-            current_inst: 0,
+            current_offset: 0,
+            width: Width::W32,
+            encoding: Encoding::Fixed,
+            listing: Listing::default(),
+            line_offset: 0,
+            errors: vec![],
         }
     }
 
-    fn get_tokens(&mut self) -> Vec<Token> {
-        let mut lexer = Lexer::new(&self.source);
+    fn get_tokens(&mut self) -> Option<(Vec<Token>, Vec<Span>)> {
+        let mut lexer = Lexer::new(self.source.clone());
         match lexer.tokenize() {
-            Ok(tokens) => tokens,
-            Err(errors) => panic!("{:?}", errors),
+            Ok(tokens) => Some((tokens, lexer.spans().clone())),
+            Err(errors) => {
+                for err in errors {
+                    let span = Span {
+                        line: err.line(),
+                        column: err.column(),
+                    };
+                    self.errors
+                        .push(AssemblerError::new(err.message(), Some(span)));
+                }
+                None
+            }
         }
     }
 
-    fn get_instructions(&mut self, tokens: Vec<Token>) -> Vec<AssemblyInstruction> {
-        let mut parser = Parser::new(tokens);
+    fn get_instructions(
+        &mut self,
+        tokens: Vec<Token>,
+        spans: Vec<Span>,
+    ) -> Option<Vec<AssemblyInstruction>> {
+        let mut parser = Parser::new_with_spans(tokens, spans);
         match parser.parse() {
-            Ok(instructions) => instructions,
-            Err(errros) => panic!("{:?}", errros),
+            Ok(instructions) => Some(instructions),
+            Err(errors) => {
+                for err in errors {
+                    self.errors
+                        .push(AssemblerError::new(err.message(), Some(err.span())));
+                }
+                None
+            }
+        }
+    }
+
+    // Assembles the source into bytecode. A failed assembly leaves the assembler
+    // exactly as it was before the call.
+    pub fn assemble(&mut self) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let checkpoint = self.checkpoint();
+        let instructions = self
+            .get_tokens()
+            .and_then(|(tokens, spans)| self.get_instructions(tokens, spans));
+        if let Some(mut instructions) = instructions {
+            self.first_phase(&instructions);
+            if self.errors.is_empty() {
+                self.second_phase(&mut instructions);
+            }
+        }
+        if !self.errors.is_empty() {
+            self.rollback(checkpoint);
+            // Numbered like the listing, as if all the pieces were one source.
+            let mut errors = std::mem::take(&mut self.errors);
+            for span in errors.iter_mut().filter_map(|err| err.span.as_mut()) {
+                span.line += self.line_offset;
+            }
+            return Err(errors);
+        }
+        Ok(self.bytecode.clone())
+    }
+
+    // Assembles another piece of source on top of everything assembled so far,
+    // so labels declared earlier stay resolvable. Only the bytes produced for the
+    // new source are returned.
+    pub fn assemble_more(&mut self, source: String) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let start = self.bytecode.len();
+        let lines = source.lines().count();
+        self.source = source;
+        let bytecode = self.assemble()?;
+        self.line_offset += lines;
+        Ok(bytecode[start..].to_vec())
+    }
+
+    // Accounts for bytecode that was produced somewhere else (e.g. typed in as hex)
+    // so that labels declared afterwards still get the right offsets.
+    pub fn append_bytecode(&mut self, bytes: &[u8]) {
+        self.bytecode.extend_from_slice(bytes);
+        self.current_offset += bytes.len() as u32;
+    }
+
+    // Same as `append_bytecode` for read-only data, so data labels declared
+    // afterwards land behind it.
+    pub fn append_read_only(&mut self, bytes: &[u8]) {
+        self.read_only_secion.extend_from_slice(bytes);
+        self.read_only_offset += bytes.len() as u32;
+    }
+
+    pub fn symbol_table(&self) -> &SymbolTable {
+        &self.symbol_table
+    }
+
+    pub fn listing(&self) -> &Listing {
+        &self.listing
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            symbol_table: self.symbol_table.clone(),
+            read_only_len: self.read_only_secion.len(),
+            bytecode_len: self.bytecode.len(),
+            read_only_offset: self.read_only_offset,
+            sections_len: self.sections.len(),
+            current_section: self.current_section.clone(),
+            current_offset: self.current_offset,
+            listing_len: self.listing.instructions.len(),
+            listing_labels_len: self.listing.labels.len(),
         }
     }
 
-    pub fn assemble(&mut self) {
-        let tokens = self.get_tokens();
-        let instructions = self.get_instructions(tokens);
-        self.first_phase(&instructions);
+    fn rollback(&mut self, checkpoint: Checkpoint) {
+        self.symbol_table = checkpoint.symbol_table;
+        self.read_only_secion.truncate(checkpoint.read_only_len);
+        self.bytecode.truncate(checkpoint.bytecode_len);
+        self.read_only_offset = checkpoint.read_only_offset;
+        self.sections.truncate(checkpoint.sections_len);
+        self.current_section = checkpoint.current_section;
+        self.current_offset = checkpoint.current_offset;
+        self.listing.instructions.truncate(checkpoint.listing_len);
+        self.listing.labels.truncate(checkpoint.listing_labels_len);
     }
 
+    // Walks the instructions once to record where every label lands, so that
+    // labels can be used before they are declared.
     pub fn first_phase(&mut self, insts: &Vec<AssemblyInstruction>) {
-        for i in insts {}
+        for inst in insts {
+            match inst.directive_type() {
+                Some(DirectiveType::Data) => {
+                    self.switch_section(AssemblerSection::Data {
+                        starting_offset: Some(self.read_only_offset),
+                    });
+                }
+                Some(DirectiveType::Code) => {
+                    self.switch_section(AssemblerSection::Code {
+                        starting_offset: Some(self.current_offset),
+                    });
+                }
+                _ => {}
+            }
+            if let Some(name) = inst.label_name() {
+                self.declare_label(name, inst.span);
+            }
+            if let Some(literal) = inst.string_literal() {
+                self.read_only_secion.extend_from_slice(literal.as_bytes());
+                self.read_only_secion.push(0);
+                self.read_only_offset += literal.len() as u32 + 1;
+            }
+            if let Some(value) = inst.load_literal() {
+                if !self.width.fits(value) {
+                    self.errors.push(AssemblerError::new(
+                        &format!(
+                            "#{} does not fit in a {}-bit register",
+                            value,
+                            self.width.bits()
+                        ),
+                        Some(inst.span),
+                    ));
+                }
+            }
+            self.current_offset += inst.byte_len(self.encoding) as u32;
+        }
+    }
+
+    pub fn second_phase(&mut self, insts: &mut Vec<AssemblyInstruction>) {
+        for inst in insts {
+            let span = Span {
+                line: inst.span.line + self.line_offset,
+                column: inst.span.column,
+            };
+            if let Some(opcode) = inst.opcode() {
+                // The instructions of a `load` sequence all have the same length.
+                let length = self.encoding.length(opcode);
+                for index in 0..inst.instruction_count() {
+                    let offset = self.bytecode.len() + index * length;
+                    self.listing.instructions.push((offset, span));
+                }
+            }
+            match inst.to_bytes(&self.symbol_table, self.encoding) {
+                Ok(bytes) => self.bytecode.extend(bytes),
+                Err(message) => self
+                    .errors
+                    .push(AssemblerError::new(&message, Some(inst.span))),
+            }
+        }
+    }
+
+    fn switch_section(&mut self, section: AssemblerSection) {
+        if let Some(previous) = self.current_section.take() {
+            self.sections.push(previous);
+        }
+        self.current_section = Some(section);
+    }
+
+    fn declare_label(&mut self, name: &str, span: Span) {
+        if self.symbol_table.has_symbol(name) {
+            self.errors.push(AssemblerError::new(
+                &format!("label `{}` is declared more than once", name),
+                Some(span),
+            ));
+            return;
+        }
+        // Anything before the first section directive is code.
+        let (offset, symbol_type) = match self.current_section {
+            Some(AssemblerSection::Data { .. }) => (self.read_only_offset, SymbolType::Data),
+            Some(AssemblerSection::Code { .. } | AssemblerSection::Unkown) | None => {
+                (self.current_offset, SymbolType::Code)
+            }
+        };
+        if symbol_type == SymbolType::Code {
+            self.listing
+                .labels
+                .push((name.to_string(), offset as usize));
+        }
+        self.symbol_table
+            .add_symbol(Symbol::new(name.to_string(), offset, symbol_type));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{RunStatus, VM};
+
+    #[test]
+    fn test_assemble_forward_label() {
+        let mut assembler = Assembler::new(
+            r###"
+                jmp @end
+                inc $0
+                end:
+                dec $0
+            "###
+            .to_string(),
+        );
+        let bytecode = assembler.assemble().unwrap();
+        assert_eq!(&bytecode[0..4], &[6, 0, 8, 0]);
+
+        // Jumps reach past the first 255 bytes.
+        let source = format!("jmp @end\n{}end:\nhlt", "inc $0\n".repeat(100));
+        let bytecode = Assembler::new(source).assemble().unwrap();
+        assert_eq!(&bytecode[0..4], &[6, 1, 148, 0]);
+        let mut vm = VM::new_with_program(bytecode);
+        assert_eq!(vm.run(), RunStatus::Halted(0));
+        assert_eq!(vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_assemble_wide_immediate() {
+        let mut assembler = Assembler::new("load $0 #300\nloadx $1 #65535".to_string());
+        assert_eq!(
+            assembler.assemble().unwrap(),
+            vec![1, 0, 1, 44, 17, 1, 255, 255]
+        );
+    }
+
+    #[test]
+    fn test_assemble_trailing_whitespace() {
+        let mut assembler = Assembler::new("inc $0\n\n\n".to_string());
+        assert_eq!(assembler.assemble().unwrap(), vec![10, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_assemble_large_literals() {
+        let mut assembler = Assembler::new("load $0 #-5\nload $1 #70000\nend:\nhlt".to_string());
+        assert_eq!(
+            assembler.assemble().unwrap(),
+            vec![16, 0, 255, 251, 1, 1, 0, 1, 17, 1, 17, 112, 14, 0, 0, 0]
+        );
+        assert_eq!(assembler.symbol_table().get_symbol_value("end"), Some(12));
+        assert_eq!(assembler.listing().span_at(8).unwrap().line, 2);
+
+        let mut assembler = Assembler::new("load $0 #4294967296".to_string());
+        let errors = assembler.assemble().unwrap_err();
+        assert_eq!(
+            errors[0].message(),
+            "#4294967296 does not fit in a 32-bit register"
+        );
+        assembler.width = Width::W64;
+        assert_eq!(assembler.assemble().unwrap().len(), 12);
+    }
+
+    #[test]
+    fn test_assemble_compact() {
+        let mut assembler =
+            Assembler::new("load $0 #70000\nloop:\ndec $0\njmp @loop\nend:\nhlt".to_string());
+        assembler.encoding = Encoding::Compact;
+        assert_eq!(
+            assembler.assemble().unwrap(),
+            vec![1, 0, 0, 1, 17, 0, 17, 112, 11, 0, 6, 0, 8, 14]
+        );
+        assert_eq!(assembler.symbol_table().get_symbol_value("end"), Some(13));
+        assert_eq!(assembler.listing().span_at(10).unwrap().line, 4);
+    }
+
+    #[test]
+    fn test_assemble_data_addresses() {
+        let mut assembler = Assembler::new(
+            ".data\nhello: .asciiz \"hello\"\nworld: .asciiz \"world\"\n.code\nlda $0 @world\nstrload $2 $0"
+                .to_string(),
+        );
+        assert_eq!(
+            assembler.assemble().unwrap(),
+            vec![27, 0, 0, 6, 29, 2, 0, 0]
+        );
+        assert_eq!(assembler.read_only_secion, b"hello\0world\0");
+
+        // Data labels reach past the first 255 bytes.
+        let source = format!(
+            ".data\npadding: .asciiz \"{}\"\nfar: .asciiz \"far\"\n.code\nlda $0 @far",
+            "x".repeat(300)
+        );
+        let mut assembler = Assembler::new(source);
+        assert_eq!(assembler.assemble().unwrap(), vec![27, 0, 1, 45]);
+
+        let mut assembler = Assembler::new("strload $31 $0\nlda $0 @nowhere".to_string());
+        let errors = assembler.assemble().unwrap_err();
+        assert_eq!(
+            errors[0].message(),
+            "`strload` expects a register pair as operand 1, but register pair $31 is out of range (0-30)"
+        );
+    }
+
+    #[test]
+    fn test_assemble_label_kinds() {
+        let mut assembler =
+            Assembler::new(".data\nmsg: .asciiz \"hi\"\n.code\njmp @msg\nhlt".to_string());
+        let errors = assembler.assemble().unwrap_err();
+        assert_eq!(
+            errors[0].message(),
+            "`msg` is a data label, but a code label is needed here"
+        );
+        assert_eq!(errors[0].span, Some(Span { line: 4, column: 1 }));
+
+        let mut assembler = Assembler::new("start:\nlda $0 @start".to_string());
+        let errors = assembler.assemble().unwrap_err();
+        assert_eq!(
+            errors[0].message(),
+            "`start` is a code label, but a data label is needed here"
+        );
+        assert_eq!(errors[0].span, Some(Span { line: 2, column: 1 }));
+    }
+
+    #[test]
+    fn test_assemble_labels_out_of_range() {
+        // Only using a label jumps can't reach is an error.
+        let body = "inc $0\n".repeat(16_400);
+        let mut assembler = Assembler::new(format!("{}far:\nhlt", body));
+        assert!(assembler.assemble().is_ok());
+        let mut assembler = Assembler::new(format!("jmp @far\n{}far:\nhlt", body));
+        let errors = assembler.assemble().unwrap_err();
+        assert_eq!(
+            errors[0].message(),
+            "label `far` at offset 65604 is out of range (0-65535)"
+        );
+        assert_eq!(errors[0].span, Some(Span { line: 1, column: 1 }));
+    }
+
+    #[test]
+    fn test_assemble_undefined_label() {
+        let mut assembler = Assembler::new(
+            r###"
+                loop:
+                inc $0
+                jmp @lopo
+            "###
+            .to_string(),
+        );
+        let errors = assembler.assemble().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].message(),
+            "undefined label `lopo`, did you mean `loop`?"
+        );
+        assert_eq!(errors[0].span().unwrap().line, 4);
+    }
+
+    #[test]
+    fn test_assemble_duplicate_label() {
+        let mut assembler = Assembler::new("a:\ninc $0\na:\ndec $0".to_string());
+        let errors = assembler.assemble().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span().unwrap().line, 3);
+    }
+
+    #[test]
+    fn test_assemble_more_keeps_labels() {
+        let mut assembler = Assembler::new(String::new());
+        assert_eq!(
+            assembler.assemble_more("inc $0\n".to_string()).unwrap(),
+            vec![10, 0, 0, 0]
+        );
+        assert_eq!(
+            assembler
+                .assemble_more("loop: dec $0\n".to_string())
+                .unwrap(),
+            vec![11, 0, 0, 0]
+        );
+        assert_eq!(
+            assembler.assemble_more("jmp @loop\n".to_string()).unwrap(),
+            vec![6, 0, 4, 0]
+        );
+        assert_eq!(assembler.listing().span_at(8).unwrap().line, 3);
+
+        // Errors are numbered like the listing.
+        let errors = assembler
+            .assemble_more("inc $0\njmp @nowhere\n".to_string())
+            .unwrap_err();
+        assert_eq!(errors[0].span, Some(Span { line: 5, column: 1 }));
+        let errors = assembler
+            .assemble_more("inc $99\n".to_string())
+            .unwrap_err();
+        assert_eq!(errors[0].span.unwrap().line, 4);
+    }
+
+    #[test]
+    fn test_listing() {
+        let mut assembler = Assembler::new(
+            ".data\nmsg: .asciiz \"hi\"\n.code\ninc $0\nloop:\ndec $0\njmp @loop\n".to_string(),
+        );
+        assert!(assembler.assemble().is_ok());
+        let listing = assembler.listing();
+        assert_eq!(listing.span_at(4).unwrap().line, 6);
+        assert_eq!(listing.span_at(5), None);
+        assert_eq!(listing.label_at(8), Some(("loop", 4)));
+        assert_eq!(listing.label_at(0), None);
+    }
+
+    #[test]
+    fn test_failed_assembly_rolls_back() {
+        let mut assembler = Assembler::new(String::new());
+        assert!(assembler
+            .assemble_more("a: inc $0\njmp @nowhere\n".to_string())
+            .is_err());
+        assert!(!assembler.symbol_table().has_symbol("a"));
+        assert_eq!(
+            assembler.assemble_more("a: inc $0\n".to_string()).unwrap(),
+            vec![10, 0, 0, 0]
+        );
     }
 }
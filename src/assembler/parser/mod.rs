@@ -1,8 +1,12 @@
-use crate::instruction::Opcode;
+use std::fmt::Display;
+
+use std::ops::RangeInclusive;
+
+use crate::instruction::{Encoding, Opcode, OperandKind, MAX_EXIT_STATUS, REGISTER_COUNT};
 
 use super::{
-    lexer::token::{DirectiveType, Token},
-    symbol::symbol_table::SymbolTable,
+    lexer::token::{DirectiveType, Span, Token},
+    symbol::{symbol::SymbolType, symbol_table::SymbolTable},
 };
 
 #[allow(dead_code)]
@@ -14,58 +18,231 @@ pub struct AssemblyInstruction {
     operand1: Option<Token>,
     operand2: Option<Token>,
     operand3: Option<Token>,
+    pub span: Span,
 }
 
 impl AssemblyInstruction {
-    pub fn to_bytes(&mut self, symbol_table: &SymbolTable) -> Option<Vec<u8>> {
-        if let None = self.opcode {
-            return None;
-        }
+    pub fn to_bytes(
+        &mut self,
+        symbol_table: &SymbolTable,
+        encoding: Encoding,
+    ) -> Result<Vec<u8>, String> {
         let mut bytes: Vec<u8> = vec![];
-        if let Some(token) = self.opcode.clone() {
-            match token {
-                Token::Op { code } => match code {
-                    _ => bytes.push(u8::from(code)),
-                },
-
-                _ => {}
-            };
+        if self.opcode.is_none() {
+            return Ok(bytes);
         }
-        for op in vec![
+        if let (Some(Token::Register { reg_number }), Some(value)) =
+            (&self.operand1, self.load_literal())
+        {
+            for (opcode, immediate) in load_sequence(value) {
+                let start = bytes.len();
+                bytes.push(u8::from(opcode));
+                bytes.push(*reg_number);
+                bytes.extend_from_slice(&immediate.to_be_bytes());
+                bytes.resize(start + encoding.length(opcode), 0);
+            }
+            return Ok(bytes);
+        }
+        // The data address of an `lda` is an immediate like any other.
+        if let (Some(Opcode::LDA), Some(Token::LabelUsage { value })) =
+            (self.opcode(), &self.operand2)
+        {
+            self.operand2 = Some(Token::IntegerOp {
+                value: resolve_label(value, SymbolType::Data, symbol_table)? as i64,
+            });
+        }
+        let length = match self.opcode() {
+            Some(code) => {
+                bytes.push(u8::from(code));
+                encoding.length(code)
+            }
+            None => 0,
+        };
+        for op in [
             self.label.clone(),
             self.operand1.clone(),
             self.operand2.clone(),
             self.operand3.clone(),
-        ] {
-            match op {
-                Some(op) => AssemblyInstruction::extract_operands(op, &mut bytes, &symbol_table),
-                None => {}
-            };
+        ]
+        .into_iter()
+        .flatten()
+        {
+            AssemblyInstruction::extract_operands(op, &mut bytes, symbol_table)?;
+        }
+        bytes.resize(length, 0);
+        Ok(bytes)
+    }
+
+    pub fn is_opcode(&self) -> bool {
+        self.opcode.is_some()
+    }
+
+    pub fn opcode(&self) -> Option<Opcode> {
+        match &self.opcode {
+            Some(Token::Op { code }) => Some(*code),
+            _ => None,
+        }
+    }
+
+    // How many bytes this line takes up in the bytecode.
+    pub fn byte_len(&self, encoding: Encoding) -> usize {
+        match self.opcode() {
+            Some(opcode) => self.instruction_count() * encoding.length(opcode),
+            None => 0,
+        }
+    }
+
+    // How many machine instructions this line turns into, a `load` of a
+    // literal that needs more than 16 bits becomes a sequence.
+    pub fn instruction_count(&self) -> usize {
+        match (&self.opcode, self.load_literal()) {
+            (None, _) => 0,
+            (Some(_), Some(value)) => load_sequence(value).len(),
+            (Some(_), None) => 1,
+        }
+    }
+
+    // The literal of a `load`, the one instruction taking any integer.
+    pub fn load_literal(&self) -> Option<i64> {
+        match (&self.opcode, &self.operand2) {
+            (Some(Token::Op { code: Opcode::LOAD }), Some(Token::IntegerOp { value })) => {
+                Some(*value)
+            }
+            _ => None,
         }
-        while bytes.len() < 4 {
-            bytes.push(0);
+    }
+
+    pub fn label_name(&self) -> Option<&str> {
+        match &self.label {
+            Some(Token::LabelDeclaration { value }) => Some(value),
+            _ => None,
         }
-        Some(bytes)
     }
 
-    fn extract_operands(op: Token, bytes: &mut Vec<u8>, st: &SymbolTable) {
+    pub fn directive_type(&self) -> Option<DirectiveType> {
+        match &self.directive {
+            Some(Token::Directive { directive_type, .. }) => Some(*directive_type),
+            _ => None,
+        }
+    }
+
+    pub fn string_literal(&self) -> Option<&str> {
+        match &self.operand1 {
+            Some(Token::StringLiteral { value }) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn extract_operands(op: Token, bytes: &mut Vec<u8>, st: &SymbolTable) -> Result<(), String> {
         match op {
             Token::Register { reg_number } => bytes.push(reg_number),
             Token::IntegerOp { value } => {
                 let converted = value as u16;
-                let high_part = converted & 0xFF00;
+                let high_part = converted >> 8;
                 let low_part = converted & 0x00FF;
                 bytes.push(high_part as u8);
                 bytes.push(low_part as u8);
             }
             Token::LabelUsage { value } => {
-                if let Some(offset) = st.get_symbol_value(&value) {
-                    bytes.push(offset);
-                }
+                bytes.extend_from_slice(&resolve_label(&value, SymbolType::Code, st)?.to_be_bytes())
             }
 
             _ => {}
         };
+        Ok(())
+    }
+}
+
+// The 16-bit address of a label of the kind the instruction takes.
+fn resolve_label(name: &str, expected: SymbolType, st: &SymbolTable) -> Result<u16, String> {
+    match st.get_symbol(name) {
+        Some(symbol) if symbol.symbol_type != expected => Err(format!(
+            "`{}` is {}, but {} is needed here",
+            name,
+            symbol.symbol_type.describe(),
+            expected.describe()
+        )),
+        Some(symbol) => u16::try_from(symbol.offset).map_err(|_| {
+            format!(
+                "label `{}` at offset {} is out of range (0-{})",
+                name,
+                symbol.offset,
+                u16::MAX
+            )
+        }),
+        None => {
+            let mut message = format!("undefined label `{}`", name);
+            if let Some(suggestion) = st.closest_match(name) {
+                message.push_str(&format!(", did you mean `{}`?", suggestion));
+            }
+            Err(message)
+        }
+    }
+}
+
+// The shortest sequence of instructions that loads `value` into a register:
+// a `load` (zero extended) or `loads` (sign extended) of the top 16 bits and a
+// `loadx` for every 16 bits below them.
+pub fn load_sequence(value: i64) -> Vec<(Opcode, u16)> {
+    let mut chunks = 1;
+    let first = if value >= 0 {
+        while chunks < 4 && value >> (16 * chunks) != 0 {
+            chunks += 1;
+        }
+        Opcode::LOAD
+    } else {
+        while chunks < 4 && value >> (16 * chunks - 1) != -1 {
+            chunks += 1;
+        }
+        Opcode::LOADS
+    };
+    let mut sequence = vec![(first, (value >> (16 * (chunks - 1))) as u16)];
+    for chunk in (0..chunks - 1).rev() {
+        sequence.push((Opcode::LOADX, (value >> (16 * chunk)) as u16));
+    }
+    sequence
+}
+
+// The integers an instruction accepts as its immediate.
+fn immediate_range(opcode: Opcode) -> RangeInclusive<i64> {
+    match opcode {
+        Opcode::LOAD => i64::MIN..=i64::MAX,
+        Opcode::LOADS => i16::MIN as i64..=i16::MAX as i64,
+        Opcode::EXIT => 0..=MAX_EXIT_STATUS as i64,
+        _ => 0..=u16::MAX as i64,
+    }
+}
+
+#[derive(Debug)]
+pub struct ParserError {
+    message: String,
+    span: Span,
+}
+
+impl ParserError {
+    pub fn new(message: &str, span: Span) -> ParserError {
+        ParserError {
+            message: message.to_string(),
+            span,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
+
+impl Display for ParserError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ERROR: {} on line {} column {}",
+            self.message, self.span.line, self.span.column
+        )
     }
 }
 
@@ -73,22 +250,38 @@ impl AssemblyInstruction {
 #[derive(Debug)]
 pub struct Parser {
     tokens: Vec<Token>,
+    spans: Vec<Span>,
     current: usize,
 }
 
 #[allow(dead_code)]
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Parser {
-        Parser { tokens, current: 0 }
+        Parser {
+            tokens,
+            spans: Vec::new(),
+            current: 0,
+        }
     }
 
-    pub fn parse(&mut self) -> Result<Vec<AssemblyInstruction>, Vec<String>> {
-        let mut errors: Vec<String> = vec![];
+    pub fn new_with_spans(tokens: Vec<Token>, spans: Vec<Span>) -> Parser {
+        Parser {
+            tokens,
+            spans,
+            current: 0,
+        }
+    }
+
+    pub fn parse(&mut self) -> Result<Vec<AssemblyInstruction>, Vec<ParserError>> {
+        let mut errors: Vec<ParserError> = vec![];
         let mut instructions: Vec<AssemblyInstruction> = vec![];
         while self.current < self.tokens.len() {
             match self.parse_instruction() {
                 Ok(token) => instructions.push(token),
-                Err(e) => errors.push(e),
+                Err(e) => {
+                    errors.push(e);
+                    self.synchronize();
+                }
             }
         }
         if !errors.is_empty() {
@@ -97,10 +290,11 @@ impl Parser {
         Ok(instructions)
     }
 
-    fn parse_instruction(&mut self) -> Result<AssemblyInstruction, String> {
+    fn parse_instruction(&mut self) -> Result<AssemblyInstruction, ParserError> {
+        let span = self.current_span();
         let token = self
             .next_token()
-            .ok_or("expected an opcode but found none")?;
+            .ok_or(ParserError::new("expected an opcode but found none", span))?;
 
         match token {
             Token::LabelDeclaration { .. } => {
@@ -111,6 +305,7 @@ impl Parser {
                     operand2: None,
                     operand3: None,
                     label: Some(token),
+                    span,
                 });
             }
             Token::Directive { directive_type, .. } => {
@@ -125,11 +320,13 @@ impl Parser {
                                     operand1: Some(string_literla_tok),
                                     opcode: None,
                                     label: None,
+                                    span,
                                 });
                             }
                             _ => {
-                                return Err(format!(
-                                    "Expected string literal after asciiz directive"
+                                return Err(ParserError::new(
+                                    "Expected string literal after asciiz directive",
+                                    span,
                                 ))
                             }
                         }
@@ -142,6 +339,7 @@ impl Parser {
                     operand2: None,
                     operand3: None,
                     label: None,
+                    span,
                 });
             }
             _ => {}
@@ -155,79 +353,123 @@ impl Parser {
                 operand2: None,
                 operand3: None,
                 label: Some(token),
+                span,
             });
         }
 
         let opcode = match token {
             Token::Op { code } => code,
-            _ => return Err(format!("expected an opcode but found {:?}", token)),
+            _ => {
+                return Err(ParserError::new(
+                    &format!("expected an opcode but found {}", describe_token(&token)),
+                    span,
+                ))
+            }
         };
 
-        let (operand1, operand2, operand3, label) = match opcode {
-            Opcode::ADD | Opcode::SUB | Opcode::DIV | Opcode::MUL => {
-                let op1 = self.next_token();
-                let op2 = self.next_token();
-                let op3 = self.next_token();
-                if !self.check_if_operand(&op1) {
-                    return Err(format!("Unexpected operand for add instruction {:?}", op1));
-                } else if !self.check_if_operand(&op2) {
-                    return Err(format!("Unexpected operand for add instruction {:?}", op2));
-                } else if !self.check_if_operand(&op3) {
-                    return Err(format!("Unexpected operand for add instruction {:?}", op3));
+        let mut operands: Vec<Option<Token>> = vec![];
+        for (index, kind) in opcode.operands().iter().enumerate() {
+            let operand_span = self.current_span();
+            let operand = self.next_token();
+            if let Err(message) = self.check_operand(opcode, *kind, &operand) {
+                return Err(ParserError::new(
+                    &format!(
+                        "`{}` expects {} as operand {}, {}",
+                        opcode.mnemonic(),
+                        kind.describe(),
+                        index + 1,
+                        message
+                    ),
+                    operand_span,
+                ));
+            }
+            operands.push(operand);
+        }
+        let mut operands = operands.into_iter();
+
+        Ok(AssemblyInstruction {
+            opcode: Some(token),
+            operand1: operands.next().flatten(),
+            operand2: operands.next().flatten(),
+            operand3: operands.next().flatten(),
+            label: None,
+            directive: None,
+            span,
+        })
+    }
+
+    fn check_operand(
+        &self,
+        opcode: Opcode,
+        kind: OperandKind,
+        token: &Option<Token>,
+    ) -> Result<(), String> {
+        match (kind, token) {
+            (OperandKind::Register, Some(Token::Register { reg_number })) => {
+                if (*reg_number as usize) < REGISTER_COUNT {
+                    Ok(())
                 } else {
-                    (op1, op2, op3, None)
+                    Err(format!(
+                        "but register ${} is out of range (0-{})",
+                        reg_number,
+                        REGISTER_COUNT - 1
+                    ))
                 }
             }
-            Opcode::LOAD | Opcode::EQ => {
-                let op1 = self.next_token();
-                let op2 = self.next_token();
-                if !self.check_if_operand(&op1) {
-                    return Err(format!("Unexpected operand for add instruction {:?}", op1));
-                } else if !self.check_if_operand(&op2) {
-                    return Err(format!("Unexpected operand for add instruction {:?}", op2));
+            (OperandKind::Pair, Some(Token::Register { reg_number })) => {
+                if (*reg_number as usize) < REGISTER_COUNT - 1 {
+                    Ok(())
                 } else {
-                    (op1, op2, None, None)
+                    Err(format!(
+                        "but register pair ${} is out of range (0-{})",
+                        reg_number,
+                        REGISTER_COUNT - 2
+                    ))
                 }
             }
-            Opcode::JMP | Opcode::JEQ | Opcode::JNEQ => {
-                let op = self.next_token();
-                if !self.check_if_operand(&op) {
-                    return Err(format!("unexpected bla bla {:?}", op));
-                }
-                (None, None, None, op)
+            // `lda` takes the address of a data label.
+            (OperandKind::Immediate, Some(Token::LabelUsage { .. })) if opcode == Opcode::LDA => {
+                Ok(())
             }
-            Opcode::INC | Opcode::DEC | Opcode::ALLOC => {
-                let op = self.next_token();
-                if !self.check_if_operand(&op) {
-                    return Err(format!("Unexpeted operand for inc/dec/alloc"));
+            (OperandKind::Immediate, Some(Token::IntegerOp { value })) => {
+                let range = immediate_range(opcode);
+                if range.contains(value) {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "but #{} is out of range ({}-{})",
+                        value,
+                        range.start(),
+                        range.end()
+                    ))
                 }
-                (op, None, None, None)
             }
-            Opcode::ZERO => (None, None, None, None),
-            _ => (None, None, None, None),
-        };
-
-        Ok(AssemblyInstruction {
-            opcode: Some(token),
-            operand1,
-            operand2,
-            operand3,
-            label,
-            directive: None,
-        })
+            (OperandKind::Label, Some(Token::LabelUsage { .. })) => Ok(()),
+            (_, Some(token)) => Err(format!("found {}", describe_token(token))),
+            (_, None) => Err("found end of input".to_string()),
+        }
     }
 
-    fn check_if_operand(&self, token: &Option<Token>) -> bool {
-        if let Some(token) = token {
-            return matches!(
-                token,
-                Token::IntegerOp { .. } | Token::Register { .. } | Token::LabelUsage { .. }
-            );
-        } else {
-            return false;
+    // Skips the rest of a broken instruction so that one mistake is reported once
+    // instead of cascading into errors for every leftover operand.
+    fn synchronize(&mut self) {
+        while let Some(token) = self.tokens.get(self.current) {
+            if self.is_opcode(token)
+                || matches!(
+                    token,
+                    Token::LabelDeclaration { .. } | Token::Directive { .. }
+                )
+            {
+                break;
+            }
+            self.current += 1;
         }
     }
 
+    fn current_span(&self) -> Span {
+        self.spans.get(self.current).copied().unwrap_or_default()
+    }
+
     pub fn next_token(&mut self) -> Option<Token> {
         if self.current < self.tokens.len() {
             let token = self.tokens[self.current].clone();
@@ -243,35 +485,74 @@ impl Parser {
     }
 }
 
+fn describe_token(token: &Token) -> String {
+    match token {
+        Token::Op { code } => format!("instruction `{}`", code.mnemonic()),
+        Token::LabelDeclaration { value } => format!("label declaration `{}:`", value),
+        Token::LabelUsage { value } => format!("label `@{}`", value),
+        Token::Register { reg_number } => format!("register `${}`", reg_number),
+        Token::IntegerOp { value } => format!("integer `#{}`", value),
+        Token::Directive { literal, .. } => format!("directive `{}`", literal),
+        Token::StringLiteral { value } => format!("string \"{}\"", value),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Token;
+    use super::{load_sequence, Token};
     use crate::{
         assembler::{
             lexer::token::DirectiveType,
-            parser::{AssemblyInstruction, Parser},
-            symbol::symbol_table::SymbolTable,
+            parser::Parser,
+            symbol::{
+                symbol::{Symbol, SymbolType},
+                symbol_table::SymbolTable,
+            },
         },
-        instruction::Opcode,
+        instruction::{Encoding, Opcode},
     };
 
     fn parse_and_check(tokens: Vec<Token>, expected_bytes: Option<&[u8]>, expected_len: usize) {
+        parse_and_check_with_symbols(tokens, &SymbolTable::new(), expected_bytes, expected_len);
+    }
+
+    fn parse_and_check_with_symbols(
+        tokens: Vec<Token>,
+        st: &SymbolTable,
+        expected_bytes: Option<&[u8]>,
+        expected_len: usize,
+    ) {
         let mut parser = Parser::new(tokens);
         let insts = parser.parse();
         assert!(insts.is_ok());
         let mut insts = insts.unwrap();
         assert_eq!(insts.len(), expected_len);
         if let Some(expected_bytes) = expected_bytes {
-            let st = SymbolTable::new();
-            let bytes = insts[0].to_bytes(&st);
-            assert!(bytes.is_some());
-            if let Some(bytes) = bytes {
+            let bytes = insts[0].to_bytes(st, Encoding::Fixed);
+            assert!(bytes.is_ok());
+            if let Ok(bytes) = bytes {
                 assert_eq!(bytes.len(), expected_bytes.len());
                 assert_eq!(bytes, expected_bytes);
             }
         }
     }
 
+    fn parse_and_expect_error(tokens: Vec<Token>, expected_message: &str) {
+        let mut parser = Parser::new(tokens);
+        let result_parse = parser.parse();
+        assert!(result_parse.is_err());
+        if let Err(errors) = result_parse {
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].message(), expected_message);
+        }
+    }
+
+    fn symbols_with(name: &str, offset: u32) -> SymbolTable {
+        let mut st = SymbolTable::new();
+        st.add_symbol(Symbol::new(name.to_string(), offset, SymbolType::Code));
+        st
+    }
+
     #[test]
     fn test_parse_load() {
         let tokens = vec![
@@ -330,9 +611,11 @@ mod tests {
     fn test_parse_jmp() {
         let tokens = vec![
             Token::Op { code: Opcode::JMP },
-            Token::Register { reg_number: 10 },
+            Token::LabelUsage {
+                value: "target".to_string(),
+            },
         ];
-        parse_and_check(tokens, Some(&[6, 10, 0, 0]), 1);
+        parse_and_check_with_symbols(tokens, &symbols_with("target", 10), Some(&[6, 0, 10, 0]), 1);
     }
 
     #[test]
@@ -349,9 +632,11 @@ mod tests {
     fn test_parse_jeq() {
         let tokens = vec![
             Token::Op { code: Opcode::JEQ },
-            Token::Register { reg_number: 5 },
+            Token::LabelUsage {
+                value: "target".to_string(),
+            },
         ];
-        parse_and_check(tokens, Some(&[8, 5, 0, 0]), 1);
+        parse_and_check_with_symbols(tokens, &symbols_with("target", 5), Some(&[8, 0, 5, 0]), 1);
     }
 
     #[test]
@@ -383,6 +668,27 @@ mod tests {
         parse_and_check(tokens, Some(&[11, 5, 0, 0]), 1);
     }
 
+    #[test]
+    fn test_parse_hlt() {
+        let tokens = vec![Token::Op { code: Opcode::HLT }];
+        parse_and_check(tokens, Some(&[14, 0, 0, 0]), 1);
+    }
+
+    #[test]
+    fn test_parse_exit() {
+        let tokens = vec![
+            Token::Op { code: Opcode::EXIT },
+            Token::IntegerOp { value: 3 },
+        ];
+        parse_and_check(tokens, Some(&[15, 0, 3, 0]), 1);
+
+        let tokens = vec![
+            Token::Op { code: Opcode::EXIT },
+            Token::IntegerOp { value: 255 },
+        ];
+        parse_and_check(tokens, Some(&[15, 0, 255, 0]), 1);
+    }
+
     #[test]
     fn test_parse_labels() {
         let tokens = vec![
@@ -415,6 +721,127 @@ mod tests {
         let mut parser = Parser::new(tokens);
         let result_parse = parser.parse();
         assert!(result_parse.is_ok());
-        todo!();
+        let insts = result_parse.unwrap();
+        assert_eq!(insts.len(), 3);
+        assert_eq!(insts[0].directive_type(), Some(DirectiveType::Data));
+        assert_eq!(insts[1].label_name(), Some("test"));
+        assert_eq!(insts[2].directive_type(), Some(DirectiveType::Asciiz));
+        assert_eq!(insts[2].string_literal(), Some("goodbye world!"));
+    }
+
+    #[test]
+    fn test_parse_rejects_wrong_operand_kind() {
+        let tokens = vec![
+            Token::Op { code: Opcode::ADD },
+            Token::IntegerOp { value: 1 },
+            Token::Register { reg_number: 2 },
+            Token::Register { reg_number: 3 },
+        ];
+        parse_and_expect_error(
+            tokens,
+            "`add` expects a register as operand 1, found integer `#1`",
+        );
+
+        let tokens = vec![
+            Token::Op { code: Opcode::LOAD },
+            Token::IntegerOp { value: 5 },
+            Token::IntegerOp { value: 6 },
+        ];
+        parse_and_expect_error(
+            tokens,
+            "`load` expects a register as operand 1, found integer `#5`",
+        );
+
+        let tokens = vec![
+            Token::Op { code: Opcode::JMP },
+            Token::Register { reg_number: 1 },
+        ];
+        parse_and_expect_error(
+            tokens,
+            "`jmp` expects a label as operand 1, found register `$1`",
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_out_of_range_operands() {
+        let tokens = vec![
+            Token::Op { code: Opcode::INC },
+            Token::Register { reg_number: 32 },
+        ];
+        parse_and_expect_error(
+            tokens,
+            "`inc` expects a register as operand 1, but register $32 is out of range (0-31)",
+        );
+
+        let tokens = vec![
+            Token::Op {
+                code: Opcode::LOADX,
+            },
+            Token::Register { reg_number: 0 },
+            Token::IntegerOp { value: 70000 },
+        ];
+        parse_and_expect_error(
+            tokens,
+            "`loadx` expects an integer as operand 2, but #70000 is out of range (0-65535)",
+        );
+
+        let tokens = vec![
+            Token::Op { code: Opcode::EXIT },
+            Token::IntegerOp { value: 256 },
+        ];
+        parse_and_expect_error(
+            tokens,
+            "`exit` expects an integer as operand 1, but #256 is out of range (0-255)",
+        );
+
+        let tokens = vec![
+            Token::Op {
+                code: Opcode::LOADS,
+            },
+            Token::Register { reg_number: 0 },
+            Token::IntegerOp { value: -40000 },
+        ];
+        parse_and_expect_error(
+            tokens,
+            "`loads` expects an integer as operand 2, but #-40000 is out of range (-32768-32767)",
+        );
+    }
+
+    #[test]
+    fn test_parse_wide_load() {
+        let tokens = vec![
+            Token::Op { code: Opcode::LOAD },
+            Token::Register { reg_number: 2 },
+            Token::IntegerOp { value: -100000 },
+        ];
+        parse_and_check(tokens, Some(&[16, 2, 0xFF, 0xFE, 17, 2, 0x79, 0x60]), 1);
+    }
+
+    #[test]
+    fn test_load_sequence() {
+        assert_eq!(load_sequence(65535), vec![(Opcode::LOAD, 65535)]);
+        assert_eq!(load_sequence(-1), vec![(Opcode::LOADS, 0xFFFF)]);
+        assert_eq!(
+            load_sequence(0x1234_5678),
+            vec![(Opcode::LOAD, 0x1234), (Opcode::LOADX, 0x5678)]
+        );
+        assert_eq!(
+            load_sequence(-32769),
+            vec![(Opcode::LOADS, 0xFFFF), (Opcode::LOADX, 0x7FFF)]
+        );
+        assert_eq!(load_sequence(i64::MIN).len(), 4);
+        assert_eq!(load_sequence(i64::MAX).len(), 4);
+    }
+
+    #[test]
+    fn test_parse_missing_operand() {
+        let tokens = vec![
+            Token::Op { code: Opcode::EQ },
+            Token::Register { reg_number: 1 },
+        ];
+        parse_and_expect_error(
+            tokens,
+            "`eq` expects a register as operand 2, found end of input",
+        );
     }
 }
@@ -1,2 +1,4 @@
+// The `Symbol` type, named like the module it is in.
+#[allow(clippy::module_inception)]
 pub mod symbol;
 pub mod symbol_table;
@@ -1,13 +1,14 @@
-#[derive(Debug)]
-#[allow(dead_code)]
+#[derive(Debug, Clone)]
 pub struct Symbol {
     pub name: String,
-    pub offset: u8,
-    symbol_type: SymbolType,
+    // Where the label is in its section. Only checked against what an
+    // instruction can address where the label is used.
+    pub offset: u32,
+    pub symbol_type: SymbolType,
 }
 
 impl Symbol {
-    pub fn new(name: String, offset: u8, symbol_type: SymbolType) -> Symbol {
+    pub fn new(name: String, offset: u32, symbol_type: SymbolType) -> Symbol {
         Symbol {
             name,
             offset,
@@ -16,7 +17,19 @@ impl Symbol {
     }
 }
 
-#[derive(Debug)]
+// The section a label was declared in. Jumps and `spawn` take code labels,
+// `lda` data labels.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SymbolType {
-    Label,
+    Code,
+    Data,
+}
+
+impl SymbolType {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            SymbolType::Code => "a code label",
+            SymbolType::Data => "a data label",
+        }
+    }
 }
@@ -1,10 +1,16 @@
 use super::symbol::Symbol;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SymbolTable {
     symbols: Vec<Symbol>,
 }
 
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SymbolTable {
     pub fn new() -> SymbolTable {
         SymbolTable {
@@ -16,12 +22,75 @@ impl SymbolTable {
         self.symbols.push(s);
     }
 
-    pub fn get_symbol_value(&self, s: &str) -> Option<u8> {
-        for symbol in &self.symbols {
-            if symbol.name == s {
-                return Some(symbol.offset);
+    pub fn get_symbol(&self, s: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == s)
+    }
+
+    pub fn get_symbol_value(&self, s: &str) -> Option<u32> {
+        self.get_symbol(s).map(|symbol| symbol.offset)
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.symbols
+            .iter()
+            .map(|symbol| symbol.name.clone())
+            .collect()
+    }
+
+    pub fn has_symbol(&self, s: &str) -> bool {
+        self.get_symbol_value(s).is_some()
+    }
+
+    // Suggests the closest declared symbol for a misspelled name, as long as it is
+    // within a third of the name's length in edits.
+    pub fn closest_match(&self, s: &str) -> Option<&str> {
+        let max_distance = std::cmp::max(1, s.chars().count() / 3);
+        self.symbols
+            .iter()
+            .map(|symbol| (edit_distance(&symbol.name, s), symbol.name.as_str()))
+            .filter(|(distance, _)| *distance <= max_distance)
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, name)| name)
+    }
+}
+
+// Optimal string alignment distance, so a swapped pair of letters counts as one edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
             }
         }
-        None
+    }
+    d[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::symbol::symbol::SymbolType;
+
+    #[test]
+    fn test_closest_match() {
+        let mut st = SymbolTable::new();
+        st.add_symbol(Symbol::new("loop".to_string(), 0, SymbolType::Code));
+        st.add_symbol(Symbol::new("end".to_string(), 8, SymbolType::Code));
+        assert_eq!(st.closest_match("lopo"), Some("loop"));
+        assert_eq!(st.closest_match("ned"), Some("end"));
+        assert_eq!(st.closest_match("something_else"), None);
     }
 }
@@ -0,0 +1,242 @@
+use crate::instruction::{Encoding, Opcode, OperandKind, Width, INSTRUCTION_LENGTH};
+use crate::reader::Reader;
+use crate::source_map::SourceMap;
+
+// On-disk format for assembled programs: a magic number, a format version, a
+// flags byte and the program length, so optional sections can follow the
+// program.
+//
+//   magic "RPD\0" | version u8 | flags u8 | program length u32 | program
+//   | read-only length u32 | read-only data (if FLAG_READ_ONLY)
+//   | source map (if FLAG_SOURCE_MAP)
+//
+// FLAG_WIDE_REGISTERS marks a program assembled for 64-bit registers and
+// FLAG_COMPACT one in the compact instruction encoding. FLAG_READ_ONLY is set
+// when the program declared any `.data`.
+pub const MAGIC: &[u8; 4] = b"RPD\0";
+pub const VERSION: u8 = 2;
+pub const HEADER_LENGTH: usize = 10;
+
+pub const FLAG_SOURCE_MAP: u8 = 0b0000_0001;
+pub const FLAG_WIDE_REGISTERS: u8 = 0b0000_0010;
+pub const FLAG_COMPACT: u8 = 0b0000_0100;
+pub const FLAG_READ_ONLY: u8 = 0b0000_1000;
+const KNOWN_FLAGS: u8 = FLAG_SOURCE_MAP | FLAG_WIDE_REGISTERS | FLAG_COMPACT | FLAG_READ_ONLY;
+
+#[derive(Debug, PartialEq)]
+pub struct Bytecode {
+    pub program: Vec<u8>,
+    pub read_only: Vec<u8>,
+    pub source_map: Option<SourceMap>,
+    pub width: Width,
+    pub encoding: Encoding,
+}
+
+impl Bytecode {
+    pub fn new(program: Vec<u8>) -> Bytecode {
+        Bytecode {
+            program,
+            read_only: Vec::new(),
+            source_map: None,
+            width: Width::W32,
+            encoding: Encoding::Fixed,
+        }
+    }
+}
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn encode(bytecode: &Bytecode) -> Vec<u8> {
+    let program = &bytecode.program;
+    let mut flags = 0;
+    if bytecode.source_map.is_some() {
+        flags |= FLAG_SOURCE_MAP;
+    }
+    if bytecode.width == Width::W64 {
+        flags |= FLAG_WIDE_REGISTERS;
+    }
+    if bytecode.encoding == Encoding::Compact {
+        flags |= FLAG_COMPACT;
+    }
+    if !bytecode.read_only.is_empty() {
+        flags |= FLAG_READ_ONLY;
+    }
+    let mut bytes = Vec::with_capacity(HEADER_LENGTH + program.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.push(VERSION);
+    bytes.push(flags);
+    bytes.extend_from_slice(&(program.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(program);
+    if !bytecode.read_only.is_empty() {
+        bytes.extend_from_slice(&(bytecode.read_only.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&bytecode.read_only);
+    }
+    if let Some(source_map) = &bytecode.source_map {
+        bytes.extend_from_slice(&source_map.encode());
+    }
+    bytes
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Bytecode, String> {
+    if !is_bytecode(bytes) {
+        return Err("not an rpd bytecode file".to_string());
+    }
+    let mut reader = Reader::new(&bytes[MAGIC.len()..], "bytecode");
+    let version = reader.read_u8()?;
+    if version != VERSION {
+        return Err(format!("unsupported bytecode version {}", version));
+    }
+    let flags = reader.read_u8()?;
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(format!("unsupported bytecode flags {:#010b}", flags));
+    }
+    let program_len = reader.read_u32()? as usize;
+    let program = reader.take(program_len)?.to_vec();
+    let read_only = if flags & FLAG_READ_ONLY != 0 {
+        let read_only_len = reader.read_u32()? as usize;
+        reader.take(read_only_len)?.to_vec()
+    } else {
+        Vec::new()
+    };
+    let source_map = if flags & FLAG_SOURCE_MAP != 0 {
+        Some(SourceMap::decode(reader.rest())?)
+    } else {
+        None
+    };
+    if !reader.is_at_end() {
+        return Err("trailing bytes after program".to_string());
+    }
+    let width = if flags & FLAG_WIDE_REGISTERS != 0 {
+        Width::W64
+    } else {
+        Width::W32
+    };
+    let encoding = if flags & FLAG_COMPACT != 0 {
+        Encoding::Compact
+    } else {
+        Encoding::Fixed
+    };
+    Ok(Bytecode {
+        program,
+        read_only,
+        source_map,
+        width,
+        encoding,
+    })
+}
+
+// Moves a program in the fixed encoding that was assembled to start at offset 0
+// so that it can be appended `base` bytes into another program, by shifting
+// every jump target. Data addresses are shifted by `read_only_base` the same
+// way, for read-only data appended to other read-only data.
+pub fn relocate(program: &mut [u8], base: usize, read_only_base: usize) -> Result<(), String> {
+    for start in (0..program.len()).step_by(INSTRUCTION_LENGTH) {
+        let mut position = start + 1;
+        let opcode = Opcode::from(program[start]);
+        if opcode == Opcode::LDA && position + 3 <= program.len() {
+            let address = u16::from_be_bytes([program[position + 1], program[position + 2]]);
+            let address = address as usize + read_only_base;
+            if address > u16::MAX as usize {
+                return Err(format!("data address {} is out of range", address));
+            }
+            program[position + 1..position + 3].copy_from_slice(&(address as u16).to_be_bytes());
+        }
+        for kind in opcode.operands() {
+            if *kind == OperandKind::Label && position + 2 <= program.len() {
+                let target = u16::from_be_bytes([program[position], program[position + 1]]);
+                let target = target as usize + base;
+                if target > u16::MAX as usize {
+                    return Err(format!(
+                        "jump target {} is out of addressable range",
+                        target
+                    ));
+                }
+                program[position..position + 2].copy_from_slice(&(target as u16).to_be_bytes());
+            }
+            position += kind.byte_len();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_round_trip() {
+        let program = vec![10, 0, 0, 0, 14, 0, 0, 0];
+        let bytecode = Bytecode::new(program);
+        assert_eq!(decode(&encode(&bytecode)), Ok(bytecode));
+    }
+
+    #[test]
+    fn test_wide_registers_flag() {
+        let mut bytecode = Bytecode::new(vec![14, 0, 0, 0]);
+        bytecode.width = Width::W64;
+        let bytes = encode(&bytecode);
+        assert_eq!(bytes[5], FLAG_WIDE_REGISTERS);
+        assert_eq!(decode(&bytes), Ok(bytecode));
+    }
+
+    #[test]
+    fn test_compact_flag() {
+        let mut bytecode = Bytecode::new(vec![10, 0, 14]);
+        bytecode.encoding = Encoding::Compact;
+        let bytes = encode(&bytecode);
+        assert_eq!(bytes[5], FLAG_COMPACT);
+        assert_eq!(decode(&bytes), Ok(bytecode));
+    }
+
+    #[test]
+    fn test_read_only_data() {
+        let mut assembler =
+            Assembler::new(".data\nmsg: .asciiz \"hi\"\n.code\nlda $0 @msg\n".to_string());
+        let mut bytecode = Bytecode::new(assembler.assemble().unwrap());
+        bytecode.read_only = assembler.read_only_secion.clone();
+        let bytes = encode(&bytecode);
+        assert_eq!(bytes[5], FLAG_READ_ONLY);
+        assert_eq!(decode(&bytes), Ok(bytecode));
+    }
+
+    #[test]
+    fn test_embedded_source_map() {
+        let mut assembler = Assembler::new("loop: inc $0\njmp @loop\n".to_string());
+        let program = assembler.assemble().unwrap();
+        let source_map = SourceMap::new("loop.rpd", assembler.listing().clone());
+        let decoded = decode(&encode(&Bytecode {
+            program: program.clone(),
+            read_only: Vec::new(),
+            source_map: Some(source_map.clone()),
+            width: Width::W32,
+            encoding: Encoding::Fixed,
+        }))
+        .unwrap();
+        assert_eq!(decoded.program, program);
+        assert_eq!(decoded.source_map, Some(source_map));
+    }
+
+    #[test]
+    fn test_relocate() {
+        let mut program = vec![10, 0, 0, 0, 6, 0, 0, 0];
+        assert!(relocate(&mut program, 8, 0).is_ok());
+        assert_eq!(program, vec![10, 0, 0, 0, 6, 0, 8, 0]);
+        assert!(relocate(&mut program, 300, 0).is_ok());
+        assert_eq!(program, vec![10, 0, 0, 0, 6, 1, 52, 0]);
+        assert!(relocate(&mut program, 65_300, 0).is_err());
+
+        let mut program = vec![27, 1, 0, 3];
+        assert!(relocate(&mut program, 8, 5).is_ok());
+        assert_eq!(program, vec![27, 1, 0, 8]);
+    }
+
+    #[test]
+    fn test_rejects_other_files() {
+        assert!(decode(b"load $0 #1").is_err());
+        assert!(decode(&[b'R', b'P', b'D', 0, 99]).is_err());
+        assert!(decode(&[b'R', b'P', b'D', 0, 1, 0, 0, 0, 0, 8, 10]).is_err());
+    }
+}
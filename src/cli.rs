@@ -0,0 +1,443 @@
+use std::fs::File;
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use crate::instruction::Opcode;
+use crate::trace::{TraceFilter, TraceFormat, Tracer};
+
+pub const USAGE: &str =
+    "usage: rpd [run <file> [options] | build <file> [options] | batch <dir> [options]]
+
+run options:
+    --optimize                   run the program through the peephole optimizer
+    --wide                       run with 64-bit registers (bytecode says so itself)
+    --compact                    assemble with the compact encoding (bytecode says so itself)
+    --heap-limit BYTES           fault when the heap would grow past BYTES
+    --heap-debug                 fault on double frees and uses of freed blocks
+    --quantum N                  switch processes every N instructions (default on yield)
+    --trace                      trace every executed instruction to stderr
+    --trace-format text|json     trace output format (default text)
+    --trace-pc START..END        only trace instructions in this address range
+    --trace-op MNEMONIC,...      only trace these instructions
+    --trace-file FILE            write the trace to FILE instead of stderr
+    --profile                    print an execution profile to stderr after the run
+    --profile-collapsed FILE     write the profile as folded stacks for flamegraphs
+    --coverage-lcov FILE         write line coverage of the source as LCOV to FILE
+    --coverage-annotate FILE     write the source annotated with hit counts to FILE
+
+build options:
+    -o FILE                      where to write the bytecode (default <file>.rpdc)
+    --source-map                 embed a source map in the bytecode
+    --optimize                   run the program through the peephole optimizer
+    --wide                       assemble for 64-bit registers
+    --compact                    use the compact variable-length instruction encoding
+
+batch options (runs every .rpdc file in <dir> in parallel):
+    --jobs N                     worker threads (default one per core)
+    --fuel N                     stop every program after N instructions
+    --quantum N                  switch processes every N instructions (default on yield)";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct TraceOptions {
+    pub format: Option<TraceFormat>,
+    pub filter: TraceFilter,
+    pub file: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RunOptions {
+    pub file: String,
+    pub optimize: bool,
+    pub wide: bool,
+    pub compact: bool,
+    pub heap_limit: Option<usize>,
+    pub heap_debug: bool,
+    // Instructions per process turn, `None` to only switch on `yield`.
+    pub quantum: Option<u64>,
+    // `None` when tracing is off.
+    pub trace: Option<TraceOptions>,
+    pub profile: bool,
+    pub profile_collapsed: Option<String>,
+    pub coverage_lcov: Option<String>,
+    pub coverage_annotate: Option<String>,
+}
+
+impl RunOptions {
+    pub fn wants_profile(&self) -> bool {
+        self.profile || self.profile_collapsed.is_some()
+    }
+
+    pub fn wants_coverage(&self) -> bool {
+        self.coverage_lcov.is_some() || self.coverage_annotate.is_some()
+    }
+
+    pub fn tracer(&self) -> Result<Option<Tracer>, String> {
+        let trace = match &self.trace {
+            Some(trace) => trace,
+            None => return Ok(None),
+        };
+        let sink: Box<dyn Write + Send> = match &trace.file {
+            Some(file) => Box::new(
+                File::create(file).map_err(|e| format!("could not create {}: {}", file, e))?,
+            ),
+            None => Box::new(std::io::stderr()),
+        };
+        let mut tracer = Tracer::new(trace.format.unwrap_or(TraceFormat::Text), sink);
+        tracer.filter = trace.filter.clone();
+        Ok(Some(tracer))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BuildOptions {
+    pub file: String,
+    pub output: String,
+    pub source_map: bool,
+    pub optimize: bool,
+    pub wide: bool,
+    pub compact: bool,
+}
+
+// Parses everything after `build`.
+pub fn parse_build_options(args: &[String]) -> Result<BuildOptions, String> {
+    let mut file: Option<String> = None;
+    let mut output: Option<String> = None;
+    let mut source_map = false;
+    let mut optimize = false;
+    let mut wide = false;
+    let mut compact = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(args.next().ok_or("-o expects a value")?.clone()),
+            "--source-map" => source_map = true,
+            "--optimize" => optimize = true,
+            "--wide" => wide = true,
+            "--compact" => compact = true,
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if file.is_some() => return Err(format!("unexpected argument {}", arg)),
+            _ => file = Some(arg.clone()),
+        }
+    }
+    let file = file.ok_or("missing file to build")?;
+    let output = output.unwrap_or_else(|| {
+        Path::new(&file)
+            .with_extension("rpdc")
+            .to_string_lossy()
+            .into_owned()
+    });
+    Ok(BuildOptions {
+        file,
+        output,
+        source_map,
+        optimize,
+        wide,
+        compact,
+    })
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BatchOptions {
+    pub dir: String,
+    // `None` for one worker per core.
+    pub jobs: Option<usize>,
+    pub fuel: Option<u64>,
+    pub quantum: Option<u64>,
+}
+
+// Parses everything after `batch`.
+pub fn parse_batch_options(args: &[String]) -> Result<BatchOptions, String> {
+    let mut dir: Option<String> = None;
+    let mut jobs: Option<usize> = None;
+    let mut fuel: Option<u64> = None;
+    let mut quantum: Option<u64> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            if dir.is_some() {
+                return Err(format!("unexpected argument {}", arg));
+            }
+            dir = Some(arg.clone());
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or(format!("{} expects a value", arg))?
+            .as_str();
+        match arg.as_str() {
+            "--jobs" => jobs = Some(parse_positive(value, "number of jobs")? as usize),
+            "--fuel" => fuel = Some(parse_positive(value, "fuel")?),
+            "--quantum" => quantum = Some(parse_positive(value, "quantum")?),
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    Ok(BatchOptions {
+        dir: dir.ok_or("missing directory to run")?,
+        jobs,
+        fuel,
+        quantum,
+    })
+}
+
+fn parse_positive(value: &str, what: &str) -> Result<u64, String> {
+    value
+        .parse::<u64>()
+        .ok()
+        .filter(|value| *value > 0)
+        .ok_or(format!("invalid {} {}", what, value))
+}
+
+// Parses everything after `run`.
+pub fn parse_run_options(args: &[String]) -> Result<RunOptions, String> {
+    let mut file: Option<String> = None;
+    let mut trace: Option<TraceOptions> = None;
+    let mut optimize = false;
+    let mut wide = false;
+    let mut compact = false;
+    let mut heap_limit: Option<usize> = None;
+    let mut heap_debug = false;
+    let mut quantum: Option<u64> = None;
+    let mut profile = false;
+    let mut profile_collapsed: Option<String> = None;
+    let mut coverage_lcov: Option<String> = None;
+    let mut coverage_annotate: Option<String> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            if file.is_some() {
+                return Err(format!("unexpected argument {}", arg));
+            }
+            file = Some(arg.clone());
+            continue;
+        }
+        match arg.as_str() {
+            "--trace" => {
+                trace.get_or_insert_with(TraceOptions::default);
+                continue;
+            }
+            "--profile" => {
+                profile = true;
+                continue;
+            }
+            "--optimize" => {
+                optimize = true;
+                continue;
+            }
+            "--wide" => {
+                wide = true;
+                continue;
+            }
+            "--compact" => {
+                compact = true;
+                continue;
+            }
+            "--heap-debug" => {
+                heap_debug = true;
+                continue;
+            }
+            _ => {}
+        }
+        let value = args
+            .next()
+            .ok_or(format!("{} expects a value", arg))?
+            .as_str();
+        match arg.as_str() {
+            "--trace-format" => {
+                trace.get_or_insert_with(TraceOptions::default).format =
+                    Some(parse_trace_format(value)?)
+            }
+            "--trace-pc" => {
+                trace
+                    .get_or_insert_with(TraceOptions::default)
+                    .filter
+                    .pc_range = Some(parse_pc_range(value)?)
+            }
+            "--trace-op" => {
+                trace
+                    .get_or_insert_with(TraceOptions::default)
+                    .filter
+                    .opcodes = parse_opcodes(value)?
+            }
+            "--trace-file" => {
+                trace.get_or_insert_with(TraceOptions::default).file = Some(value.to_string())
+            }
+            "--heap-limit" => {
+                heap_limit = Some(
+                    value
+                        .parse::<usize>()
+                        .map_err(|_| format!("invalid heap limit {}", value))?,
+                )
+            }
+            "--quantum" => quantum = Some(parse_positive(value, "quantum")?),
+            "--profile-collapsed" => profile_collapsed = Some(value.to_string()),
+            "--coverage-lcov" => coverage_lcov = Some(value.to_string()),
+            "--coverage-annotate" => coverage_annotate = Some(value.to_string()),
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    match file {
+        Some(file) => Ok(RunOptions {
+            file,
+            optimize,
+            wide,
+            compact,
+            heap_limit,
+            heap_debug,
+            quantum,
+            trace,
+            profile,
+            profile_collapsed,
+            coverage_lcov,
+            coverage_annotate,
+        }),
+        None => Err("missing file to run".to_string()),
+    }
+}
+
+fn parse_trace_format(value: &str) -> Result<TraceFormat, String> {
+    match value {
+        "text" => Ok(TraceFormat::Text),
+        "json" => Ok(TraceFormat::Json),
+        _ => Err(format!("unknown trace format {}", value)),
+    }
+}
+
+fn parse_pc_range(value: &str) -> Result<RangeInclusive<usize>, String> {
+    let invalid = || format!("invalid address range {}, expected START..END", value);
+    let (start, end) = value.split_once("..").ok_or_else(invalid)?;
+    let start = start.parse::<usize>().map_err(|_| invalid())?;
+    let end = end.parse::<usize>().map_err(|_| invalid())?;
+    Ok(start..=end)
+}
+
+fn parse_opcodes(value: &str) -> Result<Vec<Opcode>, String> {
+    value
+        .split(',')
+        .map(|mnemonic| {
+            Opcode::from_mnemonic(mnemonic).ok_or(format!("unknown instruction {}", mnemonic))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_plain_run() {
+        assert_eq!(
+            parse_run_options(&args("prog.rpd")),
+            Ok(RunOptions {
+                file: "prog.rpd".to_string(),
+                optimize: false,
+                wide: false,
+                compact: false,
+                heap_limit: None,
+                heap_debug: false,
+                quantum: None,
+                trace: None,
+                profile: false,
+                profile_collapsed: None,
+                coverage_lcov: None,
+                coverage_annotate: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_profile_options() {
+        let options = parse_run_options(&args("--profile-collapsed out.folded prog.rpd")).unwrap();
+        assert_eq!(options.file, "prog.rpd");
+        assert!(!options.profile);
+        assert_eq!(options.profile_collapsed, Some("out.folded".to_string()));
+        assert!(options.wants_profile());
+        assert!(options.trace.is_none());
+        assert!(!options.wants_coverage());
+    }
+
+    #[test]
+    fn test_parse_heap_options() {
+        let options = parse_run_options(&args("prog.rpd --heap-limit 4096 --heap-debug")).unwrap();
+        assert_eq!(options.heap_limit, Some(4096));
+        assert!(options.heap_debug);
+    }
+
+    #[test]
+    fn test_parse_quantum() {
+        let options = parse_run_options(&args("prog.rpd --quantum 100")).unwrap();
+        assert_eq!(options.quantum, Some(100));
+        assert!(parse_run_options(&args("prog.rpd --quantum 0")).is_err());
+        assert!(parse_run_options(&args("prog.rpd --quantum")).is_err());
+    }
+
+    #[test]
+    fn test_parse_batch_options() {
+        assert_eq!(
+            parse_batch_options(&args("--jobs 8 jobs/ --fuel 100000")),
+            Ok(BatchOptions {
+                dir: "jobs/".to_string(),
+                jobs: Some(8),
+                fuel: Some(100000),
+                quantum: None,
+            })
+        );
+        assert!(parse_batch_options(&args("")).is_err());
+        assert!(parse_batch_options(&args("jobs --jobs 0")).is_err());
+        assert!(parse_batch_options(&args("jobs --fuel")).is_err());
+        assert!(parse_batch_options(&args("jobs other")).is_err());
+    }
+
+    #[test]
+    fn test_parse_trace_options() {
+        let options = parse_run_options(&args(
+            "prog.rpd --trace-format json --trace-pc 4..12 --trace-op jmp,jeq",
+        ))
+        .unwrap();
+        let trace = options.trace.unwrap();
+        assert_eq!(trace.format, Some(TraceFormat::Json));
+        assert_eq!(trace.filter.pc_range, Some(4..=12));
+        assert_eq!(trace.filter.opcodes, vec![Opcode::JMP, Opcode::JEQ]);
+    }
+
+    #[test]
+    fn test_parse_build_options() {
+        assert_eq!(
+            parse_build_options(&args("src/loop.rpd --source-map")),
+            Ok(BuildOptions {
+                file: "src/loop.rpd".to_string(),
+                output: "src/loop.rpdc".to_string(),
+                source_map: true,
+                optimize: false,
+                wide: false,
+                compact: false,
+            })
+        );
+        assert!(parse_build_options(&args("loop.rpd --wide")).unwrap().wide);
+        assert!(
+            parse_build_options(&args("loop.rpd --compact"))
+                .unwrap()
+                .compact
+        );
+        assert_eq!(
+            parse_build_options(&args("-o out.rpdc loop.rpd"))
+                .unwrap()
+                .output,
+            "out.rpdc"
+        );
+        assert!(parse_build_options(&args("-o")).is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_run_options(&args("")).is_err());
+        assert!(parse_run_options(&args("prog.rpd --trace-op nope")).is_err());
+        assert!(parse_run_options(&args("prog.rpd --trace-pc 4")).is_err());
+        assert!(parse_run_options(&args("prog.rpd --bogus 1")).is_err());
+        assert!(parse_run_options(&args("prog.rpd --heap-limit lots")).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+
+use crate::assembler::Listing;
+
+// How often every instruction ran, collected by `VM::run` while
+// `VM::coverage` is set.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Coverage {
+    // Indexed by the offset the instruction starts at.
+    hits: Vec<u64>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    pub fn record(&mut self, pc: usize) {
+        if pc >= self.hits.len() {
+            self.hits.resize(pc + 1, 0);
+        }
+        self.hits[pc] += 1;
+    }
+
+    pub fn hits_at(&self, pc: usize) -> u64 {
+        self.hits.get(pc).copied().unwrap_or(0)
+    }
+
+    // Adds up the hits of several runs, e.g. one per test program input.
+    pub fn merge(&mut self, other: &Coverage) {
+        if other.hits.len() > self.hits.len() {
+            self.hits.resize(other.hits.len(), 0);
+        }
+        for (hits, other_hits) in self.hits.iter_mut().zip(other.hits.iter()) {
+            *hits += other_hits;
+        }
+    }
+
+    // Hits per source line, for every line that produced an instruction.
+    pub fn line_hits(&self, listing: &Listing) -> BTreeMap<usize, u64> {
+        let mut lines: BTreeMap<usize, u64> = BTreeMap::new();
+        for (offset, span) in &listing.instructions {
+            let hits = lines.entry(span.line).or_insert(0);
+            *hits = (*hits).max(self.hits_at(*offset));
+        }
+        lines
+    }
+
+    // The source with a gcov style hit count in front of every line: `-` for
+    // lines without code and `#####` for code that never ran.
+    pub fn annotate(&self, source: &str, listing: &Listing) -> String {
+        let lines = self.line_hits(listing);
+        let mut annotated = String::new();
+        for (index, text) in source.lines().enumerate() {
+            let hits = match lines.get(&(index + 1)) {
+                Some(0) => "#####".to_string(),
+                Some(hits) => hits.to_string(),
+                None => "-".to_string(),
+            };
+            let _ = writeln!(annotated, "{:>9}:{:>5}:{}", hits, index + 1, text);
+        }
+        annotated
+    }
+
+    // One LCOV record for `file`, the format genhtml and most CI tooling read.
+    pub fn to_lcov(&self, file: &str, listing: &Listing) -> String {
+        let lines = self.line_hits(listing);
+        let mut lcov = format!("TN:\nSF:{}\n", file);
+        for (line, hits) in &lines {
+            let _ = writeln!(lcov, "DA:{},{}", line, hits);
+        }
+        let _ = writeln!(lcov, "LF:{}", lines.len());
+        let _ = writeln!(
+            lcov,
+            "LH:{}",
+            lines.values().filter(|hits| **hits > 0).count()
+        );
+        lcov.push_str("end_of_record\n");
+        lcov
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::VM;
+
+    const SOURCE: &str = "load $0 #2\nloop:\ndec $0\nneq $0 $1\njeq @loop\nhlt\ninc $0";
+
+    fn coverage(source: &str) -> (Coverage, Listing) {
+        let mut assembler = Assembler::new(source.to_string());
+        let mut vm = VM::new_with_program(assembler.assemble().unwrap());
+        vm.coverage = Some(Coverage::new());
+        vm.run();
+        (vm.coverage.unwrap(), assembler.listing().clone())
+    }
+
+    #[test]
+    fn test_annotate() {
+        let (coverage, listing) = coverage(SOURCE);
+        assert_eq!(
+            coverage.annotate(SOURCE, &listing),
+            [
+                "        1:    1:load $0 #2",
+                "        -:    2:loop:",
+                "        2:    3:dec $0",
+                "        2:    4:neq $0 $1",
+                "        2:    5:jeq @loop",
+                "        1:    6:hlt",
+                "    #####:    7:inc $0",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_lcov() {
+        let (coverage, listing) = coverage(SOURCE);
+        assert_eq!(
+            coverage.to_lcov("loop.rpd", &listing),
+            "TN:\nSF:loop.rpd\nDA:1,1\nDA:3,2\nDA:4,2\nDA:5,2\nDA:6,1\nDA:7,0\nLF:6\nLH:5\nend_of_record\n"
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut first = Coverage::new();
+        first.record(0);
+        let mut second = Coverage::new();
+        second.record(0);
+        second.record(8);
+        first.merge(&second);
+        assert_eq!(first.hits_at(0), 2);
+        assert_eq!(first.hits_at(4), 0);
+        assert_eq!(first.hits_at(8), 1);
+    }
+}
@@ -0,0 +1,196 @@
+use crate::instruction::{Encoding, Opcode, OperandKind, REGISTER_COUNT};
+
+// An instruction with its operands already pulled out of the bytes. Jump
+// targets are instruction indices rather than byte offsets.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DecodedInstruction {
+    pub opcode: Opcode,
+    pub operands: [usize; 3],
+}
+
+impl DecodedInstruction {
+    pub fn new(opcode: Opcode, operands: [usize; 3]) -> DecodedInstruction {
+        DecodedInstruction { opcode, operands }
+    }
+
+    // Encodes the instruction back into its bytes, the inverse of `decode`.
+    // `offsets` holds the byte offset of every instruction, see `offsets`.
+    // `None` if a label lands past what 16 bits can address.
+    pub fn to_bytes(&self, encoding: Encoding, offsets: &[usize]) -> Option<Vec<u8>> {
+        let mut bytes = vec![u8::from(self.opcode)];
+        for (index, kind) in self.opcode.operands().iter().enumerate() {
+            let operand = self.operands[index];
+            match kind {
+                OperandKind::Register | OperandKind::Pair => bytes.push(operand as u8),
+                OperandKind::Immediate => bytes.extend_from_slice(&(operand as u16).to_be_bytes()),
+                OperandKind::Label => {
+                    let target = u16::try_from(offsets[operand]).ok()?;
+                    bytes.extend_from_slice(&target.to_be_bytes());
+                }
+            }
+        }
+        bytes.resize(encoding.length(self.opcode), 0);
+        Some(bytes)
+    }
+}
+
+// The offset every instruction starts at, followed by the length of the whole
+// program. `None` if an opcode is unknown or the last instruction is cut off.
+pub fn boundaries(program: &[u8], encoding: Encoding) -> Option<Vec<usize>> {
+    let mut offsets = vec![];
+    let mut position = 0;
+    while position < program.len() {
+        let opcode = Opcode::from(program[position]);
+        if opcode == Opcode::ILLEGAL {
+            return None;
+        }
+        offsets.push(position);
+        position += encoding.length(opcode);
+    }
+    if position != program.len() {
+        return None;
+    }
+    offsets.push(position);
+    Some(offsets)
+}
+
+// Where every instruction will start once encoded, followed by the end of the
+// program, the layout `to_bytes` expects.
+pub fn offsets(instructions: &[DecodedInstruction], encoding: Encoding) -> Vec<usize> {
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    let mut position = 0;
+    for instruction in instructions {
+        offsets.push(position);
+        position += encoding.length(instruction.opcode);
+    }
+    offsets.push(position);
+    offsets
+}
+
+// Encodes decoded instructions into a program. `None` if the program got too
+// long for its labels, e.g. a large compact program re-encoded as fixed.
+pub fn encode(instructions: &[DecodedInstruction], encoding: Encoding) -> Option<Vec<u8>> {
+    let offsets = offsets(instructions, encoding);
+    let mut program = Vec::with_capacity(offsets[instructions.len()]);
+    for instruction in instructions {
+        program.extend(instruction.to_bytes(encoding, &offsets)?);
+    }
+    Some(program)
+}
+
+// Decodes a whole program up front so the VM can dispatch without looking at
+// the bytes again. Only programs that are safe to run that way are decoded:
+// whole instructions, known opcodes, existing registers and jumps that land on
+// an instruction (or the end of the program). Anything else gives `None` and
+// is left to the byte-level interpreter.
+pub fn decode(program: &[u8], encoding: Encoding) -> Option<Vec<DecodedInstruction>> {
+    let offsets = boundaries(program, encoding)?;
+    let mut decoded = Vec::with_capacity(offsets.len() - 1);
+    for start in &offsets[..offsets.len() - 1] {
+        let opcode = Opcode::from(program[*start]);
+        let mut operands = [0; 3];
+        let mut position = start + 1;
+        for (index, kind) in opcode.operands().iter().enumerate() {
+            operands[index] = match kind {
+                OperandKind::Register | OperandKind::Pair => {
+                    let register = program[position] as usize;
+                    if register + kind.register_count() > REGISTER_COUNT {
+                        return None;
+                    }
+                    register
+                }
+                OperandKind::Immediate => read_u16(program, position),
+                OperandKind::Label => offsets.binary_search(&read_u16(program, position)).ok()?,
+            };
+            position += kind.byte_len();
+        }
+        decoded.push(DecodedInstruction { opcode, operands });
+    }
+    Some(decoded)
+}
+
+// A big-endian 16-bit operand, like immediates and labels.
+fn read_u16(program: &[u8], position: usize) -> usize {
+    ((program[position] as usize) << 8) | program[position + 1] as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let decoded = decode(&[1, 3, 1, 0, 6, 0, 8, 0, 14, 0, 0, 0], Encoding::Fixed).unwrap();
+        assert_eq!(
+            decoded,
+            vec![
+                DecodedInstruction {
+                    opcode: Opcode::LOAD,
+                    operands: [3, 256, 0]
+                },
+                DecodedInstruction {
+                    opcode: Opcode::JMP,
+                    operands: [2, 0, 0]
+                },
+                DecodedInstruction {
+                    opcode: Opcode::HLT,
+                    operands: [0, 0, 0]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_bytes_round_trip() {
+        let program = vec![1, 3, 1, 0, 6, 0, 8, 0, 2, 1, 2, 3, 14, 0, 0, 0];
+        let decoded = decode(&program, Encoding::Fixed).unwrap();
+        assert_eq!(encode(&decoded, Encoding::Fixed), Some(program));
+    }
+
+    #[test]
+    fn test_compact_encoding() {
+        // load $3 #256; jmp @end; add $1 $2 $3; end: hlt
+        let compact = vec![1, 3, 1, 0, 6, 0, 11, 2, 1, 2, 3, 14];
+        assert_eq!(
+            boundaries(&compact, Encoding::Compact),
+            Some(vec![0, 4, 7, 11, 12])
+        );
+        let decoded = decode(&compact, Encoding::Compact).unwrap();
+        assert_eq!(decoded[1].operands[0], 3);
+        assert_eq!(encode(&decoded, Encoding::Compact), Some(compact.clone()));
+        assert_eq!(
+            encode(&decoded, Encoding::Fixed),
+            Some(vec![1, 3, 1, 0, 6, 0, 12, 0, 2, 1, 2, 3, 14, 0, 0, 0])
+        );
+        assert_eq!(decode(&compact[..10], Encoding::Compact), None);
+    }
+
+    #[test]
+    fn test_far_labels() {
+        // A jump over 300 `inc`s, past what a byte addresses.
+        let mut compact = vec![6, 1, 47];
+        compact.extend([10, 0].repeat(150));
+        compact.push(14);
+        let decoded = decode(&compact, Encoding::Compact).unwrap();
+        assert_eq!(decoded[0].operands[0], 151);
+        let fixed = encode(&decoded, Encoding::Fixed).unwrap();
+        assert_eq!(&fixed[0..4], &[6, 2, 92, 0]);
+
+        // Re-encoded as fixed the end lands past 16 bits.
+        let mut compact = vec![6, 0xAA, 0xAD];
+        compact.extend([10, 0].repeat(21_845));
+        compact.push(14);
+        let decoded = decode(&compact, Encoding::Compact).unwrap();
+        assert_eq!(encode(&decoded, Encoding::Fixed), None);
+    }
+
+    #[test]
+    fn test_refuses_unsafe_programs() {
+        assert_eq!(decode(&[1, 10, 1, 1, 0], Encoding::Fixed), None);
+        assert_eq!(decode(&[200, 0, 0, 0], Encoding::Fixed), None);
+        assert_eq!(decode(&[10, 32, 0, 0], Encoding::Fixed), None);
+        assert_eq!(decode(&[6, 0, 2, 0], Encoding::Fixed), None);
+        assert_eq!(decode(&[6, 0, 8, 0], Encoding::Fixed), None);
+        assert!(decode(&[6, 0, 4, 0], Encoding::Fixed).is_some());
+    }
+}
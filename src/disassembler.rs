@@ -0,0 +1,108 @@
+use crate::instruction::{Encoding, Opcode, OperandKind};
+
+// Renders a single encoded instruction back into assembly, using the same
+// operand signatures the parser checks against.
+pub fn disassemble_instruction(bytes: &[u8]) -> String {
+    let opcode = match bytes.first() {
+        Some(byte) => Opcode::from(*byte),
+        None => return String::new(),
+    };
+    if opcode == Opcode::ILLEGAL {
+        return format!("illegal 0x{:02x}", bytes[0]);
+    }
+    let mut text = opcode.mnemonic().to_string();
+    let mut position = 1;
+    for kind in opcode.operands() {
+        let end = position + kind.byte_len();
+        if end > bytes.len() {
+            text.push_str(" <truncated>");
+            break;
+        }
+        let operand = match kind {
+            OperandKind::Register | OperandKind::Pair => format!("${}", bytes[position]),
+            OperandKind::Immediate => {
+                let value = ((bytes[position] as u16) << 8) | bytes[position + 1] as u16;
+                match opcode {
+                    Opcode::LOADS => format!("#{}", value as i16),
+                    _ => format!("#{}", value),
+                }
+            }
+            OperandKind::Label => {
+                let target = ((bytes[position] as u16) << 8) | bytes[position + 1] as u16;
+                format!("@{}", target)
+            }
+        };
+        text.push(' ');
+        text.push_str(&operand);
+        position = end;
+    }
+    text
+}
+
+pub fn disassemble(program: &[u8], encoding: Encoding) -> Vec<String> {
+    disassemble_from(program, 0, encoding)
+}
+
+// Like `disassemble`, for a slice that starts `offset` bytes into a program.
+pub fn disassemble_from(program: &[u8], offset: usize, encoding: Encoding) -> Vec<String> {
+    let mut lines = vec![];
+    let mut position = 0;
+    while position < program.len() {
+        let end = (position + encoding.length(Opcode::from(program[position]))).min(program.len());
+        lines.push(format!(
+            "{:04}: {}",
+            offset + position,
+            disassemble_instruction(&program[position..end])
+        ));
+        position = end;
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble_program() {
+        let program = vec![1, 1, 1, 0, 2, 3, 1, 2, 6, 0, 0, 0, 10, 4, 0, 0];
+        assert_eq!(
+            disassemble(&program, Encoding::Fixed),
+            vec![
+                "0000: load $1 #256",
+                "0004: add $3 $1 $2",
+                "0008: jmp @0",
+                "0012: inc $4",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble_compact() {
+        let program = vec![1, 1, 1, 0, 10, 4, 6, 1, 4, 14];
+        assert_eq!(
+            disassemble_from(&program, 8, Encoding::Compact),
+            vec![
+                "0008: load $1 #256",
+                "0012: inc $4",
+                "0014: jmp @260",
+                "0017: hlt"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble_illegal_and_truncated() {
+        assert_eq!(disassemble_instruction(&[200, 0, 0, 0]), "illegal 0xc8");
+        assert_eq!(disassemble_instruction(&[2, 1]), "add $1 <truncated>");
+    }
+
+    #[test]
+    fn test_disassemble_signed_immediate() {
+        assert_eq!(disassemble_instruction(&[16, 0, 255, 254]), "loads $0 #-2");
+        assert_eq!(
+            disassemble_instruction(&[17, 0, 255, 254]),
+            "loadx $0 #65534"
+        );
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::bytecode::Bytecode;
+use crate::instruction::REGISTER_COUNT;
+use crate::scheduler::{exit_status, Scheduler};
+use crate::vm::{RunStatus, VM};
+
+// A program to run on its own, named after where it came from.
+#[derive(Debug)]
+pub struct Job {
+    pub name: String,
+    pub vm: VM,
+}
+
+impl Job {
+    pub fn new(name: &str, vm: VM) -> Job {
+        Job {
+            name: name.to_string(),
+            vm,
+        }
+    }
+
+    pub fn from_bytecode(name: &str, bytecode: Bytecode) -> Job {
+        let mut vm = VM::new_with_program(bytecode.program);
+        vm.read_only = bytecode.read_only;
+        vm.source_map = bytecode.source_map;
+        vm.width = bytecode.width;
+        vm.encoding = bytecode.encoding;
+        Job::new(name, vm)
+    }
+}
+
+// How a job went. `error` is set when the job didn't finish normally, like
+// every ERROR line `rpd run` would print.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobResult {
+    pub name: String,
+    pub exit_code: i32,
+    pub registers: [i64; REGISTER_COUNT],
+    pub error: Option<String>,
+    // Instructions run by all processes of the job.
+    pub instructions: u64,
+    pub duration: Duration,
+}
+
+impl JobResult {
+    // A job that couldn't even be started, e.g. because its file didn't decode.
+    pub fn failed(name: &str, error: String) -> JobResult {
+        JobResult {
+            name: name.to_string(),
+            exit_code: 1,
+            registers: [0; REGISTER_COUNT],
+            error: Some(error),
+            instructions: 0,
+            duration: Duration::ZERO,
+        }
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none() && self.exit_code == 0
+    }
+}
+
+impl Display for JobResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(error) = &self.error {
+            return write!(f, "{}: ERROR: {}", self.name, error);
+        }
+        write!(
+            f,
+            "{}: exit {} after {} instructions in {:.2?}",
+            self.name, self.exit_code, self.instructions, self.duration
+        )?;
+        // Only the registers a program left something in.
+        for (register, value) in self.registers.iter().enumerate() {
+            if *value != 0 {
+                write!(f, " ${}={}", register, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The results of a batch in the order its jobs were given.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub results: Vec<JobResult>,
+    pub elapsed: Duration,
+}
+
+impl Summary {
+    pub fn failed(&self) -> usize {
+        self.results.iter().filter(|r| !r.succeeded()).count()
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for result in &self.results {
+            writeln!(f, "{}", result)?;
+        }
+        write!(
+            f,
+            "{} jobs, {} succeeded, {} failed in {:.2?}",
+            self.results.len(),
+            self.results.len() - self.failed(),
+            self.failed(),
+            self.elapsed
+        )
+    }
+}
+
+// Runs independent jobs on a pool of OS threads. Jobs share nothing, every
+// one is a VM of its own moved to whichever worker picks it up, and runs
+// through a `Scheduler` so programs that spawn processes work too.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Executor {
+    pub workers: usize,
+    // Instructions every job may run, over all of its processes.
+    pub fuel: Option<u64>,
+    // See `Scheduler::quantum`.
+    pub quantum: Option<u64>,
+}
+
+impl Executor {
+    pub fn new(workers: usize) -> Executor {
+        Executor {
+            workers: workers.max(1),
+            fuel: None,
+            quantum: None,
+        }
+    }
+
+    // One worker per core the host offers.
+    pub fn with_available_parallelism() -> Executor {
+        Executor::new(thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+
+    pub fn run(&self, jobs: Vec<Job>) -> Summary {
+        let start = Instant::now();
+        let count = jobs.len();
+        let queue = Mutex::new(jobs.into_iter().enumerate().collect::<VecDeque<_>>());
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            for _ in 0..self.workers.min(count) {
+                let sender = sender.clone();
+                let queue = &queue;
+                scope.spawn(move || loop {
+                    // The lock is only held to take the next job.
+                    let next = queue.lock().unwrap().pop_front();
+                    let (index, job) = match next {
+                        Some(next) => next,
+                        None => break,
+                    };
+                    // A bug in the VM showing up in one job must not take the
+                    // worker and every job after it down.
+                    let name = job.name.clone();
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| self.run_job(job)))
+                        .unwrap_or_else(|payload| {
+                            let message = payload
+                                .downcast_ref::<&str>()
+                                .map(|message| message.to_string())
+                                .or_else(|| payload.downcast_ref::<String>().cloned())
+                                .unwrap_or_default();
+                            JobResult::failed(&name, format!("the VM panicked: {}", message))
+                        });
+                    if sender.send((index, result)).is_err() {
+                        break;
+                    }
+                });
+            }
+        });
+        drop(sender);
+        let mut results: Vec<(usize, JobResult)> = receiver.into_iter().collect();
+        results.sort_by_key(|(index, _)| *index);
+        Summary {
+            results: results.into_iter().map(|(_, result)| result).collect(),
+            elapsed: start.elapsed(),
+        }
+    }
+
+    fn run_job(&self, job: Job) -> JobResult {
+        let start = Instant::now();
+        if let Err(errors) = job.vm.verify() {
+            let errors: Vec<String> = errors
+                .iter()
+                .map(|err| format!("{} at offset {}", err.message(), err.offset()))
+                .collect();
+            return JobResult::failed(&job.name, errors.join("; "));
+        }
+        let mut scheduler = Scheduler::new(job.vm);
+        scheduler.fuel = self.fuel;
+        scheduler.quantum = self.quantum;
+        let status = scheduler.run();
+        let instructions = scheduler.instructions;
+        let vm = scheduler.into_main();
+        let pc = vm.program_counter;
+        let at = match vm.location(pc) {
+            Some(location) => format!("at pc {} ({})", pc, location),
+            None => format!("at pc {}", pc),
+        };
+        let error = match status {
+            RunStatus::Fault(fault) => Some(format!("{} {}", fault, at)),
+            RunStatus::Deadlock => Some(format!("every process is waiting, deadlock {}", at)),
+            RunStatus::InstructionLimit => Some(format!("out of fuel {}", at)),
+            RunStatus::Timeout => Some(format!("out of time {}", at)),
+            RunStatus::Interrupted => Some(format!("interrupted {}", at)),
+            _ => None,
+        };
+        JobResult {
+            name: job.name,
+            exit_code: match error {
+                Some(_) => 1,
+                None => exit_status(status) as i32,
+            },
+            registers: vm.registers,
+            error,
+            instructions,
+            duration: start.elapsed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn job(name: &str, source: &str) -> Job {
+        let program = Assembler::new(source.to_string()).assemble().unwrap();
+        Job::new(name, VM::new_with_program(program))
+    }
+
+    #[test]
+    fn test_runs_jobs_in_order() {
+        let jobs = (0..20)
+            .map(|n| {
+                job(
+                    &format!("job{}", n),
+                    &format!("load $0 #{}\ninc $0\nexit #{}\n", n, n % 3),
+                )
+            })
+            .collect();
+        let summary = Executor::new(4).run(jobs);
+        assert_eq!(summary.results.len(), 20);
+        for (n, result) in summary.results.iter().enumerate() {
+            assert_eq!(result.name, format!("job{}", n));
+            assert_eq!(result.registers[0], n as i64 + 1);
+            assert_eq!(result.exit_code, n as i32 % 3);
+            assert_eq!(result.instructions, 2);
+        }
+        assert_eq!(summary.failed(), 13);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut executor = Executor::new(2);
+        executor.fuel = Some(1000);
+        let summary = executor.run(vec![
+            job("loop", "loop:\njmp @loop\n"),
+            job("fault", "load $0 #4\nfree $0\n"),
+            job("deadlock", "chan $0\nrecv $0 $0\n"),
+            Job::new("invalid", VM::new_with_program(vec![1, 40, 0, 0])),
+            job("ok", "load $3 #7\n"),
+            job("zero", "load $0 #5\nload $1 #0\ndiv $2 $0 $1\n"),
+        ]);
+        let errors: Vec<Option<&str>> = summary
+            .results
+            .iter()
+            .map(|result| result.error.as_deref())
+            .collect();
+        assert_eq!(errors[0], Some("out of fuel at pc 0"));
+        assert!(errors[1].unwrap().ends_with("at pc 4"));
+        assert_eq!(
+            errors[2],
+            Some("every process is waiting, deadlock at pc 4")
+        );
+        assert_eq!(errors[3], Some("register $40 is out of range at offset 1"));
+        assert_eq!(errors[4], None);
+        assert_eq!(errors[5], Some("division by zero at pc 8"));
+        assert_eq!(summary.results[0].instructions, 1000);
+        assert_eq!(summary.failed(), 5);
+        assert_eq!(
+            summary.results[4].to_string().split(" in ").next(),
+            Some("ok: exit 0 after 1 instructions")
+        );
+    }
+}
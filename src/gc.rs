@@ -0,0 +1,334 @@
+use std::fmt::Display;
+
+use crate::vm::Fault;
+
+// Object references are ordinary register values with this bit set and the
+// object's slot in the low 24 bits, which survives wrapping to 32 bits. Any
+// value of that shape is treated as a reference when looking for live
+// objects, so an integer that happens to look like one keeps that object
+// alive, but a live object is never collected.
+pub const REFERENCE_TAG: i64 = 0x4000_0000;
+const SLOT_MASK: i64 = 0xff_ffff;
+pub const MAX_OBJECTS: usize = SLOT_MASK as usize + 1;
+pub const MAX_FIELDS: usize = u16::MAX as usize;
+// Live objects at which the first automatic collection runs. After every
+// collection the next one waits until the survivors have doubled.
+pub const INITIAL_THRESHOLD: usize = 1024;
+
+pub fn reference(slot: usize) -> i64 {
+    REFERENCE_TAG | slot as i64
+}
+
+pub fn is_reference(value: i64) -> bool {
+    value & !SLOT_MASK == REFERENCE_TAG
+}
+
+// What `gcinfo` can ask for, by its immediate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GcInfo {
+    LiveObjects = 0,
+    Collections = 1,
+    Freed = 2,
+    StackDepth = 3,
+}
+
+impl GcInfo {
+    pub fn from_immediate(value: usize) -> Option<GcInfo> {
+        match value {
+            0 => Some(GcInfo::LiveObjects),
+            1 => Some(GcInfo::Collections),
+            2 => Some(GcInfo::Freed),
+            3 => Some(GcInfo::StackDepth),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Object {
+    pub fields: Vec<i64>,
+    marked: bool,
+}
+
+impl Object {
+    pub fn new(fields: Vec<i64>) -> Object {
+        Object {
+            fields,
+            marked: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct GcStats {
+    pub live_objects: usize,
+    pub peak_objects: usize,
+    pub allocations: u64,
+    pub collections: u64,
+    pub freed: u64,
+}
+
+impl Display for GcStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "objects:       {} live (peak {})",
+            self.live_objects, self.peak_objects
+        )?;
+        write!(
+            f,
+            "operations:    {} allocations, {} collections freeing {} objects",
+            self.allocations, self.collections, self.freed
+        )
+    }
+}
+
+// Objects of tagged fields for languages that want their memory managed,
+// collected by mark and sweep with the registers and `stack` as roots. It
+// lives next to the raw `VM::heap`, neither knows about the other.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManagedHeap {
+    // Objects by slot, `None` for slots the sweep gave back.
+    pub objects: Vec<Option<Object>>,
+    // Values pushed by `push`, kept alive like the registers.
+    pub stack: Vec<i64>,
+    // Live objects at which the next allocation collects first.
+    pub threshold: usize,
+    pub stats: GcStats,
+    free_slots: Vec<usize>,
+}
+
+impl Default for ManagedHeap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManagedHeap {
+    pub fn new() -> ManagedHeap {
+        ManagedHeap {
+            objects: Vec::new(),
+            stack: Vec::new(),
+            threshold: INITIAL_THRESHOLD,
+            stats: GcStats::default(),
+            free_slots: Vec::new(),
+        }
+    }
+
+    // Rebuilds the bookkeeping around saved objects and stack.
+    pub fn with_objects(objects: Vec<Option<Object>>, stack: Vec<i64>) -> ManagedHeap {
+        let mut heap = ManagedHeap {
+            objects,
+            stack,
+            ..ManagedHeap::new()
+        };
+        heap.free_slots = (0..heap.objects.len())
+            .rev()
+            .filter(|slot| heap.objects[*slot].is_none())
+            .collect();
+        heap.stats.live_objects = heap.objects.len() - heap.free_slots.len();
+        heap.stats.peak_objects = heap.stats.live_objects;
+        heap.threshold = heap.threshold.max(heap.stats.live_objects * 2);
+        heap
+    }
+
+    // Allocates an object with `field_count` zeroed fields and returns a
+    // reference to it, collecting first if enough objects piled up.
+    pub fn alloc(&mut self, field_count: i64, registers: &[i64]) -> Result<i64, Fault> {
+        if field_count < 0 || field_count as usize > MAX_FIELDS {
+            return Err(Fault::InvalidFieldCount(field_count));
+        }
+        if self.stats.live_objects >= self.threshold {
+            self.collect(registers);
+            self.threshold = self.threshold.max(self.stats.live_objects * 2);
+        }
+        let object = Object::new(vec![0; field_count as usize]);
+        let slot = match self.free_slots.pop() {
+            Some(slot) => slot,
+            None if self.objects.len() < MAX_OBJECTS => {
+                self.objects.push(None);
+                self.objects.len() - 1
+            }
+            None => return Err(Fault::OutOfObjects(MAX_OBJECTS)),
+        };
+        self.objects[slot] = Some(object);
+        self.stats.allocations += 1;
+        self.stats.live_objects += 1;
+        self.stats.peak_objects = self.stats.peak_objects.max(self.stats.live_objects);
+        Ok(reference(slot))
+    }
+
+    pub fn get(&self, object: i64, index: i64) -> Result<i64, Fault> {
+        let fields = &self.object(object)?.fields;
+        match usize::try_from(index).ok().and_then(|i| fields.get(i)) {
+            Some(value) => Ok(*value),
+            None => Err(Fault::FieldOutOfBounds { object, index }),
+        }
+    }
+
+    pub fn set(&mut self, object: i64, index: i64, value: i64) -> Result<(), Fault> {
+        let fields = &mut self.object_mut(object)?.fields;
+        match usize::try_from(index).ok().and_then(|i| fields.get_mut(i)) {
+            Some(field) => *field = value,
+            None => return Err(Fault::FieldOutOfBounds { object, index }),
+        }
+        Ok(())
+    }
+
+    pub fn push(&mut self, value: i64) {
+        self.stack.push(value);
+    }
+
+    pub fn pop(&mut self) -> Result<i64, Fault> {
+        self.stack.pop().ok_or(Fault::StackUnderflow)
+    }
+
+    pub fn info(&self, info: GcInfo) -> i64 {
+        match info {
+            GcInfo::LiveObjects => self.stats.live_objects as i64,
+            GcInfo::Collections => self.stats.collections as i64,
+            GcInfo::Freed => self.stats.freed as i64,
+            GcInfo::StackDepth => self.stack.len() as i64,
+        }
+    }
+
+    // Frees every object not reachable from the registers or the stack and
+    // returns how many that were.
+    pub fn collect(&mut self, registers: &[i64]) -> usize {
+        let mut pending: Vec<i64> = registers.iter().chain(&self.stack).copied().collect();
+        while let Some(value) = pending.pop() {
+            let slot = match self.slot(value) {
+                Some(slot) => slot,
+                None => continue,
+            };
+            if let Some(object) = &mut self.objects[slot] {
+                if !object.marked {
+                    object.marked = true;
+                    pending.extend_from_slice(&object.fields);
+                }
+            }
+        }
+        let mut freed = 0;
+        for (slot, entry) in self.objects.iter_mut().enumerate() {
+            match entry {
+                Some(object) if object.marked => object.marked = false,
+                Some(_) => {
+                    *entry = None;
+                    self.free_slots.push(slot);
+                    freed += 1;
+                }
+                None => {}
+            }
+        }
+        self.stats.collections += 1;
+        self.stats.freed += freed as u64;
+        self.stats.live_objects -= freed;
+        freed
+    }
+
+    // The slot of a live object `value` refers to.
+    fn slot(&self, value: i64) -> Option<usize> {
+        if !is_reference(value) {
+            return None;
+        }
+        let slot = (value & SLOT_MASK) as usize;
+        match self.objects.get(slot) {
+            Some(Some(_)) => Some(slot),
+            _ => None,
+        }
+    }
+
+    fn object(&self, object: i64) -> Result<&Object, Fault> {
+        let slot = self.slot(object).ok_or(Fault::InvalidObject(object))?;
+        Ok(self.objects[slot].as_ref().unwrap())
+    }
+
+    fn object_mut(&mut self, object: i64) -> Result<&mut Object, Fault> {
+        let slot = self.slot(object).ok_or(Fault::InvalidObject(object))?;
+        Ok(self.objects[slot].as_mut().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_references() {
+        assert!(is_reference(reference(0)));
+        assert!(is_reference(reference(MAX_OBJECTS - 1)));
+        assert!(!is_reference(0));
+        assert!(!is_reference(-1));
+        assert!(!is_reference(REFERENCE_TAG << 1));
+    }
+
+    #[test]
+    fn test_fields() {
+        let mut heap = ManagedHeap::new();
+        let object = heap.alloc(2, &[]).unwrap();
+        heap.set(object, 1, 42).unwrap();
+        assert_eq!(heap.get(object, 0), Ok(0));
+        assert_eq!(heap.get(object, 1), Ok(42));
+        assert_eq!(
+            heap.get(object, 2),
+            Err(Fault::FieldOutOfBounds { object, index: 2 })
+        );
+        assert_eq!(
+            heap.set(object, -1, 0),
+            Err(Fault::FieldOutOfBounds { object, index: -1 })
+        );
+        assert_eq!(heap.get(7, 0), Err(Fault::InvalidObject(7)));
+        assert_eq!(heap.alloc(-1, &[]), Err(Fault::InvalidFieldCount(-1)));
+    }
+
+    #[test]
+    fn test_collect_keeps_reachable_objects() {
+        let mut heap = ManagedHeap::new();
+        let root = heap.alloc(1, &[]).unwrap();
+        let child = heap.alloc(1, &[]).unwrap();
+        let garbage = heap.alloc(1, &[]).unwrap();
+        let pushed = heap.alloc(1, &[]).unwrap();
+        heap.set(root, 0, child).unwrap();
+        // A cycle only reachable from itself.
+        heap.set(garbage, 0, garbage).unwrap();
+        heap.push(pushed);
+        assert_eq!(heap.collect(&[root, 5]), 1);
+        assert_eq!(heap.get(child, 0), Ok(0));
+        assert_eq!(heap.get(pushed, 0), Ok(0));
+        assert_eq!(heap.get(garbage, 0), Err(Fault::InvalidObject(garbage)));
+        assert_eq!(heap.stats.live_objects, 3);
+        assert_eq!(heap.info(GcInfo::Freed), 1);
+
+        // The freed slot is handed out again.
+        assert_eq!(heap.alloc(0, &[]), Ok(garbage));
+        assert_eq!(heap.pop(), Ok(pushed));
+        assert_eq!(heap.pop(), Err(Fault::StackUnderflow));
+    }
+
+    #[test]
+    fn test_collects_automatically() {
+        let mut heap = ManagedHeap::new();
+        heap.threshold = 4;
+        let kept = heap.alloc(0, &[]).unwrap();
+        for _ in 0..3 {
+            heap.alloc(0, &[kept]).unwrap();
+        }
+        assert_eq!(heap.stats.collections, 0);
+        heap.alloc(0, &[kept]).unwrap();
+        assert_eq!(heap.stats.collections, 1);
+        assert_eq!(heap.stats.live_objects, 2);
+        assert_eq!(heap.threshold, 4);
+    }
+
+    #[test]
+    fn test_with_objects() {
+        let heap = ManagedHeap::with_objects(
+            vec![Some(Object::new(vec![1])), None, Some(Object::new(vec![]))],
+            vec![reference(0)],
+        );
+        assert_eq!(heap.stats.live_objects, 2);
+        let mut heap = heap;
+        assert_eq!(heap.alloc(0, &[]), Ok(reference(1)));
+        assert_eq!(heap.alloc(0, &[]), Ok(reference(3)));
+    }
+}
@@ -0,0 +1,444 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use crate::vm::Fault;
+
+// Byte freed blocks are filled with in debug mode, so stale data stands out.
+pub const POISON: u8 = 0xdd;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockState {
+    Live,
+    Free,
+    // Freed in debug mode. Never handed out again, so any later use of the
+    // pointer is caught.
+    Quarantined,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Block {
+    pub size: usize,
+    pub state: BlockState,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub peak_used: usize,
+    pub live_blocks: usize,
+    pub free_blocks: usize,
+    pub allocations: u64,
+    pub frees: u64,
+    pub reallocations: u64,
+}
+
+impl Display for HeapStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "heap size:     {} bytes", self.size)?;
+        writeln!(
+            f,
+            "in use:        {} bytes in {} blocks (peak {} bytes)",
+            self.used, self.live_blocks, self.peak_used
+        )?;
+        writeln!(
+            f,
+            "free:          {} bytes in {} blocks",
+            self.size - self.used,
+            self.free_blocks
+        )?;
+        write!(
+            f,
+            "operations:    {} allocs, {} frees, {} reallocs",
+            self.allocations, self.frees, self.reallocations
+        )
+    }
+}
+
+// Hands out blocks of `VM::heap`. Block bookkeeping is kept here rather than
+// in the heap bytes, so a pointer is just the offset of its block. Free blocks
+// are reused first fit and merged with free neighbours; the heap only grows
+// when nothing fits.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Allocator {
+    // Every block by its offset, together they cover the whole heap.
+    pub blocks: BTreeMap<usize, Block>,
+    // The heap never grows past this many bytes.
+    pub max_size: Option<usize>,
+    // Quarantines and poisons freed blocks to report double frees and uses
+    // after free reliably, at the cost of never reusing memory.
+    pub debug: bool,
+    pub stats: HeapStats,
+}
+
+impl Allocator {
+    pub fn new() -> Allocator {
+        Allocator::default()
+    }
+
+    // Bookkeeping from a saved block table. `None` unless the blocks cover the
+    // heap exactly, without gaps or overlaps.
+    pub fn with_blocks(heap: &[u8], blocks: BTreeMap<usize, Block>) -> Option<Allocator> {
+        let mut end = 0;
+        for (start, block) in &blocks {
+            if *start != end || block.size == 0 {
+                return None;
+            }
+            end += block.size;
+        }
+        if end != heap.len() {
+            return None;
+        }
+        let mut allocator = Allocator {
+            blocks,
+            ..Allocator::new()
+        };
+        allocator.update_stats(heap);
+        Some(allocator)
+    }
+
+    // Allocates a zeroed block of `size` bytes (at least one, so every block has
+    // its own address) and returns its offset.
+    pub fn alloc(&mut self, heap: &mut Vec<u8>, size: i64) -> Result<usize, Fault> {
+        if size < 0 {
+            return Err(Fault::NegativeSize(size));
+        }
+        let size = (size as usize).max(1);
+        let pointer = self.place(heap, size)?;
+        heap[pointer..pointer + size].fill(0);
+        self.stats.allocations += 1;
+        self.update_stats(heap);
+        Ok(pointer)
+    }
+
+    pub fn free(&mut self, heap: &mut [u8], pointer: i64) -> Result<(), Fault> {
+        let start = self.live_block(pointer, Fault::DoubleFree(pointer))?;
+        self.release(heap, start);
+        self.stats.frees += 1;
+        self.update_stats(heap);
+        Ok(())
+    }
+
+    // Resizes the block at `pointer`, in place when possible. Returns the
+    // offset of the block, which moves (keeping its contents) when it can't
+    // grow where it is.
+    pub fn realloc(&mut self, heap: &mut Vec<u8>, pointer: i64, size: i64) -> Result<usize, Fault> {
+        let start = self.live_block(pointer, Fault::UseAfterFree(pointer))?;
+        if size < 0 {
+            return Err(Fault::NegativeSize(size));
+        }
+        let size = (size as usize).max(1);
+        let old_size = self.blocks[&start].size;
+        let new_start = if size <= old_size || self.grow_in_place(heap, start, size)? {
+            self.shrink(start, size);
+            start
+        } else {
+            let new_start = self.place(heap, size)?;
+            heap.copy_within(start..start + old_size, new_start);
+            heap[new_start + old_size..new_start + size].fill(0);
+            self.release(heap, start);
+            new_start
+        };
+        self.stats.reallocations += 1;
+        self.update_stats(heap);
+        Ok(new_start)
+    }
+
+    // Checks that `len` bytes at `address` lie inside one live block, for
+    // instructions that read or write the heap.
+    pub fn check_access(&self, address: i64, len: usize) -> Result<(), Fault> {
+        let out_of_bounds = Fault::OutOfBounds { address, len };
+        let offset = usize::try_from(address).map_err(|_| out_of_bounds)?;
+        let (start, block) = self
+            .blocks
+            .range(..=offset)
+            .next_back()
+            .ok_or(out_of_bounds)?;
+        match block.state {
+            BlockState::Live if offset + len <= start + block.size => Ok(()),
+            BlockState::Live => Err(out_of_bounds),
+            BlockState::Free | BlockState::Quarantined => Err(Fault::UseAfterFree(address)),
+        }
+    }
+
+    // The offset of the live block `pointer` points at, or why it doesn't.
+    fn live_block(&self, pointer: i64, when_freed: Fault) -> Result<usize, Fault> {
+        let start = usize::try_from(pointer).map_err(|_| Fault::InvalidPointer(pointer))?;
+        match self.blocks.get(&start) {
+            Some(block) if block.state == BlockState::Live => Ok(start),
+            Some(_) => Err(when_freed),
+            None => Err(Fault::InvalidPointer(pointer)),
+        }
+    }
+
+    // Finds room for `size` bytes and marks it live, growing the heap if no
+    // free block is large enough.
+    fn place(&mut self, heap: &mut Vec<u8>, size: usize) -> Result<usize, Fault> {
+        let fit = self
+            .blocks
+            .iter()
+            .find(|(_, block)| block.state == BlockState::Free && block.size >= size)
+            .map(|(start, _)| *start);
+        let start = match fit {
+            Some(start) => start,
+            None => {
+                // A free block at the end only needs topping up.
+                let start = match self.blocks.iter().next_back() {
+                    Some((start, block)) if block.state == BlockState::Free => *start,
+                    _ => heap.len(),
+                };
+                self.grow_heap(heap, start, size)?;
+                self.blocks.insert(
+                    start,
+                    Block {
+                        size: heap.len() - start,
+                        state: BlockState::Free,
+                    },
+                );
+                start
+            }
+        };
+        self.blocks.insert(
+            start,
+            Block {
+                size: self.blocks[&start].size,
+                state: BlockState::Live,
+            },
+        );
+        self.shrink(start, size);
+        Ok(start)
+    }
+
+    // Grows the live block at `start` to at least `size` bytes by taking over
+    // the free block right after it, topping up the heap if that is the end
+    // of it. False if the block is boxed in.
+    fn grow_in_place(
+        &mut self,
+        heap: &mut Vec<u8>,
+        start: usize,
+        size: usize,
+    ) -> Result<bool, Fault> {
+        let end = start + self.blocks[&start].size;
+        let available = match self.blocks.get(&end) {
+            Some(next) if next.state == BlockState::Free => next.size,
+            Some(_) => return Ok(false),
+            None => 0,
+        };
+        if end - start + available < size {
+            if end + available != heap.len() {
+                return Ok(false);
+            }
+            self.grow_heap(heap, start, size)?;
+        }
+        self.blocks.remove(&end);
+        self.blocks.get_mut(&start).unwrap().size = (end + available).max(start + size) - start;
+        heap[end..start + size].fill(0);
+        Ok(true)
+    }
+
+    // Grows the heap to hold `size` bytes at `start`. Sizes come straight from
+    // registers, so anything past the limit, the address space or what the
+    // host can give is a fault rather than an abort.
+    fn grow_heap(&self, heap: &mut Vec<u8>, start: usize, size: usize) -> Result<(), Fault> {
+        let out_of_memory = Fault::OutOfMemory {
+            requested: size,
+            limit: self.max_size,
+        };
+        let len = start.checked_add(size).ok_or(out_of_memory)?;
+        if self.max_size.is_some_and(|limit| len > limit) {
+            return Err(out_of_memory);
+        }
+        if len > heap.len() {
+            heap.try_reserve_exact(len - heap.len())
+                .map_err(|_| out_of_memory)?;
+            heap.resize(len, 0);
+        }
+        Ok(())
+    }
+
+    // Cuts the live block at `start` down to `size` bytes, giving the rest back.
+    fn shrink(&mut self, start: usize, size: usize) {
+        let block = self.blocks.get_mut(&start).unwrap();
+        if block.size <= size {
+            return;
+        }
+        let rest = block.size - size;
+        block.size = size;
+        self.blocks.insert(
+            start + size,
+            Block {
+                size: rest,
+                state: BlockState::Free,
+            },
+        );
+        self.merge_free(start + size);
+    }
+
+    fn release(&mut self, heap: &mut [u8], start: usize) {
+        let block = self.blocks.get_mut(&start).unwrap();
+        if self.debug {
+            block.state = BlockState::Quarantined;
+            heap[start..start + block.size].fill(POISON);
+            return;
+        }
+        block.state = BlockState::Free;
+        self.merge_free(start);
+    }
+
+    // Merges the free block at `start` with free blocks on either side.
+    fn merge_free(&mut self, start: usize) {
+        let mut start = start;
+        if let Some((previous, block)) = self.blocks.range(..start).next_back() {
+            if block.state == BlockState::Free {
+                let previous = *previous;
+                let size = self.blocks.remove(&start).unwrap().size;
+                self.blocks.get_mut(&previous).unwrap().size += size;
+                start = previous;
+            }
+        }
+        let end = start + self.blocks[&start].size;
+        if let Some(next) = self.blocks.get(&end).copied() {
+            if next.state == BlockState::Free {
+                self.blocks.remove(&end);
+                self.blocks.get_mut(&start).unwrap().size += next.size;
+            }
+        }
+    }
+
+    fn update_stats(&mut self, heap: &[u8]) {
+        let live = self
+            .blocks
+            .values()
+            .filter(|block| block.state == BlockState::Live);
+        self.stats.size = heap.len();
+        self.stats.used = live.clone().map(|block| block.size).sum();
+        self.stats.live_blocks = live.count();
+        self.stats.free_blocks = self.blocks.len() - self.stats.live_blocks;
+        self.stats.peak_used = self.stats.peak_used.max(self.stats.used);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alloc_and_reuse() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(&mut heap, 8).unwrap();
+        let b = allocator.alloc(&mut heap, 4).unwrap();
+        assert_eq!((a, b), (0, 8));
+        assert_eq!(heap.len(), 12);
+        allocator.free(&mut heap, a as i64).unwrap();
+        assert_eq!(allocator.alloc(&mut heap, 3).unwrap(), 0);
+        assert_eq!(allocator.alloc(&mut heap, 5).unwrap(), 3);
+        assert_eq!(allocator.alloc(&mut heap, 1).unwrap(), 12);
+        assert_eq!(allocator.stats.live_blocks, 4);
+        assert_eq!(allocator.stats.used, 13);
+    }
+
+    #[test]
+    fn test_free_merges_neighbours() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::new();
+        for _ in 0..3 {
+            allocator.alloc(&mut heap, 4).unwrap();
+        }
+        allocator.free(&mut heap, 0).unwrap();
+        allocator.free(&mut heap, 8).unwrap();
+        allocator.free(&mut heap, 4).unwrap();
+        assert_eq!(
+            allocator.blocks.values().collect::<Vec<_>>(),
+            vec![&Block {
+                size: 12,
+                state: BlockState::Free
+            }]
+        );
+        assert_eq!(allocator.alloc(&mut heap, 12).unwrap(), 0);
+        assert_eq!(heap.len(), 12);
+    }
+
+    #[test]
+    fn test_realloc() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(&mut heap, 2).unwrap();
+        heap[a..a + 2].copy_from_slice(&[7, 8]);
+        // The last block grows in place.
+        assert_eq!(allocator.realloc(&mut heap, a as i64, 4).unwrap(), a);
+        assert_eq!(heap, vec![7, 8, 0, 0]);
+        let b = allocator.alloc(&mut heap, 1).unwrap();
+        // Boxed in by `b`, so it moves and keeps its contents.
+        let moved = allocator.realloc(&mut heap, a as i64, 6).unwrap();
+        assert_eq!(moved, 5);
+        assert_eq!(&heap[moved..moved + 6], &[7, 8, 0, 0, 0, 0]);
+        assert_eq!(allocator.alloc(&mut heap, 4).unwrap(), 0);
+        assert_eq!(allocator.realloc(&mut heap, b as i64, 1).unwrap(), b);
+        assert_eq!(allocator.stats.reallocations, 3);
+    }
+
+    #[test]
+    fn test_faults() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::new();
+        allocator.max_size = Some(16);
+        assert_eq!(allocator.alloc(&mut heap, -1), Err(Fault::NegativeSize(-1)));
+        assert_eq!(
+            allocator.alloc(&mut heap, 17),
+            Err(Fault::OutOfMemory {
+                requested: 17,
+                limit: Some(16)
+            })
+        );
+        let a = allocator.alloc(&mut heap, 4).unwrap() as i64;
+        // More than the host can give faults too, even without a limit.
+        allocator.max_size = None;
+        let out_of_memory = Err(Fault::OutOfMemory {
+            requested: i64::MAX as usize,
+            limit: None,
+        });
+        assert_eq!(allocator.alloc(&mut heap, i64::MAX), out_of_memory);
+        assert_eq!(
+            allocator.realloc(&mut heap, a, i64::MAX),
+            out_of_memory.map(|_| 0)
+        );
+        assert_eq!(heap.len(), 4);
+        allocator.max_size = Some(16);
+        assert_eq!(
+            allocator.free(&mut heap, a + 1),
+            Err(Fault::InvalidPointer(a + 1))
+        );
+        assert_eq!(allocator.check_access(a, 4), Ok(()));
+        assert_eq!(
+            allocator.check_access(a + 2, 4),
+            Err(Fault::OutOfBounds {
+                address: a + 2,
+                len: 4
+            })
+        );
+        allocator.free(&mut heap, a).unwrap();
+        assert_eq!(allocator.free(&mut heap, a), Err(Fault::DoubleFree(a)));
+        assert_eq!(allocator.check_access(a, 1), Err(Fault::UseAfterFree(a)));
+    }
+
+    #[test]
+    fn test_debug_mode_quarantines() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::new();
+        allocator.debug = true;
+        let a = allocator.alloc(&mut heap, 4).unwrap();
+        heap[a] = 1;
+        allocator.free(&mut heap, a as i64).unwrap();
+        assert_eq!(heap, vec![POISON; 4]);
+        assert_eq!(allocator.alloc(&mut heap, 4).unwrap(), 4);
+        assert_eq!(
+            allocator.free(&mut heap, a as i64),
+            Err(Fault::DoubleFree(a as i64))
+        );
+        assert_eq!(
+            allocator.realloc(&mut heap, a as i64, 8),
+            Err(Fault::UseAfterFree(a as i64))
+        );
+    }
+}
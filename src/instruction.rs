@@ -1,74 +1,215 @@
-#[derive(Debug, PartialEq, Clone, Copy)]
-pub enum Opcode {
-    ZERO,
-    LOAD,
-    ADD,
-    SUB,
-    MUL,
-    DIV,
-    JMP,
-    EQ,
-    NEQ,
-    JEQ,
-    JNEQ,
-    ALLOC,
-    INC,
-    DEC,
-    ILLEGAL,
+// The whole instruction set lives in the `instructions!` table at the bottom of
+// this macro. Every row generates the enum variant, its byte encoding, the
+// mnemonic the lexer accepts, the operand signature the parser and VM decode,
+// and the doc comment, so adding an instruction is a one line change (plus its
+// semantics in `VM::execute_operation`).
+macro_rules! instructions {
+    ($($name:ident = $byte:literal, $mnemonic:literal, [$($operand:ident),*], $doc:literal;)*) => {
+        #[derive(Debug, PartialEq, Clone, Copy)]
+        pub enum Opcode {
+            $(
+                #[doc = $doc]
+                $name,
+            )*
+            ILLEGAL,
+        }
+
+        impl Opcode {
+            pub const ALL: &'static [Opcode] = &[$(Opcode::$name),*];
+
+            pub fn mnemonic(&self) -> &'static str {
+                match self {
+                    $(Opcode::$name => $mnemonic,)*
+                    Opcode::ILLEGAL => "illegal",
+                }
+            }
+
+            pub fn from_mnemonic(word: &str) -> Option<Opcode> {
+                match word {
+                    $($mnemonic => Some(Opcode::$name),)*
+                    _ => None,
+                }
+            }
+
+            pub fn operands(&self) -> &'static [OperandKind] {
+                match self {
+                    $(Opcode::$name => &[$(OperandKind::$operand),*],)*
+                    Opcode::ILLEGAL => &[],
+                }
+            }
+        }
+
+        impl From<Opcode> for u8 {
+            fn from(value: Opcode) -> Self {
+                match value {
+                    $(Opcode::$name => $byte,)*
+                    Opcode::ILLEGAL => panic!("cannot convert to u8 from illegal"),
+                }
+            }
+        }
+
+        impl From<u8> for Opcode {
+            fn from(byte: u8) -> Self {
+                return match byte {
+                    $($byte => Self::$name,)*
+                    _ => Self::ILLEGAL,
+                };
+            }
+        }
+    };
 }
 
-#[allow(dead_code)]
-pub struct Instruction {
-    opcode: Opcode,
+instructions! {
+    ZERO = 0, "zero", [], "Does nothing.";
+    LOAD = 1, "load", [Register, Immediate], "Loads a 16 bit integer into a register.";
+    ADD = 2, "add", [Register, Register, Register], "Stores the sum of the last two registers in the first.";
+    SUB = 3, "sub", [Register, Register, Register], "Stores the difference of the last two registers in the first.";
+    MUL = 4, "mul", [Register, Register, Register], "Stores the product of the last two registers in the first.";
+    DIV = 5, "div", [Register, Register, Register], "Stores the quotient of the last two registers in the first and the remainder in the remainder register.";
+    JMP = 6, "jmp", [Label], "Jumps to a label.";
+    EQ = 7, "eq", [Register, Register], "Sets the equality flag if both registers hold the same value.";
+    JEQ = 8, "jeq", [Label], "Jumps to a label if the equality flag is set.";
+    ALLOC = 9, "alloc", [Register], "Allocates as many heap bytes as a register holds and puts the pointer to them in the register.";
+    INC = 10, "inc", [Register], "Increments a register by one.";
+    DEC = 11, "dec", [Register], "Decrements a register by one.";
+    JNEQ = 12, "jneq", [Label], "Jumps to a label if the equality flag is not set.";
+    NEQ = 13, "neq", [Register, Register], "Sets the equality flag if the registers hold different values.";
+    HLT = 14, "hlt", [], "Stops the program.";
+    EXIT = 15, "exit", [Immediate], "Stops the program with the given exit status.";
+    LOADS = 16, "loads", [Register, Immediate], "Loads a 16 bit integer into a register, sign extending it.";
+    LOADX = 17, "loadx", [Register, Immediate], "Shifts a register left by 16 bits and puts a 16 bit integer in the freed low bits.";
+    FREE = 18, "free", [Register], "Frees the heap block a register points to.";
+    REALLOC = 19, "realloc", [Register, Register], "Resizes the heap block the first register points to to the size in the second, updating the pointer.";
+    NEW = 20, "new", [Register, Register], "Allocates a managed object with as many fields as the second register holds and puts a reference to it in the first.";
+    GETF = 21, "getf", [Register, Register, Register], "Loads the field of the object in the second register indexed by the third register into the first.";
+    SETF = 22, "setf", [Register, Register, Register], "Stores the third register in the field of the object in the first register indexed by the second register.";
+    PUSH = 23, "push", [Register], "Pushes a register onto the stack, which keeps managed objects alive.";
+    POP = 24, "pop", [Register], "Pops the top of the stack into a register.";
+    GC = 25, "gc", [], "Collects every managed object not reachable from the registers or the stack.";
+    GCINFO = 26, "gcinfo", [Register, Immediate], "Loads a collector statistic into a register: 0 live objects, 1 collections, 2 objects freed, 3 stack depth, anything else 0.";
+    LDA = 27, "lda", [Register, Immediate], "Loads the read-only data address of a label into a register.";
+    STRLEN = 28, "strlen", [Register, Register], "Stores the length of the read-only string the second register points to in the first.";
+    STRLOAD = 29, "strload", [Pair, Register], "Copies the read-only string the register points to into a new heap string.";
+    STRCMP = 30, "strcmp", [Register, Pair, Pair], "Compares two heap strings, storing -1, 0 or 1 in the register and setting the equality flag if they are equal.";
+    STRCAT = 31, "strcat", [Pair, Pair, Pair], "Joins the last two heap strings into a new heap string.";
+    ITOA = 32, "itoa", [Pair, Register], "Writes the decimal digits of the register into a new heap string.";
+    ATOI = 33, "atoi", [Register, Pair], "Parses a heap string as a decimal integer, setting the equality flag if it is one.";
+    SPAWN = 34, "spawn", [Register, Label], "Starts a process at the label with the register in its $0 and puts the new process id in the register.";
+    YIELD = 35, "yield", [], "Lets the other processes run.";
+    JOIN = 36, "join", [Register], "Waits for the process in the register to finish and puts its exit status in the register.";
+    CHAN = 37, "chan", [Register], "Creates a channel and puts its id in the register.";
+    SEND = 38, "send", [Register, Register], "Sends the second register over the channel in the first.";
+    RECV = 39, "recv", [Register, Register], "Waits for a message on the channel in the second register and puts it in the first.";
 }
 
-impl Instruction {
-    pub fn new(op: Opcode) -> Instruction {
-        return Instruction { opcode: op };
+pub const REGISTER_COUNT: usize = 32;
+pub const INSTRUCTION_LENGTH: usize = 4;
+// Exit statuses are a byte wide on every host.
+pub const MAX_EXIT_STATUS: u16 = u8::MAX as u16;
+
+// How instructions are laid out in a program. `Fixed` pads every instruction
+// to `INSTRUCTION_LENGTH` bytes, `Compact` stores only the opcode and the
+// operands its signature asks for, so lengths differ per opcode.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum Encoding {
+    #[default]
+    Fixed,
+    Compact,
+}
+
+impl Encoding {
+    // Number of bytes an instruction with this opcode takes up.
+    pub fn length(&self, opcode: Opcode) -> usize {
+        match self {
+            Encoding::Fixed => INSTRUCTION_LENGTH,
+            Encoding::Compact => {
+                1 + opcode
+                    .operands()
+                    .iter()
+                    .map(|kind| kind.byte_len())
+                    .sum::<usize>()
+            }
+        }
     }
 }
 
-impl From<Opcode> for u8 {
-    fn from(value: Opcode) -> Self {
-        match value {
-            Opcode::ZERO => 0,
-            Opcode::LOAD => 1,
-            Opcode::ADD => 2,
-            Opcode::SUB => 3,
-            Opcode::MUL => 4,
-            Opcode::DIV => 5,
-            Opcode::JMP => 6,
-            Opcode::EQ => 7,
-            Opcode::JEQ => 8,
-            Opcode::ALLOC => 9,
-            Opcode::INC => 10,
-            Opcode::DEC => 11,
-            Opcode::JNEQ => 12,
-            Opcode::NEQ => 13,
-            Opcode::ILLEGAL => panic!("cannot convert to u8 from illegal"),
+// How wide the registers are. Registers are always stored as `i64`, a 32-bit
+// machine wraps every result to 32 bits.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum Width {
+    #[default]
+    W32,
+    W64,
+}
+
+impl Width {
+    pub fn bits(&self) -> u32 {
+        match self {
+            Width::W32 => 32,
+            Width::W64 => 64,
         }
     }
+
+    // Cuts a value down to what a register of this width holds.
+    pub fn wrap(&self, value: i64) -> i64 {
+        match self {
+            Width::W32 => value as i32 as i64,
+            Width::W64 => value,
+        }
+    }
+
+    pub fn fits(&self, value: i64) -> bool {
+        self.wrap(value) == value
+    }
 }
 
-impl From<u8> for Opcode {
-    fn from(byte: u8) -> Self {
-        return match byte {
-            0 => Self::ZERO,
-            1 => Self::LOAD,
-            2 => Self::ADD,
-            3 => Self::SUB,
-            4 => Self::MUL,
-            5 => Self::DIV,
-            6 => Self::JMP,
-            7 => Self::EQ,
-            8 => Self::JEQ,
-            9 => Self::ALLOC,
-            10 => Self::INC,
-            11 => Self::DEC,
-            12 => Self::JNEQ,
-            13 => Self::NEQ,
-            _ => Self::ILLEGAL,
-        };
+// A `Pair` is two registers written as the first of them, `$n` holds a heap
+// string's pointer and `$n+1` its length. Read-only strings are zero
+// terminated and only need a pointer.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OperandKind {
+    Register,
+    Pair,
+    Immediate,
+    Label,
+}
+
+impl OperandKind {
+    // Number of bytes the operand takes up in the encoded instruction.
+    pub fn byte_len(&self) -> usize {
+        match self {
+            OperandKind::Register | OperandKind::Pair => 1,
+            OperandKind::Immediate | OperandKind::Label => 2,
+        }
+    }
+
+    // Number of registers the operand names.
+    pub fn register_count(&self) -> usize {
+        match self {
+            OperandKind::Register => 1,
+            OperandKind::Pair => 2,
+            OperandKind::Immediate | OperandKind::Label => 0,
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            OperandKind::Register => "a register",
+            OperandKind::Pair => "a register pair",
+            OperandKind::Immediate => "an integer",
+            OperandKind::Label => "a label",
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub struct Instruction {
+    opcode: Opcode,
+}
+
+impl Instruction {
+    pub fn new(op: Opcode) -> Instruction {
+        Instruction { opcode: op }
     }
 }
 
@@ -83,4 +224,40 @@ mod tests {
         };
         assert_eq!(inst.opcode, Opcode::ZERO);
     }
+
+    #[test]
+    fn test_operands_fit_instruction() {
+        for byte in 0..=u8::MAX {
+            let opcode = Opcode::from(byte);
+            let len: usize = opcode.operands().iter().map(|kind| kind.byte_len()).sum();
+            assert!(len <= 3, "{:?} does not fit in 4 bytes", opcode);
+        }
+    }
+
+    #[test]
+    fn test_encoding_round_trips() {
+        for opcode in Opcode::ALL {
+            assert_eq!(Opcode::from(u8::from(*opcode)), *opcode);
+            assert_eq!(Opcode::from_mnemonic(opcode.mnemonic()), Some(*opcode));
+        }
+    }
+
+    #[test]
+    fn test_encoding_lengths() {
+        assert_eq!(Encoding::Fixed.length(Opcode::HLT), 4);
+        assert_eq!(Encoding::Compact.length(Opcode::HLT), 1);
+        assert_eq!(Encoding::Compact.length(Opcode::INC), 2);
+        assert_eq!(Encoding::Compact.length(Opcode::LOAD), 4);
+        assert_eq!(Encoding::Compact.length(Opcode::ADD), 4);
+        assert_eq!(Encoding::Compact.length(Opcode::JMP), 3);
+        assert_eq!(Encoding::Compact.length(Opcode::SPAWN), 4);
+    }
+
+    #[test]
+    fn test_width_wraps() {
+        assert_eq!(Width::W32.wrap(i32::MAX as i64 + 1), i32::MIN as i64);
+        assert_eq!(Width::W64.wrap(i32::MAX as i64 + 1), i32::MAX as i64 + 1);
+        assert!(Width::W32.fits(-5));
+        assert!(!Width::W32.fits(1 << 40));
+    }
 }
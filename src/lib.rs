@@ -0,0 +1,20 @@
+pub mod assembler;
+pub mod bytecode;
+pub mod cli;
+pub mod coverage;
+pub mod decoder;
+pub mod disassembler;
+pub mod executor;
+pub mod gc;
+pub mod heap;
+pub mod instruction;
+pub mod optimizer;
+pub mod profile;
+pub mod reader;
+pub mod repl;
+pub mod scheduler;
+pub mod source_map;
+pub mod state;
+pub mod trace;
+pub mod verifier;
+pub mod vm;
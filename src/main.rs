@@ -1,33 +1,335 @@
-pub mod assembler;
-pub mod instruction;
-pub mod repl;
-pub mod vm;
 use std::fs::{self};
 
-use assembler::Assembler;
+use rpd::assembler::Assembler;
+use rpd::bytecode::{self, Bytecode};
+use rpd::cli::{self, BatchOptions, BuildOptions, RunOptions};
+use rpd::coverage::Coverage;
+use rpd::executor::{Executor, Job, JobResult};
+use rpd::instruction::{Encoding, Width};
+use rpd::optimizer;
+use rpd::profile::Profile;
+use rpd::repl;
+use rpd::scheduler::{ProcessState, Scheduler};
+use rpd::source_map::SourceMap;
+use rpd::vm::{RunStatus, VM};
 
-fn get_file_content(file_name: &String) -> std::io::Result<String> {
-    fs::read_to_string(file_name).map_err(|e| {
-        std::io::Error::new(
-            e.kind(),
-            format!("Error while reading file {}: {}", file_name, e),
-        )
-    })
+fn get_file_content(file_name: &String) -> std::io::Result<Vec<u8>> {
+    fs::read(file_name)
+        .map_err(|e| std::io::Error::new(e.kind(), format!("could not read {}: {}", file_name, e)))
+}
+
+// A program ready to run, with the source it was assembled from if it was
+// given as assembly.
+struct Program {
+    bytecode: Vec<u8>,
+    read_only: Vec<u8>,
+    source_map: Option<SourceMap>,
+    source: Option<String>,
+    width: Width,
+    encoding: Encoding,
+}
+
+// Reads a bytecode file or assembles a source file, printing any errors.
+// Source is assembled for `width` in `encoding`. Bytecode brings its own
+// encoding, and its own width unless `width` asks for 64-bit registers.
+fn load_program(file: &String, width: Width, encoding: Encoding) -> Option<Program> {
+    let file_content = match get_file_content(file) {
+        Ok(file_content) => file_content,
+        Err(err) => {
+            eprintln!("ERROR: {}", err);
+            return None;
+        }
+    };
+    if bytecode::is_bytecode(&file_content) {
+        return match bytecode::decode(&file_content) {
+            Ok(decoded) => Some(Program {
+                bytecode: decoded.program,
+                read_only: decoded.read_only,
+                source_map: decoded.source_map,
+                source: None,
+                width: match width {
+                    Width::W64 => Width::W64,
+                    Width::W32 => decoded.width,
+                },
+                encoding: decoded.encoding,
+            }),
+            Err(err) => {
+                eprintln!("ERROR: {}", err);
+                None
+            }
+        };
+    }
+    let source = String::from_utf8_lossy(&file_content).into_owned();
+    let mut ass = Assembler::new(source.clone());
+    ass.width = width;
+    ass.encoding = encoding;
+    match ass.assemble() {
+        Ok(bytecode) => Some(Program {
+            bytecode,
+            read_only: ass.read_only_secion.clone(),
+            source_map: Some(SourceMap::new(file, ass.listing().clone())),
+            source: Some(source),
+            width,
+            encoding,
+        }),
+        Err(errors) => {
+            for err in errors {
+                eprintln!("{}", err);
+            }
+            None
+        }
+    }
+}
+
+fn optimize_program(program: &mut Program) {
+    let optimized = optimizer::optimize(&program.bytecode, program.encoding);
+    program.bytecode = optimized.program;
+    program.source_map = program
+        .source_map
+        .as_ref()
+        .map(|source_map| source_map.relocate(&optimized.offsets));
+}
+
+// 64-bit registers when asked for on the command line.
+fn width(wide: bool) -> Width {
+    match wide {
+        true => Width::W64,
+        false => Width::W32,
+    }
+}
+
+fn encoding(compact: bool) -> Encoding {
+    match compact {
+        true => Encoding::Compact,
+        false => Encoding::Fixed,
+    }
+}
+
+fn build_file(options: &BuildOptions) -> i32 {
+    let mut program = match load_program(
+        &options.file,
+        width(options.wide),
+        encoding(options.compact),
+    ) {
+        Some(program) => program,
+        None => return 1,
+    };
+    if options.optimize {
+        optimize_program(&mut program);
+    }
+    let bytes = bytecode::encode(&Bytecode {
+        program: program.bytecode,
+        read_only: program.read_only,
+        source_map: program.source_map.filter(|_| options.source_map),
+        width: program.width,
+        encoding: program.encoding,
+    });
+    if let Err(err) = fs::write(&options.output, bytes) {
+        eprintln!("ERROR: could not write {}: {}", options.output, err);
+        return 1;
+    }
+    0
+}
+
+fn run_file(options: &RunOptions) -> i32 {
+    let mut program = match load_program(
+        &options.file,
+        width(options.wide),
+        encoding(options.compact),
+    ) {
+        Some(program) => program,
+        None => return 1,
+    };
+    if options.optimize {
+        optimize_program(&mut program);
+    }
+    let mut vm = VM::new_with_program(program.bytecode);
+    vm.width = program.width;
+    vm.encoding = program.encoding;
+    vm.read_only = program.read_only;
+    vm.source_map = program.source_map;
+    vm.allocator.max_size = options.heap_limit;
+    vm.allocator.debug = options.heap_debug;
+    vm.tracer = match options.tracer() {
+        Ok(tracer) => tracer,
+        Err(err) => {
+            eprintln!("ERROR: {}", err);
+            return 1;
+        }
+    };
+    if let Err(errors) = vm.verify() {
+        for err in errors {
+            match vm.location(err.offset()) {
+                Some(location) => eprintln!("{} ({})", err, location),
+                None => eprintln!("{}", err),
+            }
+        }
+        return 1;
+    }
+    if options.wants_profile() {
+        vm.profile = Some(Profile::new());
+    }
+    if options.wants_coverage() {
+        if vm.source_map.is_none() {
+            eprintln!("ERROR: coverage needs the assembly source or a source map");
+            return 1;
+        }
+        if options.coverage_annotate.is_some() && program.source.is_none() {
+            eprintln!("ERROR: annotated coverage needs the assembly source");
+            return 1;
+        }
+        vm.coverage = Some(Coverage::new());
+    }
+    let mut scheduler = Scheduler::new(vm);
+    scheduler.quantum = options.quantum;
+    let status = scheduler.run();
+    // Other processes only fault on their own, the main one reports below.
+    for (id, process) in scheduler.processes.iter().enumerate().skip(1) {
+        if let ProcessState::Finished(RunStatus::Fault(fault)) = process.state {
+            let pc = process.vm.program_counter;
+            match process.vm.location(pc) {
+                Some(location) => {
+                    eprintln!(
+                        "ERROR: process {}: {} at pc {} ({})",
+                        id, fault, pc, location
+                    )
+                }
+                None => eprintln!("ERROR: process {}: {} at pc {}", id, fault, pc),
+            }
+        }
+    }
+    let vm = scheduler.into_main();
+    if let Err(err) = write_reports(options, &vm, program.source.as_deref()) {
+        eprintln!("ERROR: {}", err);
+        return 1;
+    }
+    match status {
+        RunStatus::Halted(code) => code,
+        RunStatus::Fault(fault) => {
+            let pc = vm.program_counter;
+            match vm.location(pc) {
+                Some(location) => eprintln!("ERROR: {} at pc {} ({})", fault, pc, location),
+                None => eprintln!("ERROR: {} at pc {}", fault, pc),
+            }
+            1
+        }
+        RunStatus::Deadlock => {
+            eprintln!("ERROR: every process is waiting, deadlock");
+            1
+        }
+        _ => 0,
+    }
+}
+
+// Runs every bytecode file in a directory on a thread pool and prints a line
+// per file and a summary. Fails if any program did.
+fn batch_dir(options: &BatchOptions) -> i32 {
+    let entries = match fs::read_dir(&options.dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            eprintln!("ERROR: could not read {}: {}", options.dir, err);
+            return 1;
+        }
+    };
+    let mut files: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "rpdc")
+        })
+        .collect();
+    files.sort();
+    let mut jobs = vec![];
+    // Files that can't be read or decoded are reported with the others.
+    let mut failed = vec![];
+    for file in files {
+        let name = file.to_string_lossy().into_owned();
+        match fs::read(&file)
+            .map_err(|err| err.to_string())
+            .and_then(|bytes| bytecode::decode(&bytes))
+        {
+            Ok(decoded) => jobs.push(Job::from_bytecode(&name, decoded)),
+            Err(err) => failed.push(JobResult::failed(&name, err)),
+        }
+    }
+    let mut executor = match options.jobs {
+        Some(workers) => Executor::new(workers),
+        None => Executor::with_available_parallelism(),
+    };
+    executor.fuel = options.fuel;
+    executor.quantum = options.quantum;
+    let mut summary = executor.run(jobs);
+    summary.results.extend(failed);
+    summary.results.sort_by(|a, b| a.name.cmp(&b.name));
+    println!("{}", summary);
+    match summary.failed() {
+        0 => 0,
+        _ => 1,
+    }
+}
+
+// Writes out whatever the profiler and coverage collected during the run.
+fn write_reports(options: &RunOptions, vm: &VM, source: Option<&str>) -> Result<(), String> {
+    let write = |file: &String, contents: String| {
+        fs::write(file, contents).map_err(|e| format!("could not write {}: {}", file, e))
+    };
+    let listing = vm.source_map.as_ref().map(|source_map| &source_map.listing);
+    if let Some(profile) = &vm.profile {
+        if options.profile {
+            eprint!("{}", profile.report(&vm.program, listing));
+        }
+        if let Some(file) = &options.profile_collapsed {
+            write(file, profile.to_collapsed(&vm.program, listing))?;
+        }
+    }
+    if let (Some(coverage), Some(source_map)) = (&vm.coverage, &vm.source_map) {
+        if let Some(file) = &options.coverage_lcov {
+            write(
+                file,
+                coverage.to_lcov(&source_map.file, &source_map.listing),
+            )?;
+        }
+        if let (Some(file), Some(source)) = (&options.coverage_annotate, source) {
+            write(file, coverage.annotate(source, &source_map.listing))?;
+        }
+    }
+    Ok(())
 }
 
 fn main() {
     let args: Vec<String> = std::env::args().skip(1).collect();
-    if args.len() == 0 {
+    if args.is_empty() {
         let mut repl = repl::REPL::new();
         repl.run();
-    } else if args.len() == 2 {
-        if args[0] == "-f" {
-            let file_content = match get_file_content(&args[1]) {
-                Ok(file_content) => file_content,
-                Err(err) => panic!("{}", err),
-            };
-            let mut ass = Assembler::new();
-            ass.run(file_content);
+    } else if args[0] == "run" || args[0] == "-f" {
+        match cli::parse_run_options(&args[1..]) {
+            Ok(options) => std::process::exit(run_file(&options)),
+            Err(err) => {
+                eprintln!("ERROR: {}", err);
+                eprintln!("{}", cli::USAGE);
+                std::process::exit(2);
+            }
+        }
+    } else if args[0] == "build" {
+        match cli::parse_build_options(&args[1..]) {
+            Ok(options) => std::process::exit(build_file(&options)),
+            Err(err) => {
+                eprintln!("ERROR: {}", err);
+                eprintln!("{}", cli::USAGE);
+                std::process::exit(2);
+            }
+        }
+    } else if args[0] == "batch" {
+        match cli::parse_batch_options(&args[1..]) {
+            Ok(options) => std::process::exit(batch_dir(&options)),
+            Err(err) => {
+                eprintln!("ERROR: {}", err);
+                eprintln!("{}", cli::USAGE);
+                std::process::exit(2);
+            }
         }
+    } else {
+        eprintln!("{}", cli::USAGE);
+        std::process::exit(2);
     }
 }
@@ -0,0 +1,618 @@
+use crate::decoder::{self, DecodedInstruction};
+use crate::instruction::{Encoding, Opcode, OperandKind, REGISTER_COUNT};
+
+// An optimized program and where every instruction of the original ended up,
+// so source maps can follow along.
+#[derive(Debug, PartialEq)]
+pub struct Optimized {
+    pub program: Vec<u8>,
+    // The original offset of every instruction with its new one, `None` if it
+    // was removed, ending with the original and new length of the program.
+    pub offsets: Vec<(usize, Option<usize>)>,
+}
+
+// Peephole optimizations on assembled bytecode. Every pass keeps the observable
+// behaviour of the program (registers, flags, heap and exit status) the same:
+//
+// - arithmetic on registers holding known constants is folded into a `load`,
+//   and adding or subtracting a register known to hold 1 becomes `inc`/`dec`
+// - jumps to a `jmp` go straight to its target
+// - `zero` padding and code after an unconditional jump that nothing jumps to
+//   are removed
+// - a `load` into a register that is overwritten before anything reads it, or
+//   anything can fault or block, is removed
+//
+// Constants are only tracked within a basic block. Programs that can't be
+// pre-decoded (see `decoder::decode`) are returned unchanged.
+pub fn optimize(program: &[u8], encoding: Encoding) -> Optimized {
+    let (mut instructions, original_offsets) = match (
+        decoder::decode(program, encoding),
+        decoder::boundaries(program, encoding),
+    ) {
+        (Some(instructions), Some(offsets)) => (instructions, offsets),
+        _ => {
+            return Optimized {
+                program: program.to_vec(),
+                offsets: (0..=program.len())
+                    .map(|offset| (offset, Some(offset)))
+                    .collect(),
+            }
+        }
+    };
+    fold_constants(&mut instructions);
+    thread_jumps(&mut instructions);
+    let mut keep = vec![true; instructions.len()];
+    remove_padding(&instructions, &mut keep);
+    remove_unreachable(&instructions, &mut keep);
+    remove_dead_loads(&instructions, &mut keep);
+    compact(&instructions, &keep, &original_offsets, encoding)
+}
+
+fn is_jump(opcode: Opcode) -> bool {
+    matches!(opcode, Opcode::JMP | Opcode::JEQ | Opcode::JNEQ)
+}
+
+// Execution never falls through to the next instruction after these.
+fn ends_flow(opcode: Opcode) -> bool {
+    matches!(opcode, Opcode::JMP | Opcode::HLT | Opcode::EXIT)
+}
+
+// Instructions that can fault or hand control to the scheduler, leaving the
+// registers as they are at that point for anyone to see.
+fn can_stop(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::DIV
+            | Opcode::ALLOC
+            | Opcode::FREE
+            | Opcode::REALLOC
+            | Opcode::NEW
+            | Opcode::GETF
+            | Opcode::SETF
+            | Opcode::POP
+            | Opcode::STRLEN
+            | Opcode::STRLOAD
+            | Opcode::STRCMP
+            | Opcode::STRCAT
+            | Opcode::ITOA
+            | Opcode::ATOI
+            | Opcode::SPAWN
+            | Opcode::YIELD
+            | Opcode::JOIN
+            | Opcode::CHAN
+            | Opcode::SEND
+            | Opcode::RECV
+    )
+}
+
+// Which operand of an instruction is a label, like a jump's target or where
+// `spawn` starts the new process.
+fn label_operand(opcode: Opcode) -> Option<usize> {
+    opcode
+        .operands()
+        .iter()
+        .position(|kind| *kind == OperandKind::Label)
+}
+
+// Indices that some jump or spawned process lands on. Has one extra slot for
+// the end of the program.
+fn jump_targets(instructions: &[DecodedInstruction]) -> Vec<bool> {
+    let mut targets = vec![false; instructions.len() + 1];
+    for instruction in instructions {
+        if let Some(operand) = label_operand(instruction.opcode) {
+            targets[instruction.operands[operand]] = true;
+        }
+    }
+    targets
+}
+
+// The first instruction of every basic block.
+fn block_starts(instructions: &[DecodedInstruction]) -> Vec<bool> {
+    let mut starts = jump_targets(instructions);
+    starts[0] = true;
+    for (index, instruction) in instructions.iter().enumerate() {
+        if is_jump(instruction.opcode) || ends_flow(instruction.opcode) {
+            starts[index + 1] = true;
+        }
+    }
+    starts
+}
+
+// A collection looks at every register for references.
+const ALL_REGISTERS: [usize; REGISTER_COUNT] = {
+    let mut registers = [0; REGISTER_COUNT];
+    let mut index = 0;
+    while index < REGISTER_COUNT {
+        registers[index] = index;
+        index += 1;
+    }
+    registers
+};
+
+// A pair operand reads or writes the register after it too.
+fn reads(instruction: &DecodedInstruction) -> Vec<usize> {
+    let operands = &instruction.operands;
+    match instruction.opcode {
+        Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV | Opcode::GETF => {
+            operands[1..3].to_vec()
+        }
+        Opcode::SETF => operands[0..3].to_vec(),
+        Opcode::EQ | Opcode::NEQ | Opcode::REALLOC => operands[0..2].to_vec(),
+        Opcode::ALLOC | Opcode::FREE | Opcode::INC | Opcode::DEC | Opcode::LOADX => {
+            operands[0..1].to_vec()
+        }
+        Opcode::PUSH | Opcode::SPAWN | Opcode::JOIN => operands[0..1].to_vec(),
+        Opcode::SEND => operands[0..2].to_vec(),
+        Opcode::RECV => operands[1..2].to_vec(),
+        Opcode::NEW | Opcode::GC => ALL_REGISTERS.to_vec(),
+        Opcode::STRLEN | Opcode::STRLOAD | Opcode::ITOA => operands[1..2].to_vec(),
+        Opcode::ATOI => vec![operands[1], operands[1] + 1],
+        Opcode::STRCMP | Opcode::STRCAT => {
+            vec![operands[1], operands[1] + 1, operands[2], operands[2] + 1]
+        }
+        _ => vec![],
+    }
+}
+
+fn writes(instruction: &DecodedInstruction) -> Vec<usize> {
+    let destination = instruction.operands[0];
+    match instruction.opcode {
+        Opcode::LOAD
+        | Opcode::LOADS
+        | Opcode::LOADX
+        | Opcode::ADD
+        | Opcode::SUB
+        | Opcode::MUL
+        | Opcode::DIV
+        | Opcode::INC
+        | Opcode::DEC
+        | Opcode::ALLOC
+        | Opcode::REALLOC
+        | Opcode::NEW
+        | Opcode::GETF
+        | Opcode::POP
+        | Opcode::GCINFO
+        | Opcode::LDA
+        | Opcode::STRLEN
+        | Opcode::STRCMP
+        | Opcode::ATOI
+        | Opcode::SPAWN
+        | Opcode::JOIN
+        | Opcode::CHAN
+        | Opcode::RECV => vec![destination],
+        Opcode::STRLOAD | Opcode::STRCAT | Opcode::ITOA => vec![destination, destination + 1],
+        _ => vec![],
+    }
+}
+
+fn fold_constants(instructions: &mut [DecodedInstruction]) {
+    let starts = block_starts(instructions);
+    let mut known: [Option<i32>; REGISTER_COUNT] = [None; REGISTER_COUNT];
+    for (index, instruction) in instructions.iter_mut().enumerate() {
+        if starts[index] {
+            known = [None; REGISTER_COUNT];
+        }
+        let [destination, left, right] = instruction.operands;
+        let value = match instruction.opcode {
+            Opcode::LOAD => Some(instruction.operands[1] as i32),
+            Opcode::LOADS => Some(instruction.operands[1] as u16 as i16 as i32),
+            // Only folded while the result fits in 32 bits, so it is the same
+            // whatever the register width.
+            Opcode::LOADX => known[destination]
+                .and_then(|value| value.checked_mul(1 << 16))
+                .map(|value| value | instruction.operands[1] as i32),
+            Opcode::ADD | Opcode::SUB | Opcode::MUL => {
+                let result = match (known[left], known[right]) {
+                    (Some(a), Some(b)) => match instruction.opcode {
+                        Opcode::ADD => a.checked_add(b),
+                        Opcode::SUB => a.checked_sub(b),
+                        _ => a.checked_mul(b),
+                    },
+                    _ => None,
+                };
+                match result {
+                    Some(result) if (0..=u16::MAX as i32).contains(&result) => {
+                        *instruction = DecodedInstruction::new(
+                            Opcode::LOAD,
+                            [destination, result as usize, 0],
+                        );
+                    }
+                    Some(result) if (i16::MIN as i32..0).contains(&result) => {
+                        *instruction = DecodedInstruction::new(
+                            Opcode::LOADS,
+                            [destination, result as u16 as usize, 0],
+                        );
+                    }
+                    Some(_) => {}
+                    None => collapse_to_step(instruction, &known),
+                }
+                result
+            }
+            Opcode::INC => known[destination].and_then(|value| value.checked_add(1)),
+            Opcode::DEC => known[destination].and_then(|value| value.checked_sub(1)),
+            _ => None,
+        };
+        let written = writes(instruction);
+        for register in &written {
+            known[*register] = None;
+        }
+        if let Some(register) = written.first() {
+            known[*register] = value;
+        }
+    }
+}
+
+// `add $d $d $k` (or `add $d $k $d`) with $k holding 1 is `inc $d`, and
+// `sub $d $d $k` is `dec $d`.
+fn collapse_to_step(instruction: &mut DecodedInstruction, known: &[Option<i32>; REGISTER_COUNT]) {
+    let [destination, left, right] = instruction.operands;
+    let step = match instruction.opcode {
+        Opcode::ADD if left == destination && known[right] == Some(1) => Opcode::INC,
+        Opcode::ADD if right == destination && known[left] == Some(1) => Opcode::INC,
+        Opcode::SUB if left == destination && known[right] == Some(1) => Opcode::DEC,
+        _ => return,
+    };
+    *instruction = DecodedInstruction::new(step, [destination, 0, 0]);
+}
+
+fn thread_jumps(instructions: &mut [DecodedInstruction]) {
+    for index in 0..instructions.len() {
+        if !is_jump(instructions[index].opcode) {
+            continue;
+        }
+        let mut target = instructions[index].operands[0];
+        // Bounded so a cycle of jumps can't loop forever.
+        for _ in 0..instructions.len() {
+            match instructions.get(target) {
+                Some(next) if next.opcode == Opcode::JMP && next.operands[0] != target => {
+                    target = next.operands[0];
+                }
+                _ => break,
+            }
+        }
+        instructions[index].operands[0] = target;
+    }
+}
+
+fn remove_padding(instructions: &[DecodedInstruction], keep: &mut [bool]) {
+    for (index, instruction) in instructions.iter().enumerate() {
+        if instruction.opcode == Opcode::ZERO {
+            keep[index] = false;
+        }
+    }
+}
+
+fn remove_unreachable(instructions: &[DecodedInstruction], keep: &mut [bool]) {
+    let targets = jump_targets(instructions);
+    let mut reachable = true;
+    for (index, instruction) in instructions.iter().enumerate() {
+        if targets[index] {
+            reachable = true;
+        }
+        if !reachable {
+            keep[index] = false;
+        }
+        if ends_flow(instruction.opcode) {
+            reachable = false;
+        }
+    }
+}
+
+fn remove_dead_loads(instructions: &[DecodedInstruction], keep: &mut [bool]) {
+    let starts = block_starts(instructions);
+    for index in 0..instructions.len() {
+        let is_load = matches!(
+            instructions[index].opcode,
+            Opcode::LOAD | Opcode::LOADS | Opcode::LOADX
+        );
+        if !keep[index] || !is_load {
+            continue;
+        }
+        let register = instructions[index].operands[0];
+        for later in index + 1..instructions.len() {
+            if starts[later] {
+                break;
+            }
+            let instruction = &instructions[later];
+            if !keep[later] {
+                continue;
+            }
+            if reads(instruction).contains(&register) || can_stop(instruction.opcode) {
+                break;
+            }
+            if writes(instruction).contains(&register) {
+                keep[index] = false;
+                break;
+            }
+        }
+    }
+}
+
+// Drops the removed instructions and points every label at the first surviving
+// instruction at or after its old target.
+fn compact(
+    instructions: &[DecodedInstruction],
+    keep: &[bool],
+    original_offsets: &[usize],
+    encoding: Encoding,
+) -> Optimized {
+    let mut next_kept = vec![0; instructions.len() + 1];
+    let mut kept = keep.iter().filter(|keep| **keep).count();
+    next_kept[instructions.len()] = kept;
+    for index in (0..instructions.len()).rev() {
+        if keep[index] {
+            kept -= 1;
+        }
+        next_kept[index] = kept;
+    }
+    let mut kept_instructions = vec![];
+    for (index, instruction) in instructions.iter().enumerate() {
+        if keep[index] {
+            let mut instruction = *instruction;
+            if let Some(operand) = label_operand(instruction.opcode) {
+                instruction.operands[operand] = next_kept[instruction.operands[operand]];
+            }
+            kept_instructions.push(instruction);
+        }
+    }
+    let new_offsets = decoder::offsets(&kept_instructions, encoding);
+    let offsets = original_offsets
+        .iter()
+        .enumerate()
+        .map(|(index, original)| {
+            let kept = index == instructions.len() || keep[index];
+            (*original, kept.then(|| new_offsets[next_kept[index]]))
+        })
+        .collect();
+    Optimized {
+        // Nothing got longer, so every label still fits.
+        program: decoder::encode(&kept_instructions, encoding).unwrap(),
+        offsets,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::disassembler;
+    use crate::vm::{RunStatus, VM};
+
+    fn assemble(source: &str) -> Vec<u8> {
+        Assembler::new(source.to_string()).assemble().unwrap()
+    }
+
+    fn optimized(source: &str) -> Vec<String> {
+        disassembler::disassemble(
+            &optimize(&assemble(source), Encoding::Fixed).program,
+            Encoding::Fixed,
+        )
+    }
+
+    // Runs the program as written and optimized and checks that nothing
+    // observable differs.
+    fn assert_same_behaviour(source: &str) {
+        let program = assemble(source);
+        let mut plain = VM::new_with_program(program.clone());
+        let mut fast = VM::new_with_program(optimize(&program, Encoding::Fixed).program);
+        assert!(fast.verify().is_ok());
+        assert_eq!(plain.run(), fast.run());
+        assert_eq!(plain.registers, fast.registers);
+        assert_eq!(plain.remainder, fast.remainder);
+        assert_eq!(plain.equality_flag, fast.equality_flag);
+        assert_eq!(plain.heap, fast.heap);
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        assert_eq!(
+            optimized("load $1 #6\nload $2 #7\nmul $3 $1 $2\nsub $4 $3 $1"),
+            vec![
+                "0000: load $1 #6",
+                "0004: load $2 #7",
+                "0008: load $3 #42",
+                "0012: load $4 #36",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fold_wide_loads() {
+        assert_eq!(
+            optimized("load $1 #70000\nload $2 #70002\nsub $3 $1 $2\nhlt"),
+            vec![
+                "0000: load $1 #1",
+                "0004: loadx $1 #4464",
+                "0008: load $2 #1",
+                "0012: loadx $2 #4466",
+                "0016: loads $3 #-2",
+                "0020: hlt",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_collapse_into_inc() {
+        assert_eq!(
+            optimized("load $1 #1\nadd $2 $2 $1\nsub $3 $3 $1"),
+            vec!["0000: load $1 #1", "0004: inc $2", "0008: dec $3"]
+        );
+    }
+
+    #[test]
+    fn test_constants_do_not_cross_blocks() {
+        assert_eq!(
+            optimized("load $1 #1\nloop:\nadd $2 $2 $1\njmp @loop"),
+            vec!["0000: load $1 #1", "0004: add $2 $2 $1", "0008: jmp @4"]
+        );
+    }
+
+    #[test]
+    fn test_remove_unreachable_and_thread_jumps() {
+        assert_eq!(
+            optimized("jmp @a\ninc $0\nb:\njmp @c\na:\njmp @b\ninc $1\nc:\nhlt"),
+            vec!["0000: jmp @4", "0004: hlt"]
+        );
+    }
+
+    #[test]
+    fn test_remove_dead_loads_and_padding() {
+        assert_eq!(
+            optimized("load $1 #5\nzero\nload $1 #6\ninc $1\nload $2 #1\nload $2 #2\nhlt"),
+            vec![
+                "0000: load $1 #6",
+                "0004: inc $1",
+                "0008: load $2 #2",
+                "0012: hlt"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_collections_keep_loads() {
+        // Any register may hold a reference the collection has to see, and
+        // `pop` faults on an empty stack before overwriting its register.
+        assert_eq!(
+            optimized("loadx $1 #1\ngc\nload $1 #0\nnew $2 $3\nload $4 #1\npop $4\nhlt"),
+            vec![
+                "0000: loadx $1 #1",
+                "0004: gc",
+                "0008: load $1 #0",
+                "0012: new $2 $3",
+                "0016: load $4 #1",
+                "0020: pop $4",
+                "0024: hlt"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_string_pairs() {
+        // Loads into the second register of a pair are live, and a pair result
+        // overwrites both registers only if it doesn't fault first.
+        assert_eq!(
+            optimized("load $3 #1\nload $4 #2\natoi $0 $3\nload $6 #1\nitoa $5 $0\nadd $7 $6 $6"),
+            vec![
+                "0000: load $3 #1",
+                "0004: load $4 #2",
+                "0008: atoi $0 $3",
+                "0012: load $6 #1",
+                "0016: itoa $5 $0",
+                "0020: add $7 $6 $6"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_spawn_targets() {
+        // Code after a `hlt` that a process is spawned at is reachable, and
+        // its label follows the removed padding.
+        assert_eq!(
+            optimized("zero\nload $0 #1\nspawn $0 @child\nhlt\nchild:\nsend $0 $0\n"),
+            vec![
+                "0000: load $0 #1",
+                "0004: spawn $0 @12",
+                "0008: hlt",
+                "0012: send $0 $0"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_offsets() {
+        let optimized = optimize(
+            &assemble("zero\ninc $0\njmp @end\ninc $1\nend:\nhlt"),
+            Encoding::Fixed,
+        );
+        assert_eq!(
+            optimized.offsets,
+            vec![
+                (0, None),
+                (4, Some(0)),
+                (8, Some(4)),
+                (12, None),
+                (16, Some(8)),
+                (20, Some(12))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compact_encoding() {
+        let mut assembler = Assembler::new(
+            "zero\nload $1 #1\nadd $0 $0 $1\njmp @end\ninc $2\nend:\nhlt".to_string(),
+        );
+        assembler.encoding = Encoding::Compact;
+        let optimized = optimize(&assembler.assemble().unwrap(), Encoding::Compact);
+        assert_eq!(
+            disassembler::disassemble(&optimized.program, Encoding::Compact),
+            vec![
+                "0000: load $1 #1",
+                "0004: inc $0",
+                "0006: jmp @9",
+                "0009: hlt"
+            ]
+        );
+        assert_eq!(
+            optimized.offsets,
+            vec![
+                (0, None),
+                (1, Some(0)),
+                (5, Some(4)),
+                (9, Some(6)),
+                (12, None),
+                (14, Some(9)),
+                (15, Some(10))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_same_behaviour() {
+        assert_same_behaviour(
+            "load $1 #1\nload $0 #10\nloop:\nadd $2 $2 $1\nsub $0 $0 $1\nneq $0 $3\njeq @loop\nhlt",
+        );
+        assert_same_behaviour(
+            "load $1 #300\nload $2 #7\ndiv $3 $1 $2\nmul $4 $3 $3\nalloc $2\nexit #3",
+        );
+        assert_same_behaviour("load $1 #65535\nload $2 #2\nmul $3 $1 $2\nsub $4 $3 $3\ninc $4");
+        assert_same_behaviour(
+            "load $1 #-40000\nload $2 #-3\nmul $3 $1 $2\nload $3 #5\nadd $4 $3 $2",
+        );
+        assert_same_behaviour("eq $0 $1\njeq @skip\ninc $5\nskip:\njmp @end\nzero\nend:\ndec $6");
+        assert_same_behaviour(
+            "load $0 #3\ntop:\ndec $0\nload $9 #1\nload $9 #2\neq $0 $8\njneq @top",
+        );
+    }
+
+    #[test]
+    fn test_faults_keep_loads() {
+        // The register still holds the first load when the program faults.
+        assert_eq!(
+            optimized("load $0 #5\nload $1 #0\ndiv $2 $0 $1\nload $0 #7\nhlt"),
+            vec![
+                "0000: load $0 #5",
+                "0004: load $1 #0",
+                "0008: div $2 $0 $1",
+                "0012: load $0 #7",
+                "0016: hlt"
+            ]
+        );
+        for source in [
+            "load $0 #5\nload $1 #0\ndiv $2 $0 $1\nload $0 #7\nhlt",
+            "load $3 #9\nload $1 #64\nfree $1\nload $3 #1\nhlt",
+            "load $4 #2\npop $4\nhlt",
+        ] {
+            let program = assemble(source);
+            let mut vm = VM::new_with_program(program.clone());
+            assert!(matches!(vm.run(), RunStatus::Fault(_)));
+            assert_same_behaviour(source);
+        }
+    }
+
+    #[test]
+    fn test_undecodable_program_is_unchanged() {
+        let program = vec![1, 10, 1, 1, 0];
+        assert_eq!(optimize(&program, Encoding::Fixed).program, program);
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+
+use crate::assembler::Listing;
+use crate::disassembler;
+use crate::instruction::Opcode;
+
+// How many of the most executed addresses the report lists.
+const HOT_ADDRESSES: usize = 20;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BranchCounts {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+// Execution counts collected by `VM::run` while `VM::profile` is set.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Profile {
+    pub instructions: u64,
+    pub by_address: BTreeMap<usize, u64>,
+    pub by_opcode: BTreeMap<u8, u64>,
+    pub branches: BTreeMap<usize, BranchCounts>,
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    // Records one executed instruction, `next_pc` is where the program counter
+    // ended up afterwards and `fallthrough` the instruction right after this
+    // one, telling whether a conditional jump was taken.
+    pub fn record(&mut self, pc: usize, opcode: Opcode, next_pc: usize, fallthrough: usize) {
+        self.instructions += 1;
+        *self.by_address.entry(pc).or_insert(0) += 1;
+        *self.by_opcode.entry(u8::from(opcode)).or_insert(0) += 1;
+        if opcode == Opcode::JEQ || opcode == Opcode::JNEQ {
+            let counts = self.branches.entry(pc).or_default();
+            if next_pc == fallthrough {
+                counts.not_taken += 1;
+            } else {
+                counts.taken += 1;
+            }
+        }
+    }
+
+    // Adds up the counts of several runs, e.g. one per process.
+    pub fn merge(&mut self, other: &Profile) {
+        self.instructions += other.instructions;
+        for (pc, count) in &other.by_address {
+            *self.by_address.entry(*pc).or_insert(0) += count;
+        }
+        for (opcode, count) in &other.by_opcode {
+            *self.by_opcode.entry(*opcode).or_insert(0) += count;
+        }
+        for (pc, counts) in &other.branches {
+            let branch = self.branches.entry(*pc).or_default();
+            branch.taken += counts.taken;
+            branch.not_taken += counts.not_taken;
+        }
+    }
+
+    pub fn report(&self, program: &[u8], listing: Option<&Listing>) -> String {
+        let mut report = format!("{} instructions executed\n", self.instructions);
+
+        let mut hot: Vec<(&usize, &u64)> = self.by_address.iter().collect();
+        hot.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        report.push_str("\nhot addresses:\n");
+        for (pc, count) in hot.into_iter().take(HOT_ADDRESSES) {
+            let _ = writeln!(
+                report,
+                "  {:>10} {:>6}  {}",
+                count,
+                self.percentage(*count),
+                describe_address(*pc, program, listing)
+            );
+        }
+
+        let mut opcodes: Vec<(&u8, &u64)> = self.by_opcode.iter().collect();
+        opcodes.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        report.push_str("\nby instruction:\n");
+        for (opcode, count) in opcodes {
+            let _ = writeln!(
+                report,
+                "  {:>10} {:>6}  {}",
+                count,
+                self.percentage(*count),
+                Opcode::from(*opcode).mnemonic()
+            );
+        }
+
+        if !self.branches.is_empty() {
+            report.push_str("\nbranches:\n");
+            for (pc, counts) in &self.branches {
+                let _ = writeln!(
+                    report,
+                    "  taken {:>10} not taken {:>10}  {}",
+                    counts.taken,
+                    counts.not_taken,
+                    describe_address(*pc, program, listing)
+                );
+            }
+        }
+        report
+    }
+
+    // Folded stacks ("frame;frame count" per line) for flamegraph tools. Without
+    // calls every stack is the enclosing label and the instruction.
+    pub fn to_collapsed(&self, program: &[u8], listing: Option<&Listing>) -> String {
+        let mut collapsed = String::new();
+        for (pc, count) in &self.by_address {
+            let frame = match listing.and_then(|listing| listing.label_at(*pc)) {
+                Some((label, _)) => label.to_string(),
+                None => "<program>".to_string(),
+            };
+            let _ = writeln!(
+                collapsed,
+                "{};{:04} {} {}",
+                frame,
+                pc,
+                instruction_at(*pc, program),
+                count
+            );
+        }
+        collapsed
+    }
+
+    fn percentage(&self, count: u64) -> String {
+        if self.instructions == 0 {
+            return "-".to_string();
+        }
+        format!("{:.1}%", count as f64 * 100.0 / self.instructions as f64)
+    }
+}
+
+fn instruction_at(pc: usize, program: &[u8]) -> String {
+    disassembler::disassemble_instruction(&program[pc.min(program.len())..])
+}
+
+// "0008 loop+4 (line 6): dec $0", leaving out whatever the listing doesn't know.
+fn describe_address(pc: usize, program: &[u8], listing: Option<&Listing>) -> String {
+    let mut text = format!("{:04}", pc);
+    if let Some(listing) = listing {
+        if let Some((label, distance)) = listing.label_at(pc) {
+            let _ = write!(text, " {}+{}", label, distance);
+        }
+        if let Some(span) = listing.span_at(pc) {
+            let _ = write!(text, " (line {})", span.line);
+        }
+    }
+    let _ = write!(text, ": {}", instruction_at(pc, program));
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::VM;
+
+    fn profile(source: &str) -> (Profile, Vec<u8>, Listing) {
+        let mut assembler = Assembler::new(source.to_string());
+        let program = assembler.assemble().unwrap();
+        let mut vm = VM::new_with_program(program.clone());
+        vm.profile = Some(Profile::new());
+        vm.run();
+        (vm.profile.unwrap(), program, assembler.listing().clone())
+    }
+
+    #[test]
+    fn test_counts() {
+        let (profile, _, _) = profile("load $0 #3\nloop:\ndec $0\nneq $0 $1\njeq @loop\nhlt\n");
+        assert_eq!(profile.instructions, 1 + 3 * 3 + 1);
+        assert_eq!(profile.by_address[&4], 3);
+        assert_eq!(profile.by_opcode[&u8::from(Opcode::DEC)], 3);
+        assert_eq!(
+            profile.branches[&12],
+            BranchCounts {
+                taken: 2,
+                not_taken: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let (mut first, _, _) = profile("load $0 #2\nloop:\ndec $0\nneq $0 $1\njeq @loop\n");
+        let (second, _, _) = profile("load $0 #1\nloop:\ndec $0\nneq $0 $1\njeq @loop\n");
+        first.merge(&second);
+        assert_eq!(first.instructions, 7 + 4);
+        assert_eq!(first.by_address[&4], 3);
+        assert_eq!(first.by_opcode[&u8::from(Opcode::LOAD)], 2);
+        assert_eq!(
+            first.branches[&12],
+            BranchCounts {
+                taken: 1,
+                not_taken: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_report_uses_listing() {
+        let (profile, program, listing) =
+            profile("load $0 #2\nloop:\ndec $0\nneq $0 $1\njeq @loop\n");
+        let report = profile.report(&program, Some(&listing));
+        assert!(report.contains("2  28.6%  0004 loop+0 (line 3): dec $0"));
+        assert!(
+            report.contains("taken          1 not taken          1  0012 loop+8 (line 5): jeq @4")
+        );
+        let collapsed = profile.to_collapsed(&program, Some(&listing));
+        assert!(collapsed.contains("loop;0004 dec $0 2\n"));
+        assert!(collapsed.contains("<program>;0000 load $0 #2 1\n"));
+    }
+}
@@ -0,0 +1,66 @@
+// Cursor over a big endian binary format, shared by the file formats the VM
+// reads back in. `what` names the format in error messages.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    what: &'static str,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8], what: &'static str) -> Reader<'a> {
+        Reader {
+            bytes,
+            position: 0,
+            what,
+        }
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.position == self.bytes.len()
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .position
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or(format!("{} is truncated", self.what))?;
+        let slice = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(slice)
+    }
+
+    pub fn rest(&mut self) -> &'a [u8] {
+        let slice = &self.bytes[self.position..];
+        self.position = self.bytes.len();
+        slice
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, String> {
+        let mut buffer = [0; 4];
+        buffer.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(buffer))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, String> {
+        let mut buffer = [0; 8];
+        buffer.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(buffer))
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64, String> {
+        let mut buffer = [0; 8];
+        buffer.copy_from_slice(self.take(8)?);
+        Ok(i64::from_be_bytes(buffer))
+    }
+
+    pub fn read_string(&mut self) -> Result<String, String> {
+        let len = self.read_u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| format!("{} contains invalid text", self.what))
+    }
+}
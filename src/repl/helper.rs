@@ -0,0 +1,101 @@
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+use crate::instruction::{Opcode, REGISTER_COUNT};
+
+use super::COMMANDS;
+
+// Completes dot-commands, mnemonics, registers and the labels currently in the
+// REPL's symbol table.
+pub struct ReplHelper {
+    pub labels: Vec<String>,
+}
+
+impl Default for ReplHelper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReplHelper {
+    pub fn new() -> ReplHelper {
+        ReplHelper { labels: vec![] }
+    }
+
+    pub fn candidates(&self, word: &str) -> Vec<String> {
+        let options: Vec<String> = if word.starts_with('.') {
+            COMMANDS.iter().map(|command| command.to_string()).collect()
+        } else if word.starts_with('$') {
+            (0..REGISTER_COUNT).map(|reg| format!("${}", reg)).collect()
+        } else if word.starts_with('@') {
+            self.labels
+                .iter()
+                .map(|label| format!("@{}", label))
+                .collect()
+        } else {
+            Opcode::ALL
+                .iter()
+                .map(|opcode| opcode.mnemonic().to_string())
+                .collect()
+        };
+        options
+            .into_iter()
+            .filter(|option| option.starts_with(word))
+            .collect()
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(char::is_whitespace)
+            .map(|index| index + 1)
+            .unwrap_or(0);
+        Ok((start, self.candidates(&line[start..pos])))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_complete_mnemonics() {
+        let helper = ReplHelper::new();
+        assert_eq!(helper.candidates("jn"), vec!["jneq"]);
+    }
+
+    #[test]
+    fn test_complete_registers_and_commands() {
+        let helper = ReplHelper::new();
+        assert_eq!(helper.candidates("$3"), vec!["$3", "$30", "$31"]);
+        assert_eq!(helper.candidates(".his"), vec![".history"]);
+    }
+
+    #[test]
+    fn test_complete_labels() {
+        let mut helper = ReplHelper::new();
+        helper.labels = vec!["loop".to_string(), "end".to_string()];
+        assert_eq!(helper.candidates("@l"), vec!["@loop"]);
+    }
+}
@@ -1,71 +1,676 @@
+pub mod helper;
+
 use crate::assembler::Assembler;
-use crate::vm::VM;
+use crate::bytecode::{self, Bytecode};
+use crate::decoder;
+use crate::disassembler;
+use crate::instruction::{Encoding, INSTRUCTION_LENGTH};
+use crate::scheduler::Scheduler;
+use crate::source_map::SourceMap;
+use crate::state;
+use crate::verifier;
+use crate::vm::{RunStatus, VM};
+use helper::ReplHelper;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Config, Editor};
 use std;
-use std::io::Write;
-use std::io::{self};
+use std::fs;
 use std::num::ParseIntError;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+
+pub const COMMANDS: &[&str] = &[
+    ".exit",
+    ".history",
+    ".debug",
+    ".mode",
+    ".save",
+    ".load",
+    ".reset",
+    ".snapshot",
+    ".restore",
+    ".heap",
+    ".gc",
+];
+
+const HISTORY_FILE: &str = ".rpd_history";
+
+// The file name source locations of typed in code are reported with.
+const SOURCE_NAME: &str = "<repl>";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Mode {
+    Assembly,
+    Hex,
+}
+
+// Everything `.restore` rolls back to.
+struct Snapshot {
+    vm: VM,
+    assembler: Assembler,
+    source: String,
+    has_hex_input: bool,
+}
 
-#[allow(dead_code)]
 pub struct REPL {
-    history: Vec<String>,
     vm: VM,
+    assembler: Assembler,
+    mode: Mode,
+    // The assembly entered so far, which is what `.save` writes out.
+    source: String,
+    // Raw hex input has no source form, so such sessions can only be saved as bytecode.
+    has_hex_input: bool,
+    snapshot: Option<Snapshot>,
+}
+
+impl Default for REPL {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl REPL {
     pub fn new() -> REPL {
-        return REPL {
-            history: vec![],
+        REPL {
             vm: VM::new(),
-        };
+            assembler: Assembler::new(String::new()),
+            mode: Mode::Assembly,
+            source: String::new(),
+            has_hex_input: false,
+            snapshot: None,
+        }
     }
 
     pub fn run(&mut self) {
         println!("Welcome to rpd");
+        let interrupted = self.vm.interrupt_handle();
+        if let Err(e) = ctrlc::set_handler(move || interrupted.store(true, Ordering::Relaxed)) {
+            eprintln!("Failed to install Ctrl-C handler {}", e);
+        }
+        let config = Config::builder()
+            .history_ignore_dups(true)
+            .map(|b| b.build());
+        let mut editor: Editor<ReplHelper, DefaultHistory> =
+            match config.and_then(Editor::with_config) {
+                Ok(editor) => editor,
+                Err(e) => {
+                    eprintln!("Failed to start line editor {}", e);
+                    return;
+                }
+            };
+        editor.set_helper(Some(ReplHelper::new()));
+        let history_path = history_path();
+        if let Some(path) = &history_path {
+            // A missing history file just means this is the first session.
+            let _ = editor.load_history(path);
+        }
         loop {
-            let mut buffer = String::new();
-            let stdin = io::stdin();
-            print!(">>>");
-            if let Err(e) = io::stdout().flush() {
-                eprintln!("Failed to flush stdout {}", e)
-            }
-            if let Err(e) = stdin.read_line(&mut buffer) {
-                eprint!("Failed to read line {}", e);
+            if let Some(helper) = editor.helper_mut() {
+                helper.labels = self.assembler.symbol_table().names();
             }
-            self.history.push(buffer.to_string());
+            let prompt = match self.mode {
+                Mode::Assembly => ">>>",
+                Mode::Hex => "hex>>>",
+            };
+            let buffer = match editor.readline(prompt) {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(e) => {
+                    eprintln!("Failed to read line {}", e);
+                    break;
+                }
+            };
             let buffer_trimmed = buffer.trim();
+            if !buffer_trimmed.is_empty() && buffer_trimmed != ".history" {
+                if let Err(e) = editor.add_history_entry(buffer_trimmed) {
+                    eprintln!("Failed to record history {}", e);
+                }
+            }
             match buffer_trimmed {
                 ".exit" => {
                     println!("Have a good day ^^");
-                    std::process::exit(0);
+                    break;
                 }
                 ".history" => {
-                    for command in &self.history {
+                    for command in editor.history().iter() {
                         println!("{}", command);
                     }
                 }
-                ".debug" => {
-                    println!("{}", self.vm);
+                command if command.starts_with('.') => self.execute_command(command),
+                _ => match self.mode {
+                    Mode::Assembly => self.execute_source(buffer + "\n"),
+                    Mode::Hex => self.execute_hex(buffer_trimmed),
+                },
+            }
+        }
+        if let Some(path) = &history_path {
+            if let Err(e) = editor.save_history(path) {
+                eprintln!("Failed to save history {}", e);
+            }
+        }
+    }
+
+    fn execute_command(&mut self, command: &str) {
+        let (name, argument) = match command.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, argument.trim()),
+            None => (command, ""),
+        };
+        match (name, argument) {
+            (".debug", "") => {
+                println!("{}", self.vm);
+                if let Some(line) = self.current_line() {
+                    println!("Next: {}", line);
                 }
-                _ => {
-                    buffer.push('\n');
-                    let bytes = Assembler::parse_to_bytes(buffer);
-                    self.vm.append_to_program(bytes);
-                    self.vm.run();
+            }
+            (".mode", "") => println!("{:?}", self.mode),
+            (".mode", "asm") => self.mode = Mode::Assembly,
+            (".mode", "hex") => self.mode = Mode::Hex,
+            (".save", file) if !file.is_empty() => {
+                if let Err(e) = self.save(file) {
+                    eprintln!("ERROR: could not save {}: {}", file, e);
                 }
             }
+            (".load", file) if !file.is_empty() => self.load(file),
+            (".reset", "") => self.reset(),
+            (".snapshot", "") => {
+                self.snapshot = Some(Snapshot {
+                    vm: self.vm.clone(),
+                    assembler: self.assembler.clone(),
+                    source: self.source.clone(),
+                    has_hex_input: self.has_hex_input,
+                });
+            }
+            (".restore", "") => match &self.snapshot {
+                Some(snapshot) => {
+                    self.vm = snapshot.vm.clone();
+                    self.assembler = snapshot.assembler.clone();
+                    self.source = snapshot.source.clone();
+                    self.has_hex_input = snapshot.has_hex_input;
+                }
+                None => eprintln!("ERROR: no snapshot to restore, take one with .snapshot"),
+            },
+            (".snapshot", file) => {
+                if let Err(e) = fs::write(file, state::save(&self.vm)) {
+                    eprintln!("ERROR: could not write {}: {}", file, e);
+                }
+            }
+            (".restore", file) => self.restore_file(file),
+            (".gc", "") => println!("{}", self.vm.managed.stats),
+            (".gc", "collect") => {
+                let freed = self.vm.managed.collect(&self.vm.registers);
+                println!("Freed {} objects", freed);
+            }
+            (".heap", "") => println!("{}", self.vm.allocator.stats),
+            (".heap", "debug on") => self.vm.allocator.debug = true,
+            (".heap", "debug off") => self.vm.allocator.debug = false,
+            (".heap", "limit none") => self.vm.allocator.max_size = None,
+            (".heap", argument) if argument.starts_with("limit ") => {
+                match argument["limit ".len()..].trim().parse::<usize>() {
+                    Ok(limit) => self.vm.allocator.max_size = Some(limit),
+                    Err(_) => eprintln!("ERROR: expected a heap limit in bytes or none"),
+                }
+            }
+            _ => eprintln!("ERROR: unknown command {}", command),
+        }
+    }
+
+    // Files ending in `.rpdc` get the program as bytecode, anything else gets the
+    // assembly source that was typed in.
+    fn save(&self, file: &str) -> Result<(), String> {
+        if file.ends_with(".rpdc") {
+            let bytecode = Bytecode {
+                program: self.vm.program.clone(),
+                read_only: self.vm.read_only.clone(),
+                source_map: None,
+                width: self.vm.width,
+                encoding: self.vm.encoding,
+            };
+            return fs::write(file, bytecode::encode(&bytecode)).map_err(|e| e.to_string());
+        }
+        if self.has_hex_input {
+            return Err("the program contains hex input, save it as bytecode (.rpdc)".to_string());
+        }
+        fs::write(file, &self.source).map_err(|e| e.to_string())
+    }
+
+    fn load(&mut self, file: &str) {
+        let bytes = match fs::read(file) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                eprintln!("ERROR: could not read {}: {}", file, e);
+                return;
+            }
+        };
+        if bytecode::is_bytecode(&bytes) {
+            match bytecode::decode(&bytes) {
+                Ok(Bytecode { width, .. }) if width != self.vm.width => eprintln!(
+                    "ERROR: could not load {}: it needs {}-bit registers",
+                    file,
+                    width.bits()
+                ),
+                Ok(Bytecode {
+                    program,
+                    read_only,
+                    encoding,
+                    ..
+                }) => {
+                    // The session is always in the fixed encoding.
+                    let mut program = match encoding {
+                        Encoding::Fixed => program,
+                        Encoding::Compact => match decoder::decode(&program, encoding) {
+                            Some(instructions) => {
+                                match decoder::encode(&instructions, Encoding::Fixed) {
+                                    Some(program) => program,
+                                    None => {
+                                        eprintln!("ERROR: could not load {}: {}", file, TOO_LARGE);
+                                        return;
+                                    }
+                                }
+                            }
+                            None => {
+                                eprintln!("ERROR: could not load {}: invalid program", file);
+                                return;
+                            }
+                        },
+                    };
+                    let read_only_base = self.vm.read_only.len();
+                    match bytecode::relocate(&mut program, self.vm.program.len(), read_only_base) {
+                        Ok(()) => {
+                            self.assembler.append_read_only(&read_only);
+                            self.vm.read_only.extend_from_slice(&read_only);
+                            self.execute_bytes(program);
+                        }
+                        Err(e) => eprintln!("ERROR: could not load {}: {}", file, e),
+                    }
+                }
+                Err(e) => eprintln!("ERROR: could not load {}: {}", file, e),
+            }
+            return;
+        }
+        match String::from_utf8(bytes) {
+            Ok(source) => self.execute_source(source),
+            Err(_) => eprintln!("ERROR: {} is neither assembly nor bytecode", file),
+        }
+    }
+
+    // Loads VM state saved with `.snapshot <file>`. The source it came from is
+    // unknown, so the session continues as if the program was typed in as hex.
+    fn restore_file(&mut self, file: &str) {
+        let mut vm = self.vm.clone();
+        let result = fs::read(file)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| state::restore(&mut vm, &bytes))
+            .and_then(|()| to_fixed_encoding(&mut vm));
+        if let Err(e) = result {
+            eprintln!("ERROR: could not restore {}: {}", file, e);
+            return;
+        }
+        self.vm = vm;
+        self.assembler = Assembler::new(String::new());
+        self.assembler.width = self.vm.width;
+        self.assembler.append_bytecode(&self.vm.program);
+        self.assembler.append_read_only(&self.vm.read_only);
+        self.source.clear();
+        self.has_hex_input = true;
+    }
+
+    fn reset(&mut self) {
+        self.vm.reset();
+        self.assembler = Assembler::new(String::new());
+        self.source.clear();
+        self.has_hex_input = false;
+    }
+
+    // Assembles a line on top of everything entered so far and runs only the
+    // instructions it produced.
+    fn execute_source(&mut self, source: String) {
+        match self.assembler.assemble_more(source.clone()) {
+            Ok(bytes) => {
+                self.source.push_str(&source);
+                self.vm.source_map = Some(SourceMap::new(
+                    SOURCE_NAME,
+                    self.assembler.listing().clone(),
+                ));
+                self.vm.read_only = self.assembler.read_only_secion.clone();
+                self.vm.program_counter = self.vm.program.len();
+                self.vm.append_to_program(bytes);
+                self.run_vm();
+            }
+            Err(errors) => {
+                for err in errors {
+                    eprintln!("{}", err);
+                }
+            }
+        }
+    }
+
+    fn execute_hex(&mut self, input: &str) {
+        match self.parse_hex(input) {
+            Ok(bytes) => self.execute_bytes(bytes),
+            Err(e) => eprintln!("ERROR: invalid hex input: {}", e),
         }
     }
-    #[allow(dead_code)]
+
+    // Appends raw bytes to the program after checking them with the verifier,
+    // echoing back what they disassemble to.
+    fn execute_bytes(&mut self, bytes: Vec<u8>) {
+        if !bytes.len().is_multiple_of(INSTRUCTION_LENGTH) {
+            eprintln!(
+                "ERROR: input must be whole {} byte instructions",
+                INSTRUCTION_LENGTH
+            );
+            return;
+        }
+        let start = self.vm.program.len();
+        let mut program = self.vm.program.clone();
+        program.extend_from_slice(&bytes);
+        if let Err(errors) = verifier::verify(&program, self.vm.encoding) {
+            for err in errors {
+                eprintln!("{}", err);
+            }
+            return;
+        }
+        for line in disassembler::disassemble_from(&bytes, start, self.vm.encoding) {
+            println!("{}", line);
+        }
+        self.has_hex_input = true;
+        self.assembler.append_bytecode(&bytes);
+        self.vm.program_counter = start;
+        self.vm.append_to_program(bytes);
+        self.run_vm();
+    }
+
+    fn run_vm(&mut self) {
+        // A Ctrl-C pressed while waiting at the prompt should not stop the next program.
+        self.vm.interrupt_handle().store(false, Ordering::Relaxed);
+        let mut scheduler = Scheduler::new(std::mem::take(&mut self.vm));
+        let status = scheduler.run();
+        self.vm = scheduler.into_main();
+        match status {
+            RunStatus::Done | RunStatus::Halted(0) => (),
+            RunStatus::Halted(code) => println!("Program exited with status {}", code),
+            RunStatus::Interrupted => println!("Interrupted at {}", self.describe_pc()),
+            RunStatus::Fault(fault) => println!("{} at {}", fault, self.describe_pc()),
+            RunStatus::InstructionLimit | RunStatus::Timeout => println!(
+                "Stopped at {} after running out of budget",
+                self.describe_pc()
+            ),
+            RunStatus::Deadlock => println!("Deadlock at {}", self.describe_pc()),
+            // The scheduler serves every request.
+            RunStatus::Request(_) => {}
+        }
+    }
+
+    fn describe_pc(&self) -> String {
+        let pc = self.vm.program_counter;
+        match self.vm.location(pc) {
+            Some(location) => format!("pc {} ({})", pc, location),
+            None => format!("pc {}", pc),
+        }
+    }
+
+    // The source line of the instruction the program counter points at, e.g.
+    // "<repl>:3:1 (loop+0): dec $0".
+    fn current_line(&self) -> Option<String> {
+        if self.vm.program_counter >= self.vm.program.len() {
+            return None;
+        }
+        let location = self.vm.location(self.vm.program_counter)?;
+        let text = self.source.lines().nth(location.line.checked_sub(1)?)?;
+        Some(format!("{}: {}", location, text.trim()))
+    }
+
     fn parse_hex(&mut self, i: &str) -> Result<Vec<u8>, ParseIntError> {
-        let split = i.split(" ").collect::<Vec<&str>>();
+        let split = i.split_whitespace().collect::<Vec<&str>>();
         let mut results: Vec<u8> = vec![];
         for hex_str in split {
-            let byte = u8::from_str_radix(&hex_str, 16);
+            let byte = u8::from_str_radix(hex_str, 16);
             match byte {
                 Ok(result) => results.push(result),
                 Err(e) => return Err(e),
             }
         }
-        return Ok(results);
+        Ok(results)
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+const TOO_LARGE: &str = "the program is too large for the fixed encoding";
+
+// The session is always in the fixed encoding, so a restored compact program
+// is re-encoded, keeping the program counter on the same instruction.
+fn to_fixed_encoding(vm: &mut VM) -> Result<(), String> {
+    if vm.encoding == Encoding::Fixed {
+        return Ok(());
+    }
+    let instructions = decoder::decode(&vm.program, vm.encoding).ok_or("invalid program")?;
+    let index = decoder::offsets(&instructions, vm.encoding)
+        .binary_search(&vm.program_counter)
+        .map_err(|_| "the program counter is inside an instruction")?;
+    vm.program = decoder::encode(&instructions, Encoding::Fixed).ok_or(TOO_LARGE)?;
+    vm.program_counter = index * INSTRUCTION_LENGTH;
+    vm.encoding = Encoding::Fixed;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::process;
+
+    #[test]
+    fn test_labels_persist_between_lines() {
+        let mut repl = REPL::new();
+        repl.execute_source("load $0 #3\n".to_string());
+        repl.execute_source("loop: dec $0\n".to_string());
+        assert_eq!(repl.vm.registers[0], 2);
+        repl.execute_source("eq $0 $1\n".to_string());
+        repl.execute_source("jneq @loop\n".to_string());
+        assert_eq!(repl.vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_only_new_instructions_run() {
+        let mut repl = REPL::new();
+        repl.execute_source("inc $0\n".to_string());
+        repl.execute_source("inc $1\n".to_string());
+        assert_eq!(repl.vm.registers[0], 1);
+        assert_eq!(repl.vm.registers[1], 1);
+    }
+
+    #[test]
+    fn test_errors_do_not_change_program() {
+        let mut repl = REPL::new();
+        repl.execute_source("inc $0\n".to_string());
+        repl.execute_source("jmp @missing\n".to_string());
+        repl.execute_source("add #1 $0 $0\n".to_string());
+        assert_eq!(repl.vm.program.len(), 4);
+    }
+
+    #[test]
+    fn test_source_locations() {
+        let mut repl = REPL::new();
+        repl.execute_source("load $0 #2\n".to_string());
+        repl.execute_source("loop: dec $0\nneq $0 $1\njeq @loop\n".to_string());
+        repl.vm.program_counter = 4;
+        assert_eq!(
+            repl.current_line(),
+            Some("<repl>:2:7 (loop+0): loop: dec $0".to_string())
+        );
+        repl.vm.program_counter = 12;
+        assert_eq!(repl.describe_pc(), "pc 12 (<repl>:4:1 (loop+8))");
+        repl.execute_command(".reset");
+        assert_eq!(repl.describe_pc(), "pc 0");
+    }
+
+    #[test]
+    fn test_heap_command() {
+        let mut repl = REPL::new();
+        repl.execute_command(".heap limit 16");
+        repl.execute_command(".heap debug on");
+        assert_eq!(repl.vm.allocator.max_size, Some(16));
+        assert!(repl.vm.allocator.debug);
+        repl.execute_source("load $0 #8\nalloc $0\nload $1 #32\nalloc $1".to_string());
+        assert_eq!(repl.vm.allocator.stats.live_blocks, 1);
+        assert_eq!(repl.vm.allocator.stats.used, 8);
+        repl.execute_command(".reset");
+        assert_eq!(repl.vm.allocator.max_size, Some(16));
+        assert_eq!(repl.vm.allocator.stats.allocations, 0);
+        repl.execute_command(".heap limit none");
+        assert_eq!(repl.vm.allocator.max_size, None);
+    }
+
+    #[test]
+    fn test_gc_command() {
+        let mut repl = REPL::new();
+        repl.execute_source("new $0 $1\nnew $0 $1".to_string());
+        assert_eq!(repl.vm.managed.stats.live_objects, 2);
+        repl.execute_command(".gc collect");
+        assert_eq!(repl.vm.managed.stats.live_objects, 1);
+        assert_eq!(repl.vm.managed.stats.collections, 1);
+    }
+
+    #[test]
+    fn test_hex_input() {
+        let mut repl = REPL::new();
+        repl.execute_hex("01 00 00 05");
+        repl.execute_hex("0a 00 00 00");
+        assert_eq!(repl.vm.registers[0], 6);
+        repl.execute_source("loop: dec $0\n".to_string());
+        assert_eq!(
+            repl.assembler.symbol_table().get_symbol_value("loop"),
+            Some(8)
+        );
+    }
+
+    #[test]
+    fn test_hex_input_is_verified() {
+        let mut repl = REPL::new();
+        repl.execute_hex("0a 40 00 00");
+        repl.execute_hex("0a 00");
+        repl.execute_hex("zz");
+        assert!(repl.vm.program.is_empty());
+    }
+
+    // A file in the temp directory only this test of this process uses, gone
+    // once the test is over, whether it passed or not.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(test: &str, extension: &str) -> TempFile {
+            let name = format!("rpd_repl_{}_{}.{}", process::id(), test, extension);
+            TempFile(std::env::temp_dir().join(name))
+        }
+
+        fn to_str(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_save_and_load_source() {
+        let path = TempFile::new("save_and_load_source", "rpd");
+        let file = path.to_str();
+        let mut repl = REPL::new();
+        repl.execute_source("loop: inc $0\n".to_string());
+        repl.execute_command(&format!(".save {}", file));
+        repl.execute_command(".reset");
+        assert!(repl.vm.program.is_empty());
+        assert_eq!(repl.vm.registers[0], 0);
+        repl.execute_command(&format!(".load {}", file));
+        assert_eq!(repl.vm.registers[0], 1);
+        assert_eq!(
+            repl.assembler.symbol_table().get_symbol_value("loop"),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn test_save_and_load_bytecode() {
+        let path = TempFile::new("save_and_load_bytecode", "rpdc");
+        let file = path.to_str();
+        let mut repl = REPL::new();
+        repl.execute_source("load $0 #1\njmp @skip\ninc $0\nskip: hlt\n".to_string());
+        repl.execute_command(&format!(".save {}", file));
+        repl.execute_command(".reset");
+        repl.execute_source("inc $1\n".to_string());
+        repl.execute_command(&format!(".load {}", file));
+        assert_eq!(repl.vm.registers[0], 1);
+        assert_eq!(repl.vm.registers[1], 1);
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let mut repl = REPL::new();
+        repl.execute_source("inc $0\n".to_string());
+        repl.execute_command(".snapshot");
+        repl.execute_source("a: inc $0\n".to_string());
+        assert_eq!(repl.vm.registers[0], 2);
+        repl.execute_command(".restore");
+        assert_eq!(repl.vm.registers[0], 1);
+        assert_eq!(repl.vm.program.len(), 4);
+        assert!(!repl.assembler.symbol_table().has_symbol("a"));
+    }
+
+    #[test]
+    fn test_snapshot_to_file() {
+        let path = TempFile::new("snapshot_to_file", "state");
+        let file = path.to_str();
+        let mut repl = REPL::new();
+        repl.execute_source("load $0 #5\n".to_string());
+        repl.execute_command(&format!(".snapshot {}", file));
+        repl.execute_command(".reset");
+        repl.execute_command(&format!(".restore {}", file));
+        assert_eq!(repl.vm.registers[0], 5);
+        repl.execute_source("a: inc $0\n".to_string());
+        assert_eq!(repl.vm.registers[0], 6);
+        assert_eq!(repl.assembler.symbol_table().get_symbol_value("a"), Some(4));
+    }
+
+    #[test]
+    fn test_restore_rejects_corrupt_snapshot() {
+        let path = TempFile::new("restore_rejects_corrupt_snapshot", "state");
+        fs::write(&path.0, state::save(&VM::new_with_program(vec![1, 0]))).unwrap();
+        let mut repl = REPL::new();
+        repl.execute_source("inc $0\n".to_string());
+        repl.execute_command(&format!(".restore {}", path.to_str()));
+        assert_eq!(repl.vm.program, vec![10, 0, 0, 0]);
+        assert_eq!(repl.vm.registers[0], 1);
+        assert!(repl.vm.source_map.is_some());
+    }
+
+    #[test]
+    fn test_restore_compact_snapshot() {
+        let path = TempFile::new("restore_compact_snapshot", "state");
+        let file = path.to_str();
+        let mut assembler = Assembler::new("load $0 #5\ninc $0\ninc $0\n".to_string());
+        assembler.encoding = Encoding::Compact;
+        let mut vm = VM::new_with_program(assembler.assemble().unwrap());
+        vm.encoding = Encoding::Compact;
+        vm.budget.max_instructions = Some(2);
+        vm.run();
+        fs::write(&path.0, state::save(&vm)).unwrap();
+
+        let mut repl = REPL::new();
+        repl.execute_command(&format!(".restore {}", file));
+        assert_eq!(repl.vm.encoding, Encoding::Fixed);
+        assert_eq!(repl.vm.program.len(), 12);
+        assert_eq!(repl.vm.program_counter, 8);
+        assert_eq!(repl.vm.registers[0], 6);
+        repl.execute_source("inc $0\n".to_string());
+        assert_eq!(repl.vm.registers[0], 7);
+        assert_eq!(repl.vm.program_counter, 16);
     }
 }
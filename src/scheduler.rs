@@ -0,0 +1,427 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use crate::coverage::Coverage;
+use crate::gc::ManagedHeap;
+use crate::heap::Allocator;
+use crate::instruction::REGISTER_COUNT;
+use crate::profile::Profile;
+use crate::vm::{Fault, RunStatus, VM};
+
+// What a process asks of the scheduler, see `RunStatus::Request`. Registers
+// are the ones to write the answer to, everything else is read when the
+// instruction runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Request {
+    Spawn { register: usize, target: usize },
+    Yield,
+    Join { register: usize },
+    Channel { register: usize },
+    Send { channel: i64, value: i64 },
+    Receive { register: usize, channel: i64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProcessState {
+    Ready,
+    // Waiting for another process or a message, the request is tried again
+    // on every turn.
+    Blocked(Request),
+    Finished(RunStatus),
+}
+
+#[derive(Debug)]
+pub struct Process {
+    pub vm: VM,
+    pub state: ProcessState,
+    // The budget of the VM covers the process from its creation, not each
+    // turn: when its time is up and how many instructions it had run then.
+    deadline: Option<Instant>,
+    start: u64,
+}
+
+impl Process {
+    pub fn new(vm: VM) -> Process {
+        Process {
+            deadline: vm.budget.timeout.map(|timeout| Instant::now() + timeout),
+            start: vm.instructions,
+            vm,
+            state: ProcessState::Ready,
+        }
+    }
+}
+
+// Runs rpd processes as green threads in one host. Every process is a VM of
+// its own with its own registers, program counter and heaps, sharing only the
+// program. They talk over channels, unbounded queues of register values.
+//
+// Process 0 is the VM the scheduler was made with. Like `main` in most
+// languages, the run is over once it finishes, whatever the others are doing.
+// The budget of a process covers all of its turns, `fuel` all processes.
+#[derive(Debug)]
+pub struct Scheduler {
+    // Every process by its id, finished ones included so they can be joined.
+    pub processes: Vec<Process>,
+    // Instructions a process runs before the next one gets a turn. `None`
+    // runs every process until it yields or waits.
+    pub quantum: Option<u64>,
+    // Instructions all processes together may run before `run` gives up with
+    // `InstructionLimit`.
+    pub fuel: Option<u64>,
+    // Instructions all processes ran so far.
+    pub instructions: u64,
+    channels: Vec<VecDeque<i64>>,
+    queue: VecDeque<usize>,
+}
+
+// What a turn of a process came to.
+enum Turn {
+    Progress,
+    Waiting,
+    Stop(RunStatus),
+}
+
+impl Scheduler {
+    pub fn new(vm: VM) -> Scheduler {
+        Scheduler {
+            processes: vec![Process::new(vm)],
+            quantum: None,
+            fuel: None,
+            instructions: 0,
+            channels: Vec::new(),
+            queue: VecDeque::from([0]),
+        }
+    }
+
+    // Process 0, to look at or keep running after the scheduler is done.
+    pub fn into_main(self) -> VM {
+        self.processes.into_iter().next().unwrap().vm
+    }
+
+    // Runs processes round robin until process 0 finishes and returns how it
+    // did. Returns early when a process is interrupted or out of time, and
+    // with `Deadlock` when every process left is waiting. What the profiles
+    // and coverage of the other processes collected ends up in process 0's.
+    pub fn run(&mut self) -> RunStatus {
+        let status = self.schedule();
+        self.gather_observations();
+        status
+    }
+
+    fn schedule(&mut self) -> RunStatus {
+        // Turns in a row in which no process got anywhere.
+        let mut waiting = 0;
+        while let Some(id) = self.queue.pop_front() {
+            let before = self.processes[id].vm.instructions;
+            let turn = self.turn(id);
+            self.instructions += self.processes[id].vm.instructions - before;
+            match self.processes[id].state {
+                ProcessState::Finished(status) if id == 0 => return status,
+                ProcessState::Finished(_) => {}
+                _ => self.queue.push_back(id),
+            }
+            match turn {
+                Turn::Progress => waiting = 0,
+                Turn::Waiting => waiting += 1,
+                Turn::Stop(status) => return status,
+            }
+            if waiting >= self.queue.len() {
+                return RunStatus::Deadlock;
+            }
+        }
+        match self.processes[0].state {
+            ProcessState::Finished(status) => status,
+            _ => RunStatus::Deadlock,
+        }
+    }
+
+    // Moves what every other process profiled and covered into process 0, so
+    // the next run doesn't count it again.
+    fn gather_observations(&mut self) {
+        let (main, others) = self.processes.split_at_mut(1);
+        let main = &mut main[0].vm;
+        for process in others {
+            if let (Some(profile), Some(other)) = (&mut main.profile, &mut process.vm.profile) {
+                profile.merge(&std::mem::take(other));
+            }
+            if let (Some(coverage), Some(other)) = (&mut main.coverage, &mut process.vm.coverage) {
+                coverage.merge(&std::mem::take(other));
+            }
+        }
+    }
+
+    fn turn(&mut self, id: usize) -> Turn {
+        if let ProcessState::Blocked(request) = self.processes[id].state {
+            if !self.serve(id, request) {
+                return Turn::Waiting;
+            }
+        }
+        let process = &mut self.processes[id];
+        let vm = &mut process.vm;
+        // The turn ends at whichever comes first of what is left of the budget
+        // of the process, the quantum and the fuel left. Only the quantum lets
+        // it go on later.
+        let budget = vm.budget;
+        let left = budget
+            .max_instructions
+            .map(|max| max.saturating_sub(vm.instructions - process.start));
+        let fuel = self.fuel.map(|fuel| fuel.saturating_sub(self.instructions));
+        vm.budget.max_instructions = [left, self.quantum, fuel].into_iter().flatten().min();
+        vm.budget.timeout = process
+            .deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let before = vm.instructions;
+        let status = vm.run();
+        let ran = Some(vm.instructions - before);
+        vm.budget = budget;
+        match status {
+            RunStatus::Request(request) => {
+                self.serve(id, request);
+            }
+            RunStatus::InstructionLimit if ran != left && ran != fuel => {}
+            status
+            @ (RunStatus::Interrupted | RunStatus::Timeout | RunStatus::InstructionLimit) => {
+                return Turn::Stop(status);
+            }
+            status => self.processes[id].state = ProcessState::Finished(status),
+        }
+        Turn::Progress
+    }
+
+    // Carries out a request of process `id`. False if it has to wait.
+    fn serve(&mut self, id: usize, request: Request) -> bool {
+        let result = match request {
+            Request::Spawn { register, target } => {
+                let child = self.processes.len();
+                let parent = &mut self.processes[id].vm;
+                let vm = spawn_vm(parent, target, parent.registers[register]);
+                parent.registers[register] = child as i64;
+                self.processes.push(Process::new(vm));
+                self.queue.push_back(child);
+                Ok(true)
+            }
+            Request::Yield => Ok(true),
+            Request::Join { register } => {
+                let other = self.processes[id].vm.registers[register];
+                match usize::try_from(other).ok().filter(|other| *other != id) {
+                    Some(other) if other < self.processes.len() => {
+                        match self.processes[other].state {
+                            ProcessState::Finished(status) => {
+                                self.processes[id].vm.registers[register] = exit_status(status);
+                                Ok(true)
+                            }
+                            _ => Ok(false),
+                        }
+                    }
+                    _ => Err(Fault::InvalidProcess(other)),
+                }
+            }
+            Request::Channel { register } => {
+                self.channels.push(VecDeque::new());
+                self.processes[id].vm.registers[register] = self.channels.len() as i64 - 1;
+                Ok(true)
+            }
+            Request::Send { channel, value } => match self.channel(channel) {
+                Some(queue) => {
+                    queue.push_back(value);
+                    Ok(true)
+                }
+                None => Err(Fault::InvalidChannel(channel)),
+            },
+            Request::Receive { register, channel } => match self.channel(channel) {
+                Some(queue) => match queue.pop_front() {
+                    Some(value) => {
+                        self.processes[id].vm.registers[register] = value;
+                        Ok(true)
+                    }
+                    None => Ok(false),
+                },
+                None => Err(Fault::InvalidChannel(channel)),
+            },
+        };
+        let process = &mut self.processes[id];
+        match result {
+            Ok(true) => {
+                process.vm.step_over();
+                process.state = ProcessState::Ready;
+                true
+            }
+            Ok(false) => {
+                process.state = ProcessState::Blocked(request);
+                false
+            }
+            Err(fault) => {
+                process.state = ProcessState::Finished(RunStatus::Fault(fault));
+                true
+            }
+        }
+    }
+
+    fn channel(&mut self, channel: i64) -> Option<&mut VecDeque<i64>> {
+        usize::try_from(channel)
+            .ok()
+            .and_then(|channel| self.channels.get_mut(channel))
+    }
+}
+
+// A fresh process running the parent's program from `target` with `argument`
+// in $0. Settings like the encoding, width, budget and heap limits carry over,
+// observers don't.
+fn spawn_vm(parent: &VM, target: usize, argument: i64) -> VM {
+    let mut vm = parent.clone();
+    vm.registers = [0; REGISTER_COUNT];
+    vm.registers[0] = argument;
+    vm.program_counter = target;
+    vm.remainder = 0;
+    vm.equality_flag = false;
+    vm.heap.clear();
+    vm.allocator = Allocator {
+        max_size: parent.allocator.max_size,
+        debug: parent.allocator.debug,
+        ..Allocator::new()
+    };
+    vm.managed = ManagedHeap::new();
+    vm.instructions = 0;
+    // A child traces to the same sink as its parent and counts from scratch,
+    // `Scheduler::run` adds its counts to process 0's.
+    vm.profile = parent.profile.as_ref().map(|_| Profile::new());
+    vm.coverage = parent.coverage.as_ref().map(|_| Coverage::new());
+    vm
+}
+
+// What `join` hands back for a finished process: the status of its `exit`, 0
+// if it stopped normally and -1 if it faulted.
+pub fn exit_status(status: RunStatus) -> i64 {
+    match status {
+        RunStatus::Halted(code) => code as i64,
+        RunStatus::Fault(_) => -1,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::instruction::Opcode;
+    use std::time::Duration;
+
+    fn start(source: &str) -> Scheduler {
+        let program = Assembler::new(source.to_string()).assemble().unwrap();
+        Scheduler::new(VM::new_with_program(program))
+    }
+
+    #[test]
+    fn test_spawn_and_join() {
+        let mut scheduler =
+            start("load $1 #20\nspawn $1 @child\njoin $1\nhlt\nchild:\ninc $0\ninc $0\nexit #7\n");
+        assert_eq!(scheduler.run(), RunStatus::Halted(0));
+        assert_eq!(scheduler.processes.len(), 2);
+        assert_eq!(scheduler.processes[0].vm.registers[1], 7);
+        assert_eq!(scheduler.processes[1].vm.registers[0], 22);
+        assert_eq!(
+            scheduler.processes[1].state,
+            ProcessState::Finished(RunStatus::Halted(7))
+        );
+    }
+
+    #[test]
+    fn test_channels() {
+        // The child doubles every number it receives and sends it back.
+        let mut scheduler = start(
+            "chan $0\nchan $1\nload $5 #0\nspawn $5 @child\nsend $0 $1\nload $2 #21\nsend $0 $2\nrecv $3 $1\nhlt\n\
+             child:\nrecv $1 $0\nrecv $2 $0\nadd $2 $2 $2\nsend $1 $2\n",
+        );
+        assert_eq!(scheduler.run(), RunStatus::Halted(0));
+        assert_eq!(scheduler.processes[0].vm.registers[3], 42);
+    }
+
+    #[test]
+    fn test_preemption() {
+        // Without a quantum the loop never gives the main process its turn back.
+        let source = "spawn $0 @other\nyield\nhlt\nother:\ninc $1\njmp @other\n";
+        let mut preemptive = start(source);
+        preemptive.processes[0].vm.budget.max_instructions = Some(1000);
+        preemptive.quantum = Some(10);
+        assert_eq!(preemptive.run(), RunStatus::Halted(0));
+        assert_eq!(
+            preemptive.processes[0].vm.budget.max_instructions,
+            Some(1000)
+        );
+
+        let mut cooperative = start(source);
+        cooperative.processes[0].vm.budget.max_instructions = Some(1000);
+        assert_eq!(cooperative.run(), RunStatus::InstructionLimit);
+    }
+
+    #[test]
+    fn test_budget_spans_turns() {
+        // The main process spins forever, a few instructions per turn.
+        let mut scheduler = start("spawn $0 @other\nloop:\njmp @loop\nother:\nyield\n");
+        scheduler.quantum = Some(7);
+        scheduler.processes[0].vm.budget.max_instructions = Some(100);
+        assert_eq!(scheduler.run(), RunStatus::InstructionLimit);
+        assert_eq!(scheduler.processes[0].vm.instructions, 100);
+
+        let source = "spawn $0 @other\nloop:\njmp @loop\nother:\njmp @other\n";
+        let mut vm = VM::new_with_program(Assembler::new(source.to_string()).assemble().unwrap());
+        vm.budget.timeout = Some(Duration::from_millis(20));
+        let mut scheduler = Scheduler::new(vm);
+        scheduler.quantum = Some(7);
+        assert_eq!(scheduler.run(), RunStatus::Timeout);
+    }
+
+    #[test]
+    fn test_children_are_observed() {
+        let mut scheduler = start("spawn $0 @child\njoin $0\nhlt\nchild:\ninc $0\ninc $0\n");
+        scheduler.processes[0].vm.profile = Some(Profile::new());
+        scheduler.processes[0].vm.coverage = Some(Coverage::new());
+        scheduler.run();
+        let vm = scheduler.into_main();
+        let profile = vm.profile.unwrap();
+        assert_eq!(profile.instructions, 5);
+        assert_eq!(profile.by_opcode[&u8::from(Opcode::INC)], 2);
+        let coverage = vm.coverage.unwrap();
+        assert_eq!(coverage.hits_at(12), 1);
+        assert_eq!(coverage.hits_at(16), 1);
+    }
+
+    #[test]
+    fn test_fuel() {
+        // Fuel covers every process, yielding doesn't get around it.
+        let mut scheduler = start("spawn $0 @other\nloop:\nyield\njmp @loop\nother:\njmp @other\n");
+        scheduler.fuel = Some(100);
+        scheduler.quantum = Some(7);
+        assert_eq!(scheduler.run(), RunStatus::InstructionLimit);
+        assert_eq!(scheduler.instructions, 100);
+
+        let mut scheduler = start("load $0 #3\nloop:\ndec $0\nyield\nneq $0 $1\njeq @loop\n");
+        scheduler.fuel = Some(100);
+        assert_eq!(scheduler.run(), RunStatus::Done);
+        assert_eq!(scheduler.instructions, 13);
+    }
+
+    #[test]
+    fn test_yield() {
+        let mut scheduler =
+            start("spawn $0 @other\nyield\nhlt\nother:\nload $2 #1\nloop:\nyield\njmp @loop\n");
+        assert_eq!(scheduler.run(), RunStatus::Halted(0));
+        assert_eq!(scheduler.processes[1].vm.registers[2], 1);
+    }
+
+    #[test]
+    fn test_deadlock_and_faults() {
+        let mut scheduler = start("chan $0\nrecv $1 $0\n");
+        assert_eq!(scheduler.run(), RunStatus::Deadlock);
+        assert_eq!(scheduler.processes[0].vm.program_counter, 4);
+
+        let mut scheduler = start("load $0 #3\njoin $0\n");
+        assert_eq!(scheduler.run(), RunStatus::Fault(Fault::InvalidProcess(3)));
+        assert_eq!(scheduler.processes[0].vm.program_counter, 4);
+
+        // A faulting child doesn't take the others down.
+        let mut scheduler = start("spawn $0 @child\njoin $0\nhlt\nchild:\nsend $0 $0\n");
+        assert_eq!(scheduler.run(), RunStatus::Halted(0));
+        assert_eq!(scheduler.processes[0].vm.registers[0], -1);
+    }
+}
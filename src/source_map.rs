@@ -0,0 +1,171 @@
+use std::fmt::Display;
+
+use crate::assembler::lexer::token::Span;
+use crate::assembler::Listing;
+use crate::reader::Reader;
+
+// Where a bytecode offset came from in the assembly source.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SourceLocation<'a> {
+    pub file: &'a str,
+    pub line: usize,
+    pub column: usize,
+    // The closest label before the instruction and how many bytes past it it is.
+    pub label: Option<(&'a str, usize)>,
+}
+
+impl Display for SourceLocation<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)?;
+        if let Some((label, distance)) = self.label {
+            write!(f, " ({}+{})", label, distance)?;
+        }
+        Ok(())
+    }
+}
+
+// Maps bytecode offsets back to the file, line and column they were assembled
+// from. It can travel inside a bytecode file, see `bytecode::encode`.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct SourceMap {
+    pub file: String,
+    pub listing: Listing,
+}
+
+impl SourceMap {
+    pub fn new(file: &str, listing: Listing) -> SourceMap {
+        SourceMap {
+            file: file.to_string(),
+            listing,
+        }
+    }
+
+    // The location of the instruction that contains `offset`.
+    pub fn lookup(&self, offset: usize) -> Option<SourceLocation<'_>> {
+        let instructions = &self.listing.instructions;
+        let index = instructions.partition_point(|(start, _)| *start <= offset);
+        if index == 0 {
+            return None;
+        }
+        let (start, span) = instructions[index - 1];
+        Some(SourceLocation {
+            file: &self.file,
+            line: span.line,
+            column: span.column,
+            label: self.listing.label_at(start),
+        })
+    }
+
+    // Follows the program through a transformation that moved or removed
+    // instructions, `offsets` pairing the old offset of every instruction with
+    // its new one (see `optimizer::Optimized`). Labels move to the next
+    // surviving instruction.
+    pub fn relocate(&self, offsets: &[(usize, Option<usize>)]) -> SourceMap {
+        let new_offset = |offset: usize| {
+            offsets
+                .binary_search_by_key(&offset, |(old, _)| *old)
+                .ok()
+                .and_then(|index| offsets[index].1)
+        };
+        let mut listing = Listing::default();
+        for (offset, span) in &self.listing.instructions {
+            if let Some(offset) = new_offset(*offset) {
+                listing.instructions.push((offset, *span));
+            }
+        }
+        for (name, offset) in &self.listing.labels {
+            let moved = offsets[offsets.partition_point(|(old, _)| old < offset)..]
+                .iter()
+                .find_map(|(_, new)| *new)
+                .unwrap_or(0);
+            listing.labels.push((name.clone(), moved));
+        }
+        SourceMap::new(&self.file, listing)
+    }
+
+    //   file name | instruction count u32 | (offset u32, line u32, column u32) *
+    //   | label count u32 | (name, offset u32) *
+    // where every string is a u32 length followed by UTF-8 bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![];
+        push_string(&mut bytes, &self.file);
+        bytes.extend_from_slice(&(self.listing.instructions.len() as u32).to_be_bytes());
+        for (offset, span) in &self.listing.instructions {
+            bytes.extend_from_slice(&(*offset as u32).to_be_bytes());
+            bytes.extend_from_slice(&(span.line as u32).to_be_bytes());
+            bytes.extend_from_slice(&(span.column as u32).to_be_bytes());
+        }
+        bytes.extend_from_slice(&(self.listing.labels.len() as u32).to_be_bytes());
+        for (name, offset) in &self.listing.labels {
+            push_string(&mut bytes, name);
+            bytes.extend_from_slice(&(*offset as u32).to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<SourceMap, String> {
+        let mut reader = Reader::new(bytes, "source map");
+        let file = reader.read_string()?;
+        let mut listing = Listing::default();
+        for _ in 0..reader.read_u32()? {
+            let offset = reader.read_u32()? as usize;
+            let line = reader.read_u32()? as usize;
+            let column = reader.read_u32()? as usize;
+            listing.instructions.push((offset, Span { line, column }));
+        }
+        for _ in 0..reader.read_u32()? {
+            let name = reader.read_string()?;
+            listing.labels.push((name, reader.read_u32()? as usize));
+        }
+        if !reader.is_at_end() {
+            return Err("trailing bytes after source map".to_string());
+        }
+        Ok(SourceMap { file, listing })
+    }
+}
+
+fn push_string(bytes: &mut Vec<u8>, value: &str) {
+    bytes.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(value.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn source_map() -> SourceMap {
+        let mut assembler = Assembler::new("inc $0\nloop:\n  dec $0\njmp @loop\n".to_string());
+        assert!(assembler.assemble().is_ok());
+        SourceMap::new("loop.rpd", assembler.listing().clone())
+    }
+
+    #[test]
+    fn test_lookup() {
+        let source_map = source_map();
+        assert_eq!(source_map.lookup(0).unwrap().to_string(), "loop.rpd:1:1");
+        assert_eq!(
+            source_map.lookup(5).unwrap().to_string(),
+            "loop.rpd:3:3 (loop+0)"
+        );
+        assert_eq!(source_map.lookup(8).unwrap().label, Some(("loop", 4)));
+    }
+
+    #[test]
+    fn test_relocate() {
+        let relocated =
+            source_map().relocate(&[(0, Some(0)), (4, None), (8, Some(4)), (12, Some(8))]);
+        assert_eq!(relocated.lookup(0).unwrap().line, 1);
+        assert_eq!(
+            relocated.lookup(4).unwrap().to_string(),
+            "loop.rpd:4:1 (loop+0)"
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let source_map = source_map();
+        assert_eq!(SourceMap::decode(&source_map.encode()), Ok(source_map));
+        assert!(SourceMap::decode(&[0, 0, 0, 9, b'a']).is_err());
+    }
+}
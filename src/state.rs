@@ -0,0 +1,397 @@
+use std::collections::BTreeMap;
+
+use crate::decoder;
+use crate::gc::{ManagedHeap, Object, MAX_FIELDS, MAX_OBJECTS};
+use crate::heap::{Allocator, Block, BlockState};
+use crate::instruction::{Encoding, Width, REGISTER_COUNT};
+use crate::reader::Reader;
+use crate::verifier;
+use crate::vm::VM;
+
+// Binary format for the full machine state of a `VM`, so a paused program can
+// be written to disk and resumed later. Everything is big endian like the
+// rest of the VM. Bump `VERSION` whenever the layout changes.
+//
+//   magic "RPDS" | version u8 | register width u8 (32 or 64)
+//   | encoding u8 (0 fixed, 1 compact)
+//   | register count u8 | registers i64 * count
+//   | program counter u64 | remainder i64 | equality flag u8
+//   | program length u64 | program | read-only length u64 | read-only data
+//   | heap length u64 | heap
+//   | block count u64 | (block start u64 | block size u64 | block state u8) * count
+//   | object slot count u64 | (live u8 | [field count u64 | fields i64 * count]) * slots
+//   | stack depth u64 | stack i64 * depth
+//
+// Block states are 0 live, 1 free and 2 quarantined. Free object slots are a
+// single 0 byte.
+pub const MAGIC: &[u8; 4] = b"RPDS";
+pub const VERSION: u8 = 2;
+
+pub fn save(vm: &VM) -> Vec<u8> {
+    let mut bytes: Vec<u8> = vec![];
+    bytes.extend_from_slice(MAGIC);
+    bytes.push(VERSION);
+    bytes.push(vm.width.bits() as u8);
+    bytes.push(match vm.encoding {
+        Encoding::Fixed => 0,
+        Encoding::Compact => 1,
+    });
+    bytes.push(REGISTER_COUNT as u8);
+    for register in vm.registers {
+        bytes.extend_from_slice(&register.to_be_bytes());
+    }
+    bytes.extend_from_slice(&(vm.program_counter as u64).to_be_bytes());
+    bytes.extend_from_slice(&vm.remainder.to_be_bytes());
+    bytes.push(vm.equality_flag as u8);
+    bytes.extend_from_slice(&(vm.program.len() as u64).to_be_bytes());
+    bytes.extend_from_slice(&vm.program);
+    bytes.extend_from_slice(&(vm.read_only.len() as u64).to_be_bytes());
+    bytes.extend_from_slice(&vm.read_only);
+    bytes.extend_from_slice(&(vm.heap.len() as u64).to_be_bytes());
+    bytes.extend_from_slice(&vm.heap);
+    bytes.extend_from_slice(&(vm.allocator.blocks.len() as u64).to_be_bytes());
+    for (start, block) in &vm.allocator.blocks {
+        bytes.extend_from_slice(&(*start as u64).to_be_bytes());
+        bytes.extend_from_slice(&(block.size as u64).to_be_bytes());
+        bytes.push(match block.state {
+            BlockState::Live => 0,
+            BlockState::Free => 1,
+            BlockState::Quarantined => 2,
+        });
+    }
+    bytes.extend_from_slice(&(vm.managed.objects.len() as u64).to_be_bytes());
+    for slot in &vm.managed.objects {
+        match slot {
+            Some(object) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&(object.fields.len() as u64).to_be_bytes());
+                for field in &object.fields {
+                    bytes.extend_from_slice(&field.to_be_bytes());
+                }
+            }
+            None => bytes.push(0),
+        }
+    }
+    bytes.extend_from_slice(&(vm.managed.stack.len() as u64).to_be_bytes());
+    for value in &vm.managed.stack {
+        bytes.extend_from_slice(&value.to_be_bytes());
+    }
+    bytes
+}
+
+// Replaces the machine state of `vm` with a saved one. The VM's budget and
+// interrupt handle are left alone, its source map is dropped since it
+// belonged to the old program. The program has to pass the verifier and the
+// program counter has to be on one of its instructions (or at its end), so a
+// corrupt state can't make the VM misbehave once resumed. On error the VM is
+// not modified.
+pub fn restore(vm: &mut VM, bytes: &[u8]) -> Result<(), String> {
+    let mut reader = Reader::new(bytes, "state");
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err("not an rpd state file".to_string());
+    }
+    let version = reader.read_u8()?;
+    if version != VERSION {
+        return Err(format!("unsupported state version {}", version));
+    }
+    let width = match reader.read_u8()? {
+        32 => Width::W32,
+        64 => Width::W64,
+        bits => return Err(format!("unsupported register width {}", bits)),
+    };
+    let encoding = match reader.read_u8()? {
+        0 => Encoding::Fixed,
+        1 => Encoding::Compact,
+        encoding => return Err(format!("unknown instruction encoding {}", encoding)),
+    };
+    let register_count = reader.read_u8()? as usize;
+    if register_count != REGISTER_COUNT {
+        return Err(format!(
+            "state has {} registers, this VM has {}",
+            register_count, REGISTER_COUNT
+        ));
+    }
+    let mut registers = [0; REGISTER_COUNT];
+    for register in registers.iter_mut() {
+        *register = reader.read_i64()?;
+    }
+    let program_counter = reader.read_u64()? as usize;
+    let remainder = reader.read_i64()?;
+    let equality_flag = reader.read_u8()? != 0;
+    let program_len = reader.read_u64()? as usize;
+    let program = reader.take(program_len)?.to_vec();
+    check_program(&program, encoding, program_counter)?;
+    let read_only_len = reader.read_u64()? as usize;
+    let read_only = reader.take(read_only_len)?.to_vec();
+    let heap_len = reader.read_u64()? as usize;
+    let heap = reader.take(heap_len)?.to_vec();
+    let mut allocator = read_blocks(&mut reader, &heap)?;
+    let managed = read_managed(&mut reader)?;
+    if !reader.is_at_end() {
+        return Err("trailing bytes after state".to_string());
+    }
+
+    vm.width = width;
+    vm.encoding = encoding;
+    vm.registers = registers;
+    vm.program_counter = program_counter;
+    vm.remainder = remainder;
+    vm.equality_flag = equality_flag;
+    vm.program = program;
+    vm.read_only = read_only;
+    vm.heap = heap;
+    allocator.max_size = vm.allocator.max_size;
+    allocator.debug = vm.allocator.debug;
+    vm.allocator = allocator;
+    vm.managed = managed;
+    vm.source_map = None;
+    Ok(())
+}
+
+fn check_program(program: &[u8], encoding: Encoding, program_counter: usize) -> Result<(), String> {
+    if let Err(errors) = verifier::verify(program, encoding) {
+        return Err(format!(
+            "invalid program: {} at offset {}",
+            errors[0].message(),
+            errors[0].offset()
+        ));
+    }
+    // A verified program always splits into whole instructions.
+    let boundaries = decoder::boundaries(program, encoding).unwrap_or_default();
+    if boundaries.binary_search(&program_counter).is_err() {
+        return Err(format!(
+            "program counter {} is not on an instruction",
+            program_counter
+        ));
+    }
+    Ok(())
+}
+
+fn read_blocks(reader: &mut Reader, heap: &[u8]) -> Result<Allocator, String> {
+    let count = reader.read_u64()?;
+    let mut blocks = BTreeMap::new();
+    for _ in 0..count {
+        let start = reader.read_u64()? as usize;
+        let size = reader.read_u64()? as usize;
+        let state = match reader.read_u8()? {
+            0 => BlockState::Live,
+            1 => BlockState::Free,
+            2 => BlockState::Quarantined,
+            state => return Err(format!("unknown heap block state {}", state)),
+        };
+        blocks.insert(start, Block { size, state });
+    }
+    Allocator::with_blocks(heap, blocks)
+        .ok_or_else(|| "heap blocks do not cover the heap".to_string())
+}
+
+fn read_managed(reader: &mut Reader) -> Result<ManagedHeap, String> {
+    let slot_count = reader.read_u64()?;
+    if slot_count > MAX_OBJECTS as u64 {
+        return Err(format!("{} object slots is more than allowed", slot_count));
+    }
+    let mut objects = vec![];
+    for _ in 0..slot_count {
+        if reader.read_u8()? == 0 {
+            objects.push(None);
+            continue;
+        }
+        let field_count = reader.read_u64()?;
+        if field_count > MAX_FIELDS as u64 {
+            return Err(format!(
+                "an object with {} fields is too large",
+                field_count
+            ));
+        }
+        let mut fields = vec![];
+        for _ in 0..field_count {
+            fields.push(reader.read_i64()?);
+        }
+        objects.push(Some(Object::new(fields)));
+    }
+    let depth = reader.read_u64()?;
+    let mut stack = vec![];
+    for _ in 0..depth {
+        stack.push(reader.read_i64()?);
+    }
+    Ok(ManagedHeap::with_objects(objects, stack))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{Assembler, Listing};
+    use crate::source_map::SourceMap;
+    use crate::vm::RunStatus;
+
+    fn golden_vm() -> VM {
+        let mut vm = VM::new_with_program(vec![10, 1, 0, 0, 14, 0, 0, 0]);
+        vm.registers[1] = -2;
+        vm.program_counter = 4;
+        vm.remainder = 3;
+        vm.equality_flag = true;
+        vm.heap = vec![7, 8];
+        vm.read_only = b"a\0\0".to_vec();
+        let block = Block {
+            size: 2,
+            state: BlockState::Live,
+        };
+        vm.allocator = Allocator::with_blocks(&vm.heap, BTreeMap::from([(0, block)])).unwrap();
+        vm.managed = ManagedHeap::with_objects(
+            vec![Some(Object::new(vec![9])), None],
+            vec![crate::gc::reference(0)],
+        );
+        vm
+    }
+
+    #[test]
+    fn test_golden_state() {
+        let mut vm = golden_vm();
+        vm.width = Width::W64;
+        let bytes = save(&vm);
+        let mut expected: Vec<u8> = vec![b'R', b'P', b'D', b'S', 2, 64, 0, 32];
+        expected.extend_from_slice(&[0; 8]);
+        expected.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe]);
+        expected.extend_from_slice(&[0; 30 * 8]);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 4]);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 3]);
+        expected.push(1);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 8]);
+        expected.extend_from_slice(&[10, 1, 0, 0, 14, 0, 0, 0]);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 3]);
+        expected.extend_from_slice(b"a\0\0");
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 2]);
+        expected.extend_from_slice(&[7, 8]);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        expected.extend_from_slice(&[0; 8]);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 2]);
+        expected.push(0);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 2]);
+        expected.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 1]);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 9]);
+        expected.push(0);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0x40, 0, 0, 0]);
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn test_restores_managed_heap() {
+        let mut vm = VM::new();
+        let garbage = vm.managed.alloc(1, &[]).unwrap();
+        let kept = vm.managed.alloc(2, &[]).unwrap();
+        vm.managed.set(kept, 1, -3).unwrap();
+        vm.managed.push(kept);
+        vm.managed.collect(&[garbage + 1]);
+        let bytes = save(&vm);
+
+        let mut restored = VM::new();
+        assert!(restore(&mut restored, &bytes).is_ok());
+        assert_eq!(restored.managed.objects, vm.managed.objects);
+        assert_eq!(restored.managed.stack, vm.managed.stack);
+        assert_eq!(restored.managed.stats.live_objects, 1);
+        assert_eq!(restored.managed.alloc(0, &[]), Ok(crate::gc::reference(0)));
+    }
+
+    #[test]
+    fn test_restores_heap_blocks() {
+        let mut vm = VM::new();
+        vm.allocator.debug = true;
+        let a = vm.allocator.alloc(&mut vm.heap, 4).unwrap() as i64;
+        vm.allocator.alloc(&mut vm.heap, 4).unwrap();
+        vm.allocator.free(&mut vm.heap, a).unwrap();
+        let bytes = save(&vm);
+
+        let mut restored = VM::new();
+        assert!(restore(&mut restored, &bytes).is_ok());
+        assert_eq!(restored.allocator.blocks, vm.allocator.blocks);
+        assert_eq!(restored.allocator.stats.live_blocks, 1);
+        restored.allocator.debug = true;
+        assert!(restored.allocator.free(&mut restored.heap, a).is_err());
+    }
+
+    #[test]
+    fn test_restore_resumes_program() {
+        let mut paused = VM::new_with_program(vec![10, 0, 0, 0, 10, 0, 0, 0, 10, 0, 0, 0]);
+        paused.budget.max_instructions = Some(1);
+        assert_eq!(paused.run(), RunStatus::InstructionLimit);
+        let bytes = save(&paused);
+
+        let mut resumed = VM::new();
+        assert!(restore(&mut resumed, &bytes).is_ok());
+        assert_eq!(resumed.run(), RunStatus::Done);
+        assert_eq!(resumed.registers[0], 3);
+    }
+
+    #[test]
+    fn test_restores_compact_program() {
+        let mut assembler =
+            Assembler::new("load $0 #3\nloop:\ndec $0\nneq $0 $1\njeq @loop\n".to_string());
+        assembler.encoding = Encoding::Compact;
+        let mut paused = VM::new_with_program(assembler.assemble().unwrap());
+        paused.encoding = Encoding::Compact;
+        paused.budget.max_instructions = Some(2);
+        assert_eq!(paused.run(), RunStatus::InstructionLimit);
+        let bytes = save(&paused);
+
+        let mut resumed = VM::new();
+        assert!(restore(&mut resumed, &bytes).is_ok());
+        assert_eq!(resumed.encoding, Encoding::Compact);
+        assert_eq!(resumed.run(), RunStatus::Done);
+        assert_eq!(resumed.registers[0], 0);
+        assert_eq!(resumed.program_counter, paused.program.len());
+    }
+
+    #[test]
+    fn test_restore_rejects_bad_input() {
+        let mut vm = VM::new();
+        let bytes = save(&golden_vm());
+        assert!(restore(&mut vm, &bytes[..bytes.len() - 1]).is_err());
+        assert!(restore(&mut vm, b"RPDS\x02").is_err());
+        assert!(restore(&mut vm, b"RPDS\x02\x10").is_err());
+        assert!(restore(&mut vm, b"RPDS\x02\x20\x02").is_err());
+        assert!(restore(&mut vm, b"nope").is_err());
+        assert!(vm.program.is_empty());
+    }
+
+    #[test]
+    fn test_restore_rejects_bad_programs() {
+        let mut vm = VM::new_with_program(vec![10, 0, 0, 0]);
+        vm.source_map = Some(SourceMap::new("old.rpd", Listing::default()));
+        for (program, program_counter, error) in [
+            (
+                vec![1, 0],
+                0,
+                "invalid program: `load` is missing its operands at offset 0",
+            ),
+            (
+                vec![1, 200, 0, 0],
+                0,
+                "invalid program: register $200 is out of range at offset 1",
+            ),
+            (
+                vec![10, 0, 0, 0],
+                2,
+                "program counter 2 is not on an instruction",
+            ),
+            (
+                vec![10, 0, 0, 0],
+                8,
+                "program counter 8 is not on an instruction",
+            ),
+        ] {
+            let mut saved = VM::new_with_program(program);
+            saved.program_counter = program_counter;
+            assert_eq!(restore(&mut vm, &save(&saved)), Err(error.to_string()));
+            assert_eq!(vm.program, vec![10, 0, 0, 0]);
+            assert!(vm.source_map.is_some());
+        }
+
+        // Resuming at the end is fine, and the old source map goes away.
+        let mut saved = VM::new_with_program(vec![10, 0, 0, 0]);
+        saved.program_counter = 4;
+        assert_eq!(restore(&mut vm, &save(&saved)), Ok(()));
+        assert!(vm.source_map.is_none());
+        assert_eq!(vm.run(), RunStatus::Done);
+    }
+}
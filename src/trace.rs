@@ -0,0 +1,475 @@
+use std::fmt::{self, Write as _};
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+
+use crate::disassembler;
+use crate::instruction::{Opcode, REGISTER_COUNT};
+use crate::vm::VM;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TraceFormat {
+    Text,
+    Json,
+}
+
+// Limits which executed instructions end up in the trace. Empty means everything.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TraceFilter {
+    pub pc_range: Option<RangeInclusive<usize>>,
+    pub opcodes: Vec<Opcode>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, pc: usize, opcode: Opcode) -> bool {
+        if let Some(range) = &self.pc_range {
+            if !range.contains(&pc) {
+                return false;
+            }
+        }
+        self.opcodes.is_empty() || self.opcodes.contains(&opcode)
+    }
+}
+
+// The parts of the machine an instruction can change, captured before it runs
+// so the trace can show what it did. The heap is only copied for instructions
+// that write it.
+pub struct MachineState {
+    opcode: Opcode,
+    operands: [usize; 3],
+    registers: [i64; REGISTER_COUNT],
+    remainder: i64,
+    equality_flag: bool,
+    heap_len: usize,
+    heap: Option<Vec<u8>>,
+    objects_allocated: u64,
+    // The object and field a `setf` writes, with the value it holds now.
+    field: Option<(i64, i64, i64)>,
+}
+
+impl MachineState {
+    pub fn capture(vm: &VM, opcode: Opcode, operands: [usize; 3]) -> MachineState {
+        let field = match opcode {
+            Opcode::SETF => {
+                let object = vm.registers[operands[0]];
+                let index = vm.registers[operands[1]];
+                vm.managed
+                    .get(object, index)
+                    .ok()
+                    .map(|value| (object, index, value))
+            }
+            _ => None,
+        };
+        MachineState {
+            opcode,
+            operands,
+            registers: vm.registers,
+            remainder: vm.remainder,
+            equality_flag: vm.equality_flag,
+            heap_len: vm.heap.len(),
+            heap: writes_heap(opcode).then(|| vm.heap.clone()),
+            objects_allocated: vm.managed.stats.allocations,
+            field,
+        }
+    }
+}
+
+fn writes_heap(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::ALLOC
+            | Opcode::FREE
+            | Opcode::REALLOC
+            | Opcode::STRLOAD
+            | Opcode::STRCAT
+            | Opcode::ITOA
+    )
+}
+
+// One executed instruction and everything it changed.
+#[derive(Debug, PartialEq)]
+pub struct TraceEvent {
+    pub pc: usize,
+    pub instruction: String,
+    pub registers: Vec<(usize, i64, i64)>,
+    pub remainder: Option<(i64, i64)>,
+    pub equality_flag: Option<(bool, bool)>,
+    pub heap_len: Option<(usize, usize)>,
+    pub heap: Vec<(usize, u8, u8)>,
+    // A managed object created by `new`, with its number of fields.
+    pub object: Option<(i64, usize)>,
+    // Managed object fields written, as object, field index, old and new value.
+    pub fields: Vec<(i64, i64, i64, i64)>,
+    // Where the instruction came from, when the VM has a source map.
+    pub location: Option<String>,
+}
+
+impl TraceEvent {
+    pub fn new(pc: usize, program: &[u8], before: &MachineState, vm: &VM) -> TraceEvent {
+        let registers = (0..REGISTER_COUNT)
+            .filter(|index| before.registers[*index] != vm.registers[*index])
+            .map(|index| (index, before.registers[index], vm.registers[index]))
+            .collect();
+        let heap = match &before.heap {
+            Some(heap) => heap
+                .iter()
+                .zip(vm.heap.iter())
+                .enumerate()
+                .filter(|(_, (old, new))| old != new)
+                .map(|(address, (old, new))| (address, *old, *new))
+                .collect(),
+            None => vec![],
+        };
+        let object = match before.opcode {
+            Opcode::NEW if vm.managed.stats.allocations > before.objects_allocated => Some((
+                vm.registers[before.operands[0]],
+                before.registers[before.operands[1]] as usize,
+            )),
+            _ => None,
+        };
+        let fields = before
+            .field
+            .and_then(|(object, index, old)| {
+                let new = vm.managed.get(object, index).ok()?;
+                changed(old, new).map(|(old, new)| (object, index, old, new))
+            })
+            .into_iter()
+            .collect();
+        TraceEvent {
+            pc,
+            instruction: disassembler::disassemble_instruction(&program[pc..]),
+            registers,
+            remainder: changed(before.remainder, vm.remainder),
+            equality_flag: changed(before.equality_flag, vm.equality_flag),
+            heap_len: changed(before.heap_len, vm.heap.len()),
+            heap,
+            object,
+            fields,
+            location: vm.location(pc).map(|location| location.to_string()),
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut changes: Vec<String> = vec![];
+        for (register, old, new) in &self.registers {
+            changes.push(format!("${}: {} -> {}", register, old, new));
+        }
+        if let Some((old, new)) = self.remainder {
+            changes.push(format!("remainder: {} -> {}", old, new));
+        }
+        if let Some((old, new)) = self.equality_flag {
+            changes.push(format!("equality flag: {} -> {}", old, new));
+        }
+        if let Some((old, new)) = self.heap_len {
+            changes.push(format!("heap: {} -> {} bytes", old, new));
+        }
+        for (address, old, new) in &self.heap {
+            changes.push(format!("heap[{}]: {} -> {}", address, old, new));
+        }
+        if let Some((object, field_count)) = self.object {
+            changes.push(format!("object {}: new, {} fields", object, field_count));
+        }
+        for (object, index, old, new) in &self.fields {
+            changes.push(format!("object {}[{}]: {} -> {}", object, index, old, new));
+        }
+        let mut text = format!("{:04}: {}", self.pc, self.instruction);
+        if !changes.is_empty() {
+            text.push_str(" | ");
+            text.push_str(&changes.join(", "));
+        }
+        if let Some(location) = &self.location {
+            text.push_str("  ; ");
+            text.push_str(location);
+        }
+        text
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"pc\":{},\"instruction\":\"{}\"",
+            self.pc, self.instruction
+        );
+        if !self.registers.is_empty() {
+            let registers: Vec<String> = self
+                .registers
+                .iter()
+                .map(|(register, old, new)| {
+                    format!(
+                        "{{\"register\":{},\"old\":{},\"new\":{}}}",
+                        register, old, new
+                    )
+                })
+                .collect();
+            let _ = write!(json, ",\"registers\":[{}]", registers.join(","));
+        }
+        if let Some((old, new)) = self.remainder {
+            let _ = write!(json, ",\"remainder\":{{\"old\":{},\"new\":{}}}", old, new);
+        }
+        if let Some((old, new)) = self.equality_flag {
+            let _ = write!(
+                json,
+                ",\"equality_flag\":{{\"old\":{},\"new\":{}}}",
+                old, new
+            );
+        }
+        if let Some((old, new)) = self.heap_len {
+            let _ = write!(json, ",\"heap_len\":{{\"old\":{},\"new\":{}}}", old, new);
+        }
+        if !self.heap.is_empty() {
+            let heap: Vec<String> = self
+                .heap
+                .iter()
+                .map(|(address, old, new)| {
+                    format!(
+                        "{{\"address\":{},\"old\":{},\"new\":{}}}",
+                        address, old, new
+                    )
+                })
+                .collect();
+            let _ = write!(json, ",\"heap\":[{}]", heap.join(","));
+        }
+        if let Some((object, field_count)) = self.object {
+            let _ = write!(
+                json,
+                ",\"object\":{{\"object\":{},\"fields\":{}}}",
+                object, field_count
+            );
+        }
+        if !self.fields.is_empty() {
+            let fields: Vec<String> = self
+                .fields
+                .iter()
+                .map(|(object, index, old, new)| {
+                    format!(
+                        "{{\"object\":{},\"index\":{},\"old\":{},\"new\":{}}}",
+                        object, index, old, new
+                    )
+                })
+                .collect();
+            let _ = write!(json, ",\"fields\":[{}]", fields.join(","));
+        }
+        if let Some(location) = &self.location {
+            let _ = write!(json, ",\"location\":{}", json_string(location));
+        }
+        json.push('}');
+        json
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for ch in value.chars() {
+        match ch {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            ch if ch.is_control() => {
+                let _ = write!(json, "\\u{:04x}", ch as u32);
+            }
+            ch => json.push(ch),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn changed<T: PartialEq>(old: T, new: T) -> Option<(T, T)> {
+    if old == new {
+        None
+    } else {
+        Some((old, new))
+    }
+}
+
+// Writes a line per executed instruction to its sink. Clones share the sink.
+#[derive(Clone)]
+pub struct Tracer {
+    pub format: TraceFormat,
+    pub filter: TraceFilter,
+    sink: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("format", &self.format)
+            .field("filter", &self.filter)
+            .finish()
+    }
+}
+
+impl Tracer {
+    pub fn new(format: TraceFormat, sink: Box<dyn Write + Send>) -> Tracer {
+        Tracer {
+            format,
+            filter: TraceFilter::default(),
+            sink: Arc::new(Mutex::new(sink)),
+        }
+    }
+
+    pub fn record(&self, event: &TraceEvent) {
+        let line = match self.format {
+            TraceFormat::Text => event.to_text(),
+            TraceFormat::Json => event.to_json(),
+        };
+        if let Ok(mut sink) = self.sink.lock() {
+            // A broken trace sink should not take the running program down with it.
+            let _ = writeln!(sink, "{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::source_map::SourceMap;
+
+    // Collects trace output in memory so the tests can look at it.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(|line| line.to_string())
+                .collect()
+        }
+    }
+
+    fn trace(program: Vec<u8>, format: TraceFormat, filter: TraceFilter) -> Vec<String> {
+        let buffer = Buffer::default();
+        let mut vm = VM::new_with_program(program);
+        let mut tracer = Tracer::new(format, Box::new(buffer.clone()));
+        tracer.filter = filter;
+        vm.tracer = Some(tracer);
+        vm.run();
+        buffer.lines()
+    }
+
+    #[test]
+    fn test_text_trace() {
+        let lines = trace(
+            vec![1, 0, 0, 5, 1, 1, 0, 5, 7, 0, 1, 0, 9, 0, 0, 0],
+            TraceFormat::Text,
+            TraceFilter::default(),
+        );
+        assert_eq!(
+            lines,
+            vec![
+                "0000: load $0 #5 | $0: 0 -> 5",
+                "0004: load $1 #5 | $1: 0 -> 5",
+                "0008: eq $0 $1 | equality flag: false -> true",
+                "0012: alloc $0 | $0: 5 -> 0, heap: 0 -> 5 bytes",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trace_managed_objects() {
+        let program = Assembler::new(
+            "load $1 #2\nnew $0 $1\nload $2 #1\nload $3 #7\nsetf $0 $2 $3\nsetf $0 $2 $3"
+                .to_string(),
+        )
+        .assemble()
+        .unwrap();
+        let lines = trace(program.clone(), TraceFormat::Text, TraceFilter::default());
+        assert_eq!(
+            lines[1],
+            "0004: new $0 $1 | $0: 0 -> 1073741824, object 1073741824: new, 2 fields"
+        );
+        assert_eq!(
+            &lines[4..],
+            [
+                "0016: setf $0 $2 $3 | object 1073741824[1]: 0 -> 7",
+                "0020: setf $0 $2 $3"
+            ]
+        );
+        let filter = TraceFilter {
+            pc_range: None,
+            opcodes: vec![Opcode::NEW, Opcode::SETF],
+        };
+        assert_eq!(
+            trace(program, TraceFormat::Json, filter)[..2],
+            [
+                r#"{"pc":4,"instruction":"new $0 $1","registers":[{"register":0,"old":0,"new":1073741824}],"object":{"object":1073741824,"fields":2}}"#,
+                r#"{"pc":16,"instruction":"setf $0 $2 $3","fields":[{"object":1073741824,"index":1,"old":0,"new":7}]}"#
+            ]
+        );
+    }
+
+    #[test]
+    fn test_heap_captured_only_for_heap_writes() {
+        let mut vm = VM::new_with_program(vec![]);
+        vm.heap = vec![0; 64];
+        assert!(MachineState::capture(&vm, Opcode::LOAD, [0, 0, 0])
+            .heap
+            .is_none());
+        assert!(MachineState::capture(&vm, Opcode::SETF, [0, 0, 0])
+            .heap
+            .is_none());
+        assert_eq!(
+            MachineState::capture(&vm, Opcode::STRCAT, [0, 2, 4]).heap,
+            Some(vec![0; 64])
+        );
+    }
+
+    #[test]
+    fn test_json_trace() {
+        let lines = trace(vec![1, 0, 0, 5], TraceFormat::Json, TraceFilter::default());
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"pc":0,"instruction":"load $0 #5","registers":[{"register":0,"old":0,"new":5}]}"#
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trace_with_source_map() {
+        let mut assembler = Assembler::new("loop:\n  inc $0\n".to_string());
+        let buffer = Buffer::default();
+        let mut vm = VM::new_with_program(assembler.assemble().unwrap());
+        vm.source_map = Some(SourceMap::new(
+            "say \"hi\".rpd",
+            assembler.listing().clone(),
+        ));
+        vm.tracer = Some(Tracer::new(TraceFormat::Json, Box::new(buffer.clone())));
+        vm.run();
+        assert_eq!(
+            buffer.lines(),
+            vec![
+                r#"{"pc":0,"instruction":"inc $0","registers":[{"register":0,"old":0,"new":1}],"location":"say \"hi\".rpd:2:3 (loop+0)"}"#
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trace_filters() {
+        let program = vec![10, 0, 0, 0, 10, 1, 0, 0, 11, 0, 0, 0];
+        let by_pc = TraceFilter {
+            pc_range: Some(4..=8),
+            opcodes: vec![],
+        };
+        assert_eq!(trace(program.clone(), TraceFormat::Text, by_pc).len(), 2);
+        let by_opcode = TraceFilter {
+            pc_range: None,
+            opcodes: vec![Opcode::DEC],
+        };
+        assert_eq!(
+            trace(program, TraceFormat::Text, by_opcode),
+            vec!["0008: dec $0 | $0: 1 -> 0"]
+        );
+    }
+}
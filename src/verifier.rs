@@ -0,0 +1,222 @@
+use std::fmt::Display;
+
+use crate::instruction::{Encoding, Opcode, OperandKind, MAX_EXIT_STATUS, REGISTER_COUNT};
+
+#[derive(Debug, PartialEq)]
+pub struct VerifierError {
+    message: String,
+    offset: usize,
+}
+
+impl VerifierError {
+    pub fn new(message: &str, offset: usize) -> VerifierError {
+        VerifierError {
+            message: message.to_string(),
+            offset,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl Display for VerifierError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ERROR: {} at offset {}", self.message, self.offset)
+    }
+}
+
+// Checks that a program can be run without the VM reading past the end of the
+// program, indexing a register that does not exist or jumping into the middle
+// of an instruction. Jumping to the very end of the program is allowed, it just
+// stops execution.
+pub fn verify(program: &[u8], encoding: Encoding) -> Result<(), Vec<VerifierError>> {
+    let mut errors: Vec<VerifierError> = vec![];
+    let starts = instruction_starts(program, encoding);
+    for start in &starts {
+        let start = *start;
+        let opcode = Opcode::from(program[start]);
+        if opcode == Opcode::ILLEGAL {
+            errors.push(VerifierError::new(
+                &format!("unknown opcode 0x{:02x}", program[start]),
+                start,
+            ));
+            continue;
+        }
+        let mut position = start + 1;
+        for kind in opcode.operands() {
+            if position + kind.byte_len() > program.len() {
+                errors.push(VerifierError::new(
+                    &format!("`{}` is missing its operands", opcode.mnemonic()),
+                    start,
+                ));
+                break;
+            }
+            verify_operand(program, &starts, opcode, *kind, position, &mut errors);
+            position += kind.byte_len();
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(())
+}
+
+// Where every instruction starts. An unknown opcode can't tell how long it is,
+// so in the compact encoding the next instruction is assumed right after it.
+fn instruction_starts(program: &[u8], encoding: Encoding) -> Vec<usize> {
+    let mut starts = vec![];
+    let mut position = 0;
+    while position < program.len() {
+        starts.push(position);
+        position += encoding.length(Opcode::from(program[position]));
+    }
+    starts
+}
+
+fn verify_operand(
+    program: &[u8],
+    starts: &[usize],
+    opcode: Opcode,
+    kind: OperandKind,
+    position: usize,
+    errors: &mut Vec<VerifierError>,
+) {
+    let value = match kind {
+        OperandKind::Register | OperandKind::Pair => program[position] as usize,
+        OperandKind::Immediate | OperandKind::Label => {
+            ((program[position] as usize) << 8) | program[position + 1] as usize
+        }
+    };
+    match kind {
+        OperandKind::Register => {
+            if value >= REGISTER_COUNT {
+                errors.push(VerifierError::new(
+                    &format!("register ${} is out of range", value),
+                    position,
+                ));
+            }
+        }
+        OperandKind::Pair => {
+            if value + 1 >= REGISTER_COUNT {
+                errors.push(VerifierError::new(
+                    &format!("register pair ${} is out of range", value),
+                    position,
+                ));
+            }
+        }
+        OperandKind::Label => {
+            if value > program.len() {
+                errors.push(VerifierError::new(
+                    &format!("jump target {} is outside the program", value),
+                    position,
+                ));
+            } else if value < program.len() && starts.binary_search(&value).is_err() {
+                errors.push(VerifierError::new(
+                    &format!("jump target {} is not on an instruction boundary", value),
+                    position,
+                ));
+            }
+        }
+        OperandKind::Immediate => {
+            if opcode == Opcode::EXIT && value > MAX_EXIT_STATUS as usize {
+                errors.push(VerifierError::new(
+                    &format!(
+                        "exit status {} is out of range (0-{})",
+                        value, MAX_EXIT_STATUS
+                    ),
+                    position,
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_valid_program() {
+        let program = vec![1, 1, 0, 10, 10, 1, 0, 0, 6, 0, 4, 0];
+        assert!(verify(&program, Encoding::Fixed).is_ok());
+    }
+
+    #[test]
+    fn test_verify_unknown_opcode() {
+        let errors = verify(&[200, 0, 0, 0], Encoding::Fixed).unwrap_err();
+        assert_eq!(errors, vec![VerifierError::new("unknown opcode 0xc8", 0)]);
+    }
+
+    #[test]
+    fn test_verify_truncated_instruction() {
+        let errors = verify(&[10, 0, 0, 0, 2, 1], Encoding::Fixed).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![VerifierError::new("`add` is missing its operands", 4)]
+        );
+    }
+
+    #[test]
+    fn test_verify_register_out_of_range() {
+        let errors = verify(&[10, 32, 0, 0], Encoding::Fixed).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![VerifierError::new("register $32 is out of range", 1)]
+        );
+    }
+
+    #[test]
+    fn test_verify_register_pair_out_of_range() {
+        assert!(verify(&[32, 30, 1, 0], Encoding::Fixed).is_ok());
+        let errors = verify(&[32, 31, 1, 0], Encoding::Fixed).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![VerifierError::new("register pair $31 is out of range", 1)]
+        );
+    }
+
+    #[test]
+    fn test_verify_exit_status() {
+        assert!(verify(&[15, 0, 255, 0], Encoding::Fixed).is_ok());
+        let errors = verify(&[15, 255, 255, 0], Encoding::Fixed).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![VerifierError::new(
+                "exit status 65535 is out of range (0-255)",
+                1
+            )]
+        );
+    }
+
+    #[test]
+    fn test_verify_compact_program() {
+        // inc $0; jmp @0; hlt
+        assert!(verify(&[10, 0, 6, 0, 0, 14], Encoding::Compact).is_ok());
+        let errors = verify(&[10, 0, 6, 0, 1, 14, 2, 1], Encoding::Compact).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                VerifierError::new("jump target 1 is not on an instruction boundary", 3),
+                VerifierError::new("`add` is missing its operands", 6),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verify_jump_targets() {
+        let errors = verify(&[6, 0, 2, 0, 8, 1, 4, 0], Encoding::Fixed).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                VerifierError::new("jump target 2 is not on an instruction boundary", 1),
+                VerifierError::new("jump target 260 is outside the program", 5),
+            ]
+        );
+    }
+}
@@ -1,162 +1,757 @@
-use crate::instruction::Opcode;
-use std::usize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-#[derive(Debug)]
+use crate::coverage::Coverage;
+use crate::decoder::{self, DecodedInstruction};
+use crate::gc::{GcInfo, ManagedHeap};
+use crate::heap::Allocator;
+use crate::instruction::{Encoding, Opcode, OperandKind, Width, REGISTER_COUNT};
+use crate::profile::Profile;
+use crate::scheduler::Request;
+use crate::source_map::{SourceLocation, SourceMap};
+use crate::trace::{MachineState, TraceEvent, Tracer};
+use crate::verifier::{self, VerifierError};
+
+// How many instructions run between two looks at the clock, reading it on
+// every step would dominate tight loops.
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+// Limits applied to every call of `VM::run`. `None` means unlimited.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Budget {
+    pub max_instructions: Option<u64>,
+    pub timeout: Option<Duration>,
+}
+
+// Why `VM::run` returned. `Done` means the program counter ran off the end of
+// the program, `Halted` carries the status of a `hlt` (0) or `exit`. Everything
+// except `Done` leaves the VM where it stopped, so calling `run` again resumes
+// the program. A `Fault` leaves the program counter on the faulting instruction.
+// So does a `Request`, which asks the scheduler running the VM to do something
+// and is moved past with `step_over` once done. `Deadlock` only comes from the
+// scheduler, when every process is waiting on another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunStatus {
+    Done,
+    Halted(i32),
+    InstructionLimit,
+    Timeout,
+    Interrupted,
+    Fault(Fault),
+    Request(Request),
+    Deadlock,
+}
+
+// Why an instruction couldn't run. Pointers, references and ids are the raw
+// register values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    // Faults of the heap.
+    NegativeSize(i64),
+    // `limit` is `None` when the host itself ran out.
+    OutOfMemory {
+        requested: usize,
+        limit: Option<usize>,
+    },
+    InvalidPointer(i64),
+    DoubleFree(i64),
+    UseAfterFree(i64),
+    OutOfBounds {
+        address: i64,
+        len: usize,
+    },
+    // Faults of the managed object heap, references are register values too.
+    InvalidFieldCount(i64),
+    OutOfObjects(usize),
+    InvalidObject(i64),
+    FieldOutOfBounds {
+        object: i64,
+        index: i64,
+    },
+    StackUnderflow,
+    // Faults of the string instructions.
+    InvalidLength(i64),
+    ReadOnlyOutOfBounds(i64),
+    UnterminatedString(i64),
+    // Faults of the process instructions.
+    InvalidProcess(i64),
+    InvalidChannel(i64),
+    // Faults of every other instruction.
+    DivisionByZero,
+    IllegalInstruction,
+    // The program doesn't pass `verifier::verify`, `offset` is where the first
+    // error is. Caught before running any of it.
+    InvalidProgram {
+        offset: usize,
+    },
+    ProgramCounterInsideInstruction(usize),
+}
+
+impl std::fmt::Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fault::NegativeSize(size) => write!(f, "cannot allocate {} bytes", size),
+            Fault::OutOfMemory {
+                requested,
+                limit: Some(limit),
+            } => write!(
+                f,
+                "out of memory allocating {} bytes, the heap is limited to {} bytes",
+                requested, limit
+            ),
+            Fault::OutOfMemory {
+                requested,
+                limit: None,
+            } => write!(f, "out of memory allocating {} bytes", requested),
+            Fault::InvalidPointer(pointer) => {
+                write!(f, "{} is not a pointer to an allocated block", pointer)
+            }
+            Fault::DoubleFree(pointer) => write!(f, "double free of block {}", pointer),
+            Fault::UseAfterFree(pointer) => write!(f, "use of block {} after free", pointer),
+            Fault::OutOfBounds { address, len } => write!(
+                f,
+                "access of {} bytes at {} is outside any allocated block",
+                len, address
+            ),
+            Fault::InvalidFieldCount(count) => {
+                write!(f, "cannot allocate an object with {} fields", count)
+            }
+            Fault::OutOfObjects(limit) => {
+                write!(f, "out of objects, at most {} can be live", limit)
+            }
+            Fault::InvalidObject(object) => {
+                write!(f, "{} is not a reference to a live object", object)
+            }
+            Fault::FieldOutOfBounds { object, index } => {
+                write!(f, "object {} has no field {}", object, index)
+            }
+            Fault::StackUnderflow => write!(f, "pop from an empty stack"),
+            Fault::InvalidLength(len) => write!(f, "{} is not a valid string length", len),
+            Fault::ReadOnlyOutOfBounds(address) => {
+                write!(f, "{} is outside the read-only data", address)
+            }
+            Fault::UnterminatedString(address) => {
+                write!(
+                    f,
+                    "the read-only string at {} has no terminating zero",
+                    address
+                )
+            }
+            Fault::InvalidProcess(id) => write!(f, "{} is not a process to wait for", id),
+            Fault::InvalidProgram { offset } => {
+                write!(f, "the program does not verify, see offset {}", offset)
+            }
+            Fault::ProgramCounterInsideInstruction(pc) => {
+                write!(f, "the program counter {} is inside an instruction", pc)
+            }
+            Fault::InvalidChannel(id) => write!(f, "{} is not a channel", id),
+            Fault::DivisionByZero => write!(f, "division by zero"),
+            Fault::IllegalInstruction => write!(f, "illegal instruction"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct VM {
-    pub registers: [i32; 32],
+    pub registers: [i64; REGISTER_COUNT],
     pub program_counter: usize,
     pub program: Vec<u8>,
-    pub remainder: i32,
+    pub remainder: i64,
+    // Every arithmetic result is wrapped to this width.
+    pub width: Width,
+    // How the instructions in `program` are laid out.
+    pub encoding: Encoding,
     pub equality_flag: bool,
     pub heap: Vec<u8>,
+    // The `.data` section, strings declared with `.asciiz`.
+    pub read_only: Vec<u8>,
+    // Keeps track of the blocks handed out from `heap`.
+    pub allocator: Allocator,
+    // Garbage collected objects and the stack, apart from `heap`.
+    pub managed: ManagedHeap,
+    pub budget: Budget,
+    // Instructions completed over every call of `run`, for limits spanning
+    // several of them.
+    pub instructions: u64,
+    pub tracer: Option<Tracer>,
+    pub profile: Option<Profile>,
+    pub coverage: Option<Coverage>,
+    pub source_map: Option<SourceMap>,
+    // Run through the pre-decoded instruction cache when the program allows it.
+    // Turning it off forces the byte-level interpreter, e.g. to compare the two.
+    pub predecode: bool,
+    decoded: Option<Vec<DecodedInstruction>>,
+    // Where every decoded instruction starts, followed by the program length.
+    decoded_offsets: Vec<usize>,
+    // Where the program first failed verification, `None` if it passed.
+    invalid_at: Option<usize>,
+    // The encoding and program `decoded` was built from, to notice changes.
+    decoded_from: (Encoding, Vec<u8>),
+    interrupted: Arc<AtomicBool>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
-        let vm = VM {
-            program: Vec::new(),
-            registers: [0; 32],
-            program_counter: 0,
-            remainder: 0,
-            equality_flag: false,
-            heap: Vec::new(),
-        };
-        return vm;
+        VM::new_with_program(Vec::new())
     }
 
     pub fn new_with_program(program: Vec<u8>) -> VM {
         VM {
             program,
-            registers: [0; 32],
+            registers: [0; REGISTER_COUNT],
             program_counter: 0,
             remainder: 0,
+            width: Width::W32,
+            encoding: Encoding::Fixed,
             equality_flag: false,
             heap: Vec::new(),
+            read_only: Vec::new(),
+            allocator: Allocator::new(),
+            managed: ManagedHeap::new(),
+            budget: Budget::default(),
+            instructions: 0,
+            tracer: None,
+            profile: None,
+            coverage: None,
+            source_map: None,
+            predecode: true,
+            decoded: None,
+            decoded_offsets: Vec::new(),
+            invalid_at: None,
+            decoded_from: (Encoding::Fixed, Vec::new()),
+            interrupted: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    pub fn run(&mut self) {
-        let mut is_done = false;
-        while !is_done {
-            is_done = self.execute_instrunction();
+    // Clears the program, its source map and all machine state, keeping the
+    // budget and the interrupt handle so anything holding on to them keeps working.
+    pub fn reset(&mut self) {
+        self.program.clear();
+        self.registers = [0; REGISTER_COUNT];
+        self.program_counter = 0;
+        self.remainder = 0;
+        self.equality_flag = false;
+        self.heap.clear();
+        self.read_only.clear();
+        // The heap limit and debug mode are settings, not state.
+        self.allocator = Allocator {
+            max_size: self.allocator.max_size,
+            debug: self.allocator.debug,
+            ..Allocator::new()
+        };
+        self.managed = ManagedHeap::new();
+        self.instructions = 0;
+        self.source_map = None;
+    }
+
+    // A flag that stops the running program when set, meant to be flipped from
+    // another thread or a signal handler.
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.interrupted)
+    }
+
+    // Where the instruction at `offset` came from, if the program has a source map.
+    pub fn location(&self, offset: usize) -> Option<SourceLocation<'_>> {
+        self.source_map
+            .as_ref()
+            .and_then(|source_map| source_map.lookup(offset))
+    }
+
+    pub fn verify(&self) -> Result<(), Vec<VerifierError>> {
+        verifier::verify(&self.program, self.encoding)
+    }
+
+    pub fn run(&mut self) -> RunStatus {
+        // Tracing, profiling and coverage look at every step through the
+        // byte-level interpreter, only plain runs take the fast path.
+        let observed = self.tracer.is_some() || self.profile.is_some() || self.coverage.is_some();
+        // Only a verified program, started on an instruction, is safe to run.
+        self.prepare();
+        if let Some(offset) = self.invalid_at {
+            return RunStatus::Fault(Fault::InvalidProgram { offset });
+        }
+        let index = match self.decoded_offsets.binary_search(&self.program_counter) {
+            Ok(index) => index,
+            Err(_) if self.program_counter >= self.program.len() => return RunStatus::Done,
+            Err(_) => {
+                return RunStatus::Fault(Fault::ProgramCounterInsideInstruction(
+                    self.program_counter,
+                ))
+            }
+        };
+        if self.predecode && !observed && self.decoded.is_some() {
+            return self.run_decoded(index);
+        }
+        let deadline = self.budget.timeout.map(|timeout| Instant::now() + timeout);
+        let mut executed: u64 = 0;
+        let status = loop {
+            if let Some(status) = self.check_budget(executed, deadline) {
+                break status;
+            }
+            let pc = self.program_counter;
+            let status = match self.tracer {
+                Some(_) => self.execute_traced(),
+                None => self.execute_instrunction(),
+            };
+            if pc < self.program.len() {
+                if let Some(profile) = &mut self.profile {
+                    let opcode = Opcode::from(self.program[pc]);
+                    let next = pc + self.encoding.length(opcode);
+                    profile.record(pc, opcode, self.program_counter, next);
+                }
+                if let Some(coverage) = &mut self.coverage {
+                    coverage.record(pc);
+                }
+            }
+            if let Some(status) = status {
+                break status;
+            }
+            executed += 1;
+        };
+        self.instructions += executed;
+        status
+    }
+
+    fn check_budget(&self, executed: u64, deadline: Option<Instant>) -> Option<RunStatus> {
+        // A plain load first, the swap is a much more expensive locked operation.
+        if self.interrupted.load(Ordering::Relaxed)
+            && self.interrupted.swap(false, Ordering::Relaxed)
+        {
+            return Some(RunStatus::Interrupted);
+        }
+        if let Some(max_instructions) = self.budget.max_instructions {
+            if executed >= max_instructions {
+                return Some(RunStatus::InstructionLimit);
+            }
+        }
+        if let Some(deadline) = deadline {
+            if executed.is_multiple_of(DEADLINE_CHECK_INTERVAL) && Instant::now() >= deadline {
+                return Some(RunStatus::Timeout);
+            }
         }
+        None
     }
 
-    fn execute_instrunction(&mut self) -> bool {
+    // Makes sure `invalid_at` and `decoded` match the current program,
+    // verifying and decoding it again if the program changed.
+    fn prepare(&mut self) {
+        if self.decoded_from.0 != self.encoding || self.decoded_from.1 != self.program {
+            self.invalid_at = self
+                .verify()
+                .err()
+                .map(|errors| errors.iter().map(|err| err.offset()).min().unwrap_or(0));
+            self.decoded = match self.invalid_at {
+                None => decoder::decode(&self.program, self.encoding),
+                Some(_) => None,
+            };
+            self.decoded_offsets =
+                decoder::boundaries(&self.program, self.encoding).unwrap_or_default();
+            self.decoded_from = (self.encoding, self.program.clone());
+        }
+    }
+
+    // Same as the byte-level loop in `run`, dispatching on the decoded
+    // instructions starting at `index`. The program counter is only written back
+    // when it stops.
+    fn run_decoded(&mut self, mut index: usize) -> RunStatus {
+        let decoded = self.decoded.take().unwrap_or_default();
+        let deadline = self.budget.timeout.map(|timeout| Instant::now() + timeout);
+        let mut executed: u64 = 0;
+        let status = loop {
+            if let Some(status) = self.check_budget(executed, deadline) {
+                break status;
+            }
+            let instruction = match decoded.get(index) {
+                Some(instruction) => *instruction,
+                None => break RunStatus::Done,
+            };
+            index += 1;
+            match instruction.opcode {
+                Opcode::JMP => index = instruction.operands[0],
+                Opcode::JEQ => {
+                    if self.equality_flag {
+                        index = instruction.operands[0];
+                    }
+                }
+                Opcode::JNEQ => {
+                    if !self.equality_flag {
+                        index = instruction.operands[0];
+                    }
+                }
+                // The target of a request is a byte offset, like in the program.
+                Opcode::SPAWN => {
+                    index -= 1;
+                    break RunStatus::Request(Request::Spawn {
+                        register: instruction.operands[0],
+                        target: self.decoded_offsets[instruction.operands[1]],
+                    });
+                }
+                opcode => {
+                    if let Some(status) = self.execute_operation(opcode, instruction.operands) {
+                        if let RunStatus::Fault(_) | RunStatus::Request(_) = status {
+                            index -= 1;
+                        }
+                        break status;
+                    }
+                }
+            }
+            executed += 1;
+        };
+        self.program_counter = self.decoded_offsets[index];
+        self.decoded = Some(decoded);
+        self.instructions += executed;
+        status
+    }
+
+    fn execute_traced(&mut self) -> Option<RunStatus> {
+        let pc = self.program_counter;
+        let tracer = match &self.tracer {
+            Some(tracer) if pc < self.program.len() => tracer,
+            _ => return self.execute_instrunction(),
+        };
+        let opcode = Opcode::from(self.program[pc]);
+        if !tracer.filter.matches(pc, opcode) {
+            return self.execute_instrunction();
+        }
+        // Decoding moves the program counter, put it back for the real run.
+        self.program_counter += 1;
+        let operands = self.decode_operands(opcode);
+        self.program_counter = pc;
+        let before = MachineState::capture(self, opcode, operands);
+        let status = self.execute_instrunction();
+        let event = TraceEvent::new(pc, &self.program, &before, self);
+        if let Some(tracer) = &self.tracer {
+            tracer.record(&event);
+        }
+        status
+    }
+
+    fn execute_instrunction(&mut self) -> Option<RunStatus> {
         if self.program_counter >= self.program.len() {
-            return true;
+            return Some(RunStatus::Done);
+        }
+        let start = self.program_counter;
+        let opcode = self.decode_opcode();
+        let operands = self.decode_operands(opcode);
+        match opcode {
+            Opcode::JMP => {
+                self.program_counter = operands[0];
+            }
+            Opcode::JEQ => {
+                if self.equality_flag {
+                    self.program_counter = operands[0];
+                }
+            }
+            Opcode::JNEQ => {
+                if !self.equality_flag {
+                    self.program_counter = operands[0];
+                }
+            }
+            _ => {
+                let status = self.execute_operation(opcode, operands);
+                if let Some(RunStatus::Fault(_) | RunStatus::Request(_)) = status {
+                    self.program_counter = start;
+                }
+                return status;
+            }
         }
-        match self.decode_opcode() {
+        None
+    }
+
+    // Everything but the jumps, which the two interpreters handle themselves
+    // since they track the current instruction differently.
+    fn execute_operation(&mut self, opcode: Opcode, operands: [usize; 3]) -> Option<RunStatus> {
+        match opcode {
             Opcode::LOAD => {
-                let register = self.get_next_byte() as usize;
-                let value = self.get_next_2_bytes();
-                self.registers[register] = value as i32;
+                self.registers[operands[0]] = operands[1] as i64;
+            }
+            Opcode::LOADS => {
+                self.registers[operands[0]] = operands[1] as u16 as i16 as i64;
+            }
+            Opcode::LOADX => {
+                let register = self.registers[operands[0]];
+                self.registers[operands[0]] =
+                    self.width.wrap((register << 16) | operands[1] as i64);
             }
             Opcode::ADD => {
-                let source_register = self.get_next_byte() as usize;
-                let register1 = self.registers[self.get_next_byte() as usize];
-                let register2 = self.registers[self.get_next_byte() as usize];
-                self.registers[source_register] = register1 + register2
+                let register1 = self.registers[operands[1]];
+                let register2 = self.registers[operands[2]];
+                self.registers[operands[0]] = self.width.wrap(register1.wrapping_add(register2));
             }
             Opcode::SUB => {
-                let source_register = self.get_next_byte() as usize;
-                let register1 = self.registers[self.get_next_byte() as usize];
-                let register2 = self.registers[self.get_next_byte() as usize];
-                self.registers[source_register] = register1 - register2;
+                let register1 = self.registers[operands[1]];
+                let register2 = self.registers[operands[2]];
+                self.registers[operands[0]] = self.width.wrap(register1.wrapping_sub(register2));
             }
             Opcode::MUL => {
-                let source_register = self.get_next_byte() as usize;
-                let register1 = self.registers[self.get_next_byte() as usize];
-                let register2 = self.registers[self.get_next_byte() as usize];
-                self.registers[source_register] = register1 * register2;
+                let register1 = self.registers[operands[1]];
+                let register2 = self.registers[operands[2]];
+                self.registers[operands[0]] = self.width.wrap(register1.wrapping_mul(register2));
             }
             Opcode::DIV => {
-                let source_register = self.get_next_byte() as usize;
-                let register1 = self.registers[self.get_next_byte() as usize];
-                let register2 = self.registers[self.get_next_byte() as usize];
-                self.registers[source_register] = register1 / register2;
-                self.remainder = register1 % register2
-            }
-            Opcode::JMP => {
-                let target = self.get_next_byte() as usize;
-                self.get_next_byte();
-                self.get_next_byte();
-                self.program_counter = target;
+                let register1 = self.registers[operands[1]];
+                let register2 = self.registers[operands[2]];
+                if register2 == 0 {
+                    return Some(RunStatus::Fault(Fault::DivisionByZero));
+                }
+                self.registers[operands[0]] = self.width.wrap(register1.wrapping_div(register2));
+                self.remainder = self.width.wrap(register1.wrapping_rem(register2));
             }
             Opcode::EQ => {
-                let register1 = self.registers[self.get_next_byte() as usize];
-                let register2 = self.registers[self.get_next_byte() as usize];
-                self.equality_flag = register1 == register2;
-                self.get_next_byte();
+                self.equality_flag = self.registers[operands[0]] == self.registers[operands[1]];
             }
             Opcode::NEQ => {
-                let register1 = self.registers[self.get_next_byte() as usize];
-                let register2 = self.registers[self.get_next_byte() as usize];
-                self.equality_flag = register1 != register2;
-                self.get_next_byte();
+                self.equality_flag = self.registers[operands[0]] != self.registers[operands[1]];
             }
-            Opcode::JEQ => {
-                let target = self.get_next_byte() as usize;
-                self.get_next_byte();
-                self.get_next_byte();
-                if self.equality_flag {
-                    self.program_counter = target;
+            Opcode::ALLOC => {
+                let size = self.registers[operands[0]];
+                match self.allocator.alloc(&mut self.heap, size) {
+                    Ok(pointer) => self.registers[operands[0]] = pointer as i64,
+                    Err(fault) => return Some(RunStatus::Fault(fault)),
                 }
             }
-            Opcode::JNEQ => {
-                let target = self.get_next_byte() as usize;
-                self.get_next_byte();
-                self.get_next_byte();
-                if !self.equality_flag {
-                    self.program_counter = target;
+            Opcode::FREE => {
+                let pointer = self.registers[operands[0]];
+                if let Err(fault) = self.allocator.free(&mut self.heap, pointer) {
+                    return Some(RunStatus::Fault(fault));
                 }
             }
-            Opcode::ALLOC => {
-                let size = self.registers[self.get_next_byte() as usize];
-                let new_end_heap = self.heap.len() as i32 + size;
-                self.heap.resize(new_end_heap as usize, 0);
-                self.get_next_byte();
-                self.get_next_byte();
+            Opcode::REALLOC => {
+                let pointer = self.registers[operands[0]];
+                let size = self.registers[operands[1]];
+                match self.allocator.realloc(&mut self.heap, pointer, size) {
+                    Ok(pointer) => self.registers[operands[0]] = pointer as i64,
+                    Err(fault) => return Some(RunStatus::Fault(fault)),
+                }
+            }
+            Opcode::NEW => {
+                let field_count = self.registers[operands[1]];
+                match self.managed.alloc(field_count, &self.registers) {
+                    Ok(object) => self.registers[operands[0]] = object,
+                    Err(fault) => return Some(RunStatus::Fault(fault)),
+                }
+            }
+            Opcode::GETF => {
+                let object = self.registers[operands[1]];
+                let index = self.registers[operands[2]];
+                match self.managed.get(object, index) {
+                    Ok(value) => self.registers[operands[0]] = value,
+                    Err(fault) => return Some(RunStatus::Fault(fault)),
+                }
+            }
+            Opcode::SETF => {
+                let object = self.registers[operands[0]];
+                let index = self.registers[operands[1]];
+                let value = self.registers[operands[2]];
+                if let Err(fault) = self.managed.set(object, index, value) {
+                    return Some(RunStatus::Fault(fault));
+                }
+            }
+            Opcode::PUSH => {
+                self.managed.push(self.registers[operands[0]]);
+            }
+            Opcode::POP => match self.managed.pop() {
+                Ok(value) => self.registers[operands[0]] = value,
+                Err(fault) => return Some(RunStatus::Fault(fault)),
+            },
+            Opcode::GC => {
+                self.managed.collect(&self.registers);
+            }
+            Opcode::GCINFO => {
+                self.registers[operands[0]] = match GcInfo::from_immediate(operands[1]) {
+                    Some(info) => self.managed.info(info),
+                    None => 0,
+                };
+            }
+            Opcode::LDA => {
+                self.registers[operands[0]] = operands[1] as i64;
+            }
+            Opcode::STRLEN
+            | Opcode::STRLOAD
+            | Opcode::STRCMP
+            | Opcode::STRCAT
+            | Opcode::ITOA
+            | Opcode::ATOI => {
+                if let Err(fault) = self.execute_string(opcode, operands) {
+                    return Some(RunStatus::Fault(fault));
+                }
+            }
+            // Only reached from the byte-level interpreter, where the label is
+            // already a byte offset.
+            Opcode::SPAWN => {
+                return Some(RunStatus::Request(Request::Spawn {
+                    register: operands[0],
+                    target: operands[1],
+                }));
+            }
+            Opcode::YIELD => return Some(RunStatus::Request(Request::Yield)),
+            Opcode::JOIN => {
+                return Some(RunStatus::Request(Request::Join {
+                    register: operands[0],
+                }));
+            }
+            Opcode::CHAN => {
+                return Some(RunStatus::Request(Request::Channel {
+                    register: operands[0],
+                }));
+            }
+            Opcode::SEND => {
+                return Some(RunStatus::Request(Request::Send {
+                    channel: self.registers[operands[0]],
+                    value: self.registers[operands[1]],
+                }));
+            }
+            Opcode::RECV => {
+                return Some(RunStatus::Request(Request::Receive {
+                    register: operands[0],
+                    channel: self.registers[operands[1]],
+                }));
             }
             Opcode::INC => {
-                let register = self.get_next_byte() as usize;
-                self.registers[register] += 1;
-                self.get_next_byte();
-                self.get_next_byte();
+                let register = self.registers[operands[0]];
+                self.registers[operands[0]] = self.width.wrap(register.wrapping_add(1));
             }
             Opcode::DEC => {
-                let register = self.get_next_byte() as usize;
-                self.registers[register] -= 1;
-                self.get_next_byte();
-                self.get_next_byte();
+                let register = self.registers[operands[0]];
+                self.registers[operands[0]] = self.width.wrap(register.wrapping_sub(1));
+            }
+            Opcode::HLT => {
+                return Some(RunStatus::Halted(0));
+            }
+            Opcode::EXIT => {
+                return Some(RunStatus::Halted(operands[0] as i32));
+            }
+            Opcode::ZERO | Opcode::JMP | Opcode::JEQ | Opcode::JNEQ => {}
+            Opcode::ILLEGAL => return Some(RunStatus::Fault(Fault::IllegalInstruction)),
+        }
+        None
+    }
+
+    // Moves the program counter past the current instruction, for a scheduler
+    // that carried out its request.
+    pub fn step_over(&mut self) {
+        if self.program_counter < self.program.len() {
+            let opcode = Opcode::from(self.program[self.program_counter]);
+            self.program_counter += self.encoding.length(opcode);
+            self.instructions += 1;
+        }
+    }
+
+    fn execute_string(&mut self, opcode: Opcode, operands: [usize; 3]) -> Result<(), Fault> {
+        match opcode {
+            Opcode::STRLEN => {
+                let string = self.read_only_string(self.registers[operands[1]])?;
+                self.registers[operands[0]] = string.len() as i64;
+            }
+            Opcode::STRLOAD => {
+                let string = self.read_only_string(self.registers[operands[1]])?.to_vec();
+                self.store_string(operands[0], &string)?;
             }
-            Opcode::ZERO => {
-                return false;
+            Opcode::STRCMP => {
+                let ordering = self
+                    .heap_string(operands[1])?
+                    .cmp(self.heap_string(operands[2])?);
+                self.registers[operands[0]] = ordering as i64;
+                self.equality_flag = ordering.is_eq();
             }
-            Opcode::ILLEGAL => {
-                panic!("Unrecognized instrunction");
+            Opcode::STRCAT => {
+                let mut string = self.heap_string(operands[1])?.to_vec();
+                string.extend_from_slice(self.heap_string(operands[2])?);
+                self.store_string(operands[0], &string)?;
             }
+            Opcode::ITOA => {
+                let digits = self.registers[operands[1]].to_string();
+                self.store_string(operands[0], digits.as_bytes())?;
+            }
+            Opcode::ATOI => {
+                let value = std::str::from_utf8(self.heap_string(operands[1])?)
+                    .ok()
+                    .and_then(|digits| digits.parse::<i64>().ok())
+                    .filter(|value| self.width.fits(*value));
+                self.registers[operands[0]] = value.unwrap_or(0);
+                self.equality_flag = value.is_some();
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // The zero terminated string at `address` in the read-only data, without
+    // the zero.
+    fn read_only_string(&self, address: i64) -> Result<&[u8], Fault> {
+        let rest = usize::try_from(address)
+            .ok()
+            .and_then(|start| self.read_only.get(start..))
+            .filter(|rest| !rest.is_empty())
+            .ok_or(Fault::ReadOnlyOutOfBounds(address))?;
+        match rest.iter().position(|byte| *byte == 0) {
+            Some(len) => Ok(&rest[..len]),
+            None => Err(Fault::UnterminatedString(address)),
         }
-        return false;
+    }
+
+    // The bytes of the heap string in the register pair starting at `register`,
+    // which must lie inside one live block.
+    fn heap_string(&self, register: usize) -> Result<&[u8], Fault> {
+        let pointer = self.registers[register];
+        let len = self.registers[register + 1];
+        let len = usize::try_from(len).map_err(|_| Fault::InvalidLength(len))?;
+        if len == 0 {
+            return Ok(&[]);
+        }
+        self.allocator.check_access(pointer, len)?;
+        Ok(&self.heap[pointer as usize..pointer as usize + len])
+    }
+
+    // Copies `string` into a new heap block and puts it in the register pair
+    // starting at `register`.
+    fn store_string(&mut self, register: usize, string: &[u8]) -> Result<(), Fault> {
+        let pointer = self.allocator.alloc(&mut self.heap, string.len() as i64)?;
+        self.heap[pointer..pointer + string.len()].copy_from_slice(string);
+        self.registers[register] = pointer as i64;
+        self.registers[register + 1] = string.len() as i64;
+        Ok(())
+    }
+
+    // Reads the operands of the current instruction following its signature and
+    // moves the program counter past any padding to the next instruction.
+    fn decode_operands(&mut self, opcode: Opcode) -> [usize; 3] {
+        let start = self.program_counter - 1;
+        let mut operands = [0; 3];
+        for (index, kind) in opcode.operands().iter().enumerate() {
+            operands[index] = match kind {
+                OperandKind::Register | OperandKind::Pair => self.get_next_byte() as usize,
+                OperandKind::Immediate | OperandKind::Label => self.get_next_2_bytes() as usize,
+            };
+        }
+        self.program_counter = start + self.encoding.length(opcode);
+        operands
     }
 
     fn decode_opcode(&mut self) -> Opcode {
         let opcode = Opcode::from(self.program[self.program_counter]);
         self.program_counter += 1;
-        return opcode;
+        opcode
     }
 
     fn get_next_byte(&mut self) -> u8 {
         let byte = self.program[self.program_counter];
         self.program_counter += 1;
-        return byte;
+        byte
     }
 
     // NOTE we're doing big endian
     fn get_next_2_bytes(&mut self) -> u16 {
         let high_part = self.get_next_byte() as u16;
         let low_part = self.get_next_byte() as u16;
-        return (high_part << 8) | low_part;
+        (high_part << 8) | low_part
     }
 
     pub fn append_to_program(&mut self, prg: Vec<u8>) {
@@ -170,19 +765,19 @@ impl VM {
 #[allow(unused)]
 impl std::fmt::Display for VM {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "--------Value of registers---------\n");
+        writeln!(f, "--------Value of registers---------");
         for (index, value) in self.registers.iter().enumerate() {
-            write!(f, "Value of register {}: {}\n", index, value);
+            writeln!(f, "Value of register {}: {}", index, value);
         }
-        write!(f, "------------------------------------\n");
-        write!(f, "Value of program counter: {}\n", self.program_counter);
-        write!(f, "------------------------------------\n");
-        write!(f, "Equality flag: {}\n", self.equality_flag);
+        writeln!(f, "------------------------------------");
+        writeln!(f, "Value of program counter: {}", self.program_counter);
+        writeln!(f, "------------------------------------");
+        writeln!(f, "Equality flag: {}", self.equality_flag);
         write!(f, "Program: ");
         for value in &self.program {
             write!(f, "{} ", value);
         }
-        return Ok(());
+        Ok(())
     }
 }
 
@@ -198,7 +793,7 @@ mod tests {
     #[test]
     fn test_load_inst() {
         let mut test_vm = VM::new();
-        let test_bytes = vec![1, 10, 1, 1, 0];
+        let test_bytes = vec![1, 10, 1, 1];
         test_vm.program = test_bytes;
         test_vm.run();
         assert_eq!(test_vm.registers[10], 257);
@@ -241,6 +836,62 @@ mod tests {
         assert_eq!(test_vm.remainder, 1);
     }
 
+    #[test]
+    fn test_division_by_zero_faults() {
+        for predecode in [true, false] {
+            let mut test_vm = VM::new_with_program(vec![1, 1, 0, 10, 5, 3, 1, 2]);
+            test_vm.predecode = predecode;
+            assert_eq!(test_vm.run(), RunStatus::Fault(Fault::DivisionByZero));
+            assert_eq!(test_vm.program_counter, 4);
+            assert_eq!(test_vm.registers[3], 0);
+        }
+    }
+
+    #[test]
+    fn test_illegal_instruction_faults() {
+        for predecode in [true, false] {
+            let mut test_vm = VM::new_with_program(vec![10, 0, 0, 0, 200, 0, 0, 0]);
+            test_vm.predecode = predecode;
+            assert_eq!(
+                test_vm.run(),
+                RunStatus::Fault(Fault::InvalidProgram { offset: 4 })
+            );
+            assert_eq!(test_vm.program_counter, 0);
+            assert_eq!(test_vm.registers[0], 0);
+        }
+        let mut test_vm = VM::new_with_program(vec![200, 0, 0, 0]);
+        assert_eq!(
+            test_vm.execute_instrunction(),
+            Some(RunStatus::Fault(Fault::IllegalInstruction))
+        );
+    }
+
+    #[test]
+    fn test_runs_only_verified_programs() {
+        // A cut off `load` and a register that doesn't exist.
+        for program in [vec![1, 0], vec![1, 200, 0, 0]] {
+            let mut test_vm = VM::new_with_program(program);
+            assert!(matches!(
+                test_vm.run(),
+                RunStatus::Fault(Fault::InvalidProgram { .. })
+            ));
+        }
+        let mut test_vm = VM::new_with_program(vec![10, 0, 0, 0, 10, 0, 0, 0]);
+        test_vm.program_counter = 2;
+        assert_eq!(
+            test_vm.run(),
+            RunStatus::Fault(Fault::ProgramCounterInsideInstruction(2))
+        );
+        // Fixing the program is noticed on the next run.
+        test_vm.program_counter = 0;
+        test_vm.program = vec![1, 0];
+        assert!(matches!(test_vm.run(), RunStatus::Fault(_)));
+        test_vm.program = vec![1, 0, 0, 7];
+        test_vm.coverage = Some(Coverage::new());
+        assert_eq!(test_vm.run(), RunStatus::Done);
+        assert_eq!(test_vm.registers[0], 7);
+    }
+
     #[test]
     fn test_jump_inst() {
         let mut test_vm = VM::new();
@@ -258,14 +909,14 @@ mod tests {
         let test_bytes = vec![7, 0, 1, 0];
         test_vm.program = test_bytes;
         test_vm.run();
-        assert_eq!(test_vm.equality_flag, true);
+        assert!(test_vm.equality_flag);
     }
 
     #[test]
     fn test_jeq_inst() {
         let mut test_vm = VM::new();
         test_vm.equality_flag = true;
-        let test_bytes = vec![8, 1, 0, 0];
+        let test_bytes = vec![8, 0, 1, 0];
         test_vm.program = test_bytes;
         test_vm.execute_instrunction();
         assert_eq!(test_vm.program_counter, 1);
@@ -280,6 +931,131 @@ mod tests {
         assert_eq!(test_vm.heap.len(), 32);
     }
 
+    #[test]
+    fn test_free_and_realloc_inst() {
+        // alloc $0; alloc $1; alloc $2; free $0; realloc $1 $3
+        let mut test_vm = VM::new_with_program(vec![
+            9, 0, 0, 0, 9, 1, 0, 0, 9, 2, 0, 0, 18, 0, 0, 0, 19, 1, 3, 0,
+        ]);
+        test_vm.registers[0] = 8;
+        test_vm.registers[1] = 4;
+        test_vm.registers[2] = 1;
+        test_vm.registers[3] = 6;
+        assert_eq!(test_vm.run(), RunStatus::Done);
+        // The block can't grow into $2, so it moves into the freed block.
+        assert_eq!(test_vm.registers[0], 0);
+        assert_eq!(test_vm.registers[1], 0);
+        assert_eq!(test_vm.registers[2], 12);
+        assert_eq!(test_vm.heap.len(), 13);
+        assert_eq!(test_vm.allocator.stats.live_blocks, 2);
+    }
+
+    #[test]
+    fn test_managed_objects() {
+        // new $0 $1; setf $0 $2 $1; getf $3 $0 $2; push $0; load $0 #0;
+        // new $4 $2; gc; gcinfo $5 #0; pop $6; gcinfo $7 #2
+        let mut test_vm = VM::new_with_program(vec![
+            20, 0, 1, 0, 22, 0, 2, 1, 21, 3, 0, 2, 23, 0, 0, 0, 1, 0, 0, 0, 20, 4, 2, 0, 25, 0, 0,
+            0, 26, 5, 0, 0, 24, 6, 0, 0, 26, 7, 0, 2,
+        ]);
+        test_vm.registers[1] = 3;
+        test_vm.registers[2] = 2;
+        assert_eq!(test_vm.run(), RunStatus::Done);
+        assert_eq!(test_vm.registers[3], 3);
+        // The pushed object survives, the one in $4 too.
+        assert_eq!(test_vm.registers[5], 2);
+        assert_eq!(test_vm.registers[6], crate::gc::reference(0));
+        assert_eq!(test_vm.registers[7], 0);
+    }
+
+    #[test]
+    fn test_managed_faults() {
+        // new $0 $1; getf $2 $0 $1
+        let mut test_vm = VM::new_with_program(vec![20, 0, 1, 0, 21, 2, 0, 1]);
+        test_vm.registers[1] = 2;
+        let object = crate::gc::reference(0);
+        assert_eq!(
+            test_vm.run(),
+            RunStatus::Fault(Fault::FieldOutOfBounds { object, index: 2 })
+        );
+        assert_eq!(test_vm.program_counter, 4);
+        // pop $0
+        let mut test_vm = VM::new_with_program(vec![24, 0, 0, 0]);
+        assert_eq!(test_vm.run(), RunStatus::Fault(Fault::StackUnderflow));
+    }
+
+    #[test]
+    fn test_string_instructions() {
+        // strlen $1 $0; strload $2 $0; itoa $4 $1; strcat $6 $2 $4;
+        // atoi $8 $4; strcmp $9 $2 $6
+        let mut test_vm = VM::new_with_program(vec![
+            28, 1, 0, 0, 29, 2, 0, 0, 32, 4, 1, 0, 31, 6, 2, 4, 33, 8, 4, 0, 30, 9, 2, 6,
+        ]);
+        test_vm.read_only = b"x\0abc\0".to_vec();
+        test_vm.registers[0] = 2;
+        assert_eq!(test_vm.run(), RunStatus::Done);
+        assert_eq!(test_vm.registers[1], 3);
+        assert_eq!((test_vm.registers[2], test_vm.registers[3]), (0, 3));
+        assert_eq!((test_vm.registers[4], test_vm.registers[5]), (3, 1));
+        assert_eq!((test_vm.registers[6], test_vm.registers[7]), (4, 4));
+        assert_eq!(&test_vm.heap[4..8], b"abc3");
+        assert_eq!(test_vm.registers[8], 3);
+        assert_eq!(test_vm.registers[9], -1);
+        assert!(!test_vm.equality_flag);
+    }
+
+    #[test]
+    fn test_string_faults() {
+        // strlen $1 $0
+        let mut test_vm = VM::new_with_program(vec![28, 1, 0, 0]);
+        test_vm.read_only = b"ab".to_vec();
+        assert_eq!(
+            test_vm.run(),
+            RunStatus::Fault(Fault::UnterminatedString(0))
+        );
+        test_vm.registers[0] = 2;
+        assert_eq!(
+            test_vm.run(),
+            RunStatus::Fault(Fault::ReadOnlyOutOfBounds(2))
+        );
+        // alloc $0; atoi $2 $0
+        let mut test_vm = VM::new_with_program(vec![9, 0, 0, 0, 33, 2, 0, 0]);
+        test_vm.registers[0] = 2;
+        test_vm.registers[1] = 3;
+        assert_eq!(
+            test_vm.run(),
+            RunStatus::Fault(Fault::OutOfBounds { address: 0, len: 3 })
+        );
+        test_vm.registers[1] = -1;
+        assert_eq!(test_vm.run(), RunStatus::Fault(Fault::InvalidLength(-1)));
+        // Two zero bytes are not a number.
+        test_vm.registers[1] = 2;
+        assert_eq!(test_vm.run(), RunStatus::Done);
+        assert!(!test_vm.equality_flag);
+    }
+
+    #[test]
+    fn test_heap_faults_stop_on_instruction() {
+        for predecode in [true, false] {
+            // alloc $0; free $0; free $0
+            let mut test_vm = VM::new_with_program(vec![9, 0, 0, 0, 18, 0, 0, 0, 18, 0, 0, 0]);
+            test_vm.predecode = predecode;
+            test_vm.registers[0] = 4;
+            assert_eq!(test_vm.run(), RunStatus::Fault(Fault::DoubleFree(0)));
+            assert_eq!(test_vm.program_counter, 8);
+        }
+        let mut test_vm = VM::new_with_program(vec![9, 0, 0, 0]);
+        test_vm.registers[0] = -1;
+        assert_eq!(test_vm.run(), RunStatus::Fault(Fault::NegativeSize(-1)));
+        assert!(test_vm.heap.is_empty());
+        test_vm.registers[0] = 64;
+        test_vm.allocator.max_size = Some(32);
+        assert!(matches!(
+            test_vm.run(),
+            RunStatus::Fault(Fault::OutOfMemory { .. })
+        ));
+    }
+
     #[test]
     fn test_inc_inst() {
         let mut test_vm = VM::new();
@@ -296,4 +1072,155 @@ mod tests {
         test_vm.run();
         assert_eq!(test_vm.registers[0], 69);
     }
+
+    #[test]
+    fn test_verify_program() {
+        let test_vm = VM::new_with_program(vec![10, 0, 0, 0]);
+        assert!(test_vm.verify().is_ok());
+        let test_vm = VM::new_with_program(vec![1, 40]);
+        assert!(test_vm.verify().is_err());
+    }
+
+    #[test]
+    fn test_instruction_limit_is_resumable() {
+        let mut test_vm = VM::new_with_program(vec![10, 0, 0, 0, 10, 0, 0, 0, 10, 0, 0, 0]);
+        test_vm.budget.max_instructions = Some(2);
+        assert_eq!(test_vm.run(), RunStatus::InstructionLimit);
+        assert_eq!(test_vm.registers[0], 2);
+        assert_eq!(test_vm.instructions, 2);
+        assert_eq!(test_vm.run(), RunStatus::Done);
+        assert_eq!(test_vm.registers[0], 3);
+        assert_eq!(test_vm.instructions, 3);
+    }
+
+    #[test]
+    fn test_vm_is_send() {
+        // The executor moves VMs to worker threads.
+        fn assert_send<T: Send>() {}
+        assert_send::<VM>();
+    }
+
+    #[test]
+    fn test_timeout_stops_infinite_loop() {
+        let mut test_vm = VM::new_with_program(vec![6, 0, 0, 0]);
+        test_vm.budget.timeout = Some(Duration::from_millis(10));
+        assert_eq!(test_vm.run(), RunStatus::Timeout);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut test_vm = VM::new_with_program(vec![6, 0, 0, 0]);
+        test_vm.interrupt_handle().store(true, Ordering::Relaxed);
+        assert_eq!(test_vm.run(), RunStatus::Interrupted);
+    }
+
+    // Runs a program through both interpreters and checks they end up in the
+    // same state.
+    fn assert_same_as_bytes(program: Vec<u8>) {
+        let mut decoded = VM::new_with_program(program.clone());
+        let mut bytes = VM::new_with_program(program);
+        bytes.predecode = false;
+        assert_eq!(decoded.run(), bytes.run());
+        assert!(decoded.decoded.is_some());
+        assert_eq!(decoded.registers, bytes.registers);
+        assert_eq!(decoded.program_counter, bytes.program_counter);
+        assert_eq!(decoded.remainder, bytes.remainder);
+        assert_eq!(decoded.equality_flag, bytes.equality_flag);
+        assert_eq!(decoded.heap, bytes.heap);
+    }
+
+    #[test]
+    fn test_predecoded_matches_bytes() {
+        // load $0 #10; loop: dec $0; load $2 #3; div $3 $0 $2; add $1 $1 $3;
+        // neq $0 $4; jeq @loop; alloc $1; exit #7
+        assert_same_as_bytes(vec![
+            1, 0, 0, 10, 11, 0, 0, 0, 1, 2, 0, 3, 5, 3, 0, 2, 2, 1, 1, 3, 13, 0, 4, 0, 8, 0, 4, 0,
+            9, 1, 0, 0, 15, 0, 7, 0,
+        ]);
+        // eq $0 $1; jneq @end; inc $5; mul $6 $5 $5; jmp @end; end: hlt
+        assert_same_as_bytes(vec![
+            7, 0, 1, 0, 12, 0, 20, 0, 10, 5, 0, 0, 4, 6, 5, 5, 6, 0, 20, 0, 14, 0, 0, 0,
+        ]);
+    }
+
+    #[test]
+    fn test_compact_encoding() {
+        // load $0 #3; loop: dec $0; neq $0 $1; jeq @loop; exit #9
+        let program = vec![1, 0, 0, 3, 11, 0, 13, 0, 1, 8, 0, 4, 15, 0, 9];
+        let mut decoded = VM::new_with_program(program.clone());
+        decoded.encoding = Encoding::Compact;
+        assert!(decoded.verify().is_ok());
+        assert_eq!(decoded.run(), RunStatus::Halted(9));
+        assert!(decoded.decoded.is_some());
+        let mut bytes = VM::new_with_program(program);
+        bytes.encoding = Encoding::Compact;
+        bytes.predecode = false;
+        assert_eq!(bytes.run(), RunStatus::Halted(9));
+        assert_eq!(decoded.program_counter, bytes.program_counter);
+        assert_eq!(decoded.program_counter, 15);
+        assert_eq!(bytes.registers[0], 0);
+    }
+
+    #[test]
+    fn test_predecoded_notices_program_changes() {
+        let mut test_vm = VM::new_with_program(vec![10, 0, 0, 0]);
+        test_vm.run();
+        test_vm.append_to_program(vec![10, 0, 0, 0, 10, 1, 0, 0]);
+        test_vm.run();
+        assert_eq!(test_vm.registers[0], 2);
+        assert_eq!(test_vm.registers[1], 1);
+        test_vm.program = vec![11, 0, 0, 0];
+        test_vm.program_counter = 0;
+        test_vm.run();
+        assert_eq!(test_vm.registers[0], 1);
+    }
+
+    #[test]
+    fn test_hlt_inst() {
+        let mut test_vm = VM::new_with_program(vec![14, 0, 0, 0, 10, 0, 0, 0]);
+        assert_eq!(test_vm.run(), RunStatus::Halted(0));
+        assert_eq!(test_vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_exit_inst() {
+        let mut test_vm = VM::new_with_program(vec![15, 0, 42, 0]);
+        assert_eq!(test_vm.run(), RunStatus::Halted(42));
+    }
+
+    #[test]
+    fn test_wide_loads() {
+        // loads $0 #-2; loadx $0 #0x7960; load $1 #1; loadx $1 #0; loadx $1 #0
+        let program = vec![
+            16, 0, 0xFF, 0xFE, 17, 0, 0x79, 0x60, 1, 1, 0, 1, 17, 1, 0, 0, 17, 1, 0, 0,
+        ];
+        let mut test_vm = VM::new_with_program(program.clone());
+        test_vm.run();
+        assert_eq!(test_vm.registers[0], -100000);
+        assert_eq!(test_vm.registers[1], 0);
+        let mut test_vm = VM::new_with_program(program);
+        test_vm.width = Width::W64;
+        test_vm.run();
+        assert_eq!(test_vm.registers[0], -100000);
+        assert_eq!(test_vm.registers[1], 1 << 32);
+    }
+
+    #[test]
+    fn test_arithmetic_wraps_to_width() {
+        // add $2 $0 $1; mul $3 $0 $0
+        let program = vec![2, 2, 0, 1, 4, 3, 0, 0];
+        let mut test_vm = VM::new_with_program(program.clone());
+        test_vm.registers[0] = i32::MAX as i64;
+        test_vm.registers[1] = 1;
+        test_vm.run();
+        assert_eq!(test_vm.registers[2], i32::MIN as i64);
+        assert_eq!(test_vm.registers[3], 1);
+        let mut test_vm = VM::new_with_program(program);
+        test_vm.width = Width::W64;
+        test_vm.registers[0] = i32::MAX as i64;
+        test_vm.registers[1] = 1;
+        test_vm.run();
+        assert_eq!(test_vm.registers[2], i32::MAX as i64 + 1);
+        assert_eq!(test_vm.registers[3], (i32::MAX as i64) * (i32::MAX as i64));
+    }
 }